@@ -0,0 +1,574 @@
+use core::fmt::Write;
+use std::collections::HashSet;
+
+use crate::compile::{Instruction, KnownCells, Span};
+use crate::settings::{Settings, TapePolicy};
+
+/// What is known about whether a loop ends.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoopStatus {
+    /// It may run and may end.
+    Unknown,
+
+    /// It never runs, as its cell is always 0 when it is reached.
+    Dead,
+
+    /// It never ends once it starts.
+    Infinite,
+}
+
+impl LoopStatus {
+    const fn name(self) -> &'static str {
+        match self {
+            Self::Unknown => "unknown",
+            Self::Dead => "dead",
+            Self::Infinite => "infinite",
+        }
+    }
+}
+
+/// What analysing found about a loop.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoopReport {
+    /// Index of the instruction the loop starts at.
+    pub start: usize,
+
+    /// Index of the instruction the loop ends at, which is `start` for loops that are one
+    /// instruction.
+    pub end: usize,
+
+    /// Cells the pointer moves right each time the body runs, or left if negative, if known.
+    pub shift: Option<i64>,
+
+    /// Whether the loop ends.
+    pub status: LoopStatus,
+}
+
+impl LoopReport {
+    /// Whether the pointer is back where it was each time the body runs.
+    #[must_use]
+    pub const fn is_balanced(&self) -> bool {
+        matches!(self.shift, Some(0))
+    }
+}
+
+/// What analysing a program found without running it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Report {
+    /// Every loop, in the order they start.
+    pub loops: Vec<LoopReport>,
+
+    /// Leftmost cell the program may reach, from the one it starts on, or `None` if it may go
+    /// arbitrarily far.
+    pub min_offset: Option<i64>,
+
+    /// Rightmost cell the program may reach, from the one it starts on, or `None` if it may go
+    /// arbitrarily far.
+    pub max_offset: Option<i64>,
+
+    /// Whether the program may read input.
+    pub reads_input: bool,
+}
+
+impl Report {
+    /// Whether the program may move left of the cell it starts on.
+    #[must_use]
+    pub fn moves_left(&self) -> bool {
+        self.min_offset.is_none_or(|offset| offset < 0)
+    }
+
+    /// Writes the report for people to read, with `spans` giving where each loop is in the source.
+    #[must_use]
+    pub fn to_text(&self, spans: Option<&[Span]>) -> String {
+        let yes = |value: bool| if value { "yes" } else { "no" };
+        let offset =
+            |offset: Option<i64>| offset.map_or("unbounded".to_string(), |o| o.to_string());
+        let mut text = String::new();
+        writeln!(text, "reads input: {}", yes(self.reads_input)).unwrap();
+        writeln!(text, "leftmost cell: {}", offset(self.min_offset)).unwrap();
+        writeln!(text, "rightmost cell: {}", offset(self.max_offset)).unwrap();
+        writeln!(text, "moves left of cell 0: {}", yes(self.moves_left())).unwrap();
+        writeln!(text, "loops: {}", self.loops.len()).unwrap();
+        for report in &self.loops {
+            write!(text, "  instruction {}", report.start).unwrap();
+            if let Some(span) = spans.and_then(|spans| spans.get(report.start)) {
+                write!(text, " ({}:{})", span.start.line, span.start.column).unwrap();
+            }
+            match report.shift {
+                Some(0) => text.push_str(": balanced"),
+                Some(shift) if shift > 0 => write!(text, ": moves {shift} right").unwrap(),
+                Some(shift) => write!(text, ": moves {} left", -shift).unwrap(),
+                None => text.push_str(": moves an unknown amount"),
+            }
+            match report.status {
+                LoopStatus::Unknown => {}
+                LoopStatus::Dead => text.push_str(", never runs"),
+                LoopStatus::Infinite => text.push_str(", never ends once started"),
+            }
+            text.push('\n');
+        }
+        text
+    }
+
+    /// Writes the report as JSON, with `spans` giving where each loop is in the source.
+    #[must_use]
+    pub fn to_json(&self, spans: Option<&[Span]>) -> String {
+        let number = |value: Option<i64>| value.map_or("null".to_string(), |v| v.to_string());
+        let mut json = String::new();
+        write!(
+            json,
+            "{{\"reads_input\":{},\"min_offset\":{},\"max_offset\":{},\"moves_left\":{},\"loops\":[",
+            self.reads_input,
+            number(self.min_offset),
+            number(self.max_offset),
+            self.moves_left()
+        )
+        .unwrap();
+        for (index, report) in self.loops.iter().enumerate() {
+            if index > 0 {
+                json.push(',');
+            }
+            write!(json, "{{\"start\":{},\"end\":{},", report.start, report.end).unwrap();
+            if let Some(span) = spans.and_then(|spans| spans.get(report.start)) {
+                write!(
+                    json,
+                    "\"line\":{},\"column\":{},",
+                    span.start.line, span.start.column
+                )
+                .unwrap();
+            }
+            write!(
+                json,
+                "\"shift\":{},\"balanced\":{},\"status\":\"{}\"}}",
+                number(report.shift),
+                report.is_balanced(),
+                report.status.name()
+            )
+            .unwrap();
+        }
+        json.push_str("]}\n");
+        json
+    }
+}
+
+/// Cells the pointer may be on, from the one it started on, each end `None` if unbounded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Pointer {
+    low: Option<i64>,
+    high: Option<i64>,
+}
+
+impl Pointer {
+    const fn at(offset: i64) -> Self {
+        Self {
+            low: Some(offset),
+            high: Some(offset),
+        }
+    }
+
+    fn moved(self, offset: i64) -> Self {
+        Self {
+            low: self.low.map(|low| low + offset),
+            high: self.high.map(|high| high + offset),
+        }
+    }
+
+    /// Makes unbounded each end that `next` goes past, so loops are only walked a few times.
+    fn widen(self, next: Self) -> Self {
+        Self {
+            low: self
+                .low
+                .zip(next.low)
+                .filter(|(low, next)| next >= low)
+                .map(|(low, _)| low),
+            high: self
+                .high
+                .zip(next.high)
+                .filter(|(high, next)| next <= high)
+                .map(|(high, _)| high),
+        }
+    }
+}
+
+/// Walks instructions, noting every cell they may reach.
+struct Walker<'a> {
+    instructions: &'a [Instruction],
+    dead: &'a HashSet<usize>,
+
+    /// Loops that may run.
+    reached: HashSet<usize>,
+
+    /// Leftmost and rightmost cells reached, each `None` if unbounded.
+    low: Option<i64>,
+    high: Option<i64>,
+
+    reads_input: bool,
+}
+
+impl Walker<'_> {
+    /// Notes the cell `offset` cells right of the pointer, or left if negative.
+    fn reach(&mut self, pointer: Pointer, offset: i64) {
+        self.low = self
+            .low
+            .zip(pointer.low)
+            .map(|(low, at)| low.min(at + offset));
+        self.high = self
+            .high
+            .zip(pointer.high)
+            .map(|(high, at)| high.max(at + offset));
+    }
+
+    /// Walks instructions from `start` up to `end`, returning where the pointer may be after.
+    fn walk(&mut self, start: usize, end: usize, mut pointer: Pointer) -> Pointer {
+        let mut index = start;
+        while index < end {
+            let instruction = &self.instructions[index];
+            if is_loop(instruction) {
+                if self.dead.contains(&index) {
+                    index = loop_end(self.instructions, index) + 1;
+                    continue;
+                }
+                self.reached.insert(index);
+            }
+            match *instruction {
+                Instruction::Forward(offset) => pointer = pointer.moved(i64::from(offset)),
+                Instruction::Backward(offset) => pointer = pointer.moved(-i64::from(offset)),
+                Instruction::Increment { offset, .. }
+                | Instruction::SetCell { offset, .. }
+                | Instruction::Output { offset }
+                | Instruction::Print { offset, .. } => self.reach(pointer, i64::from(offset)),
+                Instruction::MultiplyForward(offset, _) => self.reach(pointer, i64::from(offset)),
+                Instruction::MultiplyBackward(offset, _) => {
+                    self.reach(pointer, -i64::from(offset));
+                }
+                Instruction::MultiplyProduct { offset, factor, .. } => {
+                    self.reach(pointer, i64::from(offset));
+                    self.reach(pointer, i64::from(factor));
+                }
+                Instruction::Input => self.reads_input = true,
+                Instruction::ForwardLoop(_) | Instruction::IncrementForwardLoop(..) => {
+                    pointer.high = None;
+                }
+                Instruction::BackwardLoop(_) | Instruction::IncrementBackwardLoop(..) => {
+                    pointer.low = None;
+                }
+                Instruction::LoopStart(_) => {
+                    let end = loop_end(self.instructions, index);
+                    // One that moves the pointer each time may go arbitrarily far that way.
+                    match loop_shift(self.instructions, index) {
+                        Some(shift) if shift > 0 => pointer.high = None,
+                        Some(shift) if shift < 0 => pointer.low = None,
+                        _ => {}
+                    }
+                    loop {
+                        let after = self.walk(index + 1, end, pointer);
+                        let widened = pointer.widen(after);
+                        if widened == pointer {
+                            break;
+                        }
+                        pointer = widened;
+                    }
+                    index = end;
+                }
+                Instruction::IncrementLoop(_)
+                | Instruction::LoopEnd(_)
+                | Instruction::OutputBytes(_)
+                | Instruction::Stop => {}
+            }
+            self.reach(pointer, 0);
+            index += 1;
+        }
+        pointer
+    }
+}
+
+const fn is_loop(instruction: &Instruction) -> bool {
+    matches!(
+        instruction,
+        Instruction::LoopStart(_)
+            | Instruction::IncrementLoop(_)
+            | Instruction::ForwardLoop(_)
+            | Instruction::BackwardLoop(_)
+            | Instruction::IncrementForwardLoop(..)
+            | Instruction::IncrementBackwardLoop(..)
+    )
+}
+
+/// Index of the last instruction of the loop starting at `start`.
+fn loop_end(instructions: &[Instruction], start: usize) -> usize {
+    match instructions[start] {
+        Instruction::LoopStart(after) => after as usize - 1,
+        _ => start,
+    }
+}
+
+/// Cells the pointer moves each time the body of the loop starting at `start` runs, if known.
+fn loop_shift(instructions: &[Instruction], start: usize) -> Option<i64> {
+    match instructions[start] {
+        Instruction::IncrementLoop(_) => Some(0),
+        Instruction::ForwardLoop(offset) | Instruction::IncrementForwardLoop(offset, _) => {
+            Some(i64::from(offset))
+        }
+        Instruction::BackwardLoop(offset) | Instruction::IncrementBackwardLoop(offset, _) => {
+            Some(-i64::from(offset))
+        }
+        Instruction::LoopStart(_) => {
+            let end = loop_end(instructions, start);
+            let mut shift = 0;
+            let mut index = start + 1;
+            while index < end {
+                match instructions[index] {
+                    Instruction::Forward(offset) => shift += i64::from(offset),
+                    Instruction::Backward(offset) => shift -= i64::from(offset),
+                    ref instruction if is_loop(instruction) => {
+                        if loop_shift(instructions, index) != Some(0) {
+                            return None;
+                        }
+                        index = loop_end(instructions, index);
+                    }
+                    _ => {}
+                }
+                index += 1;
+            }
+            Some(shift)
+        }
+        _ => None,
+    }
+}
+
+/// Whether the body of a balanced loop, from `start` up to `end`, may change the loop's cell, with
+/// the pointer starting `position` cells right of it.
+fn may_change(
+    instructions: &[Instruction],
+    start: usize,
+    end: usize,
+    mut position: i64,
+    tape: TapePolicy,
+) -> bool {
+    let is_loop_cell = |position: i64| match tape {
+        TapePolicy::Wrap(size) => position.rem_euclid(size as i64) == 0,
+        _ => position == 0,
+    };
+    let mut index = start;
+    while index < end {
+        let written = match instructions[index] {
+            Instruction::Forward(offset) => {
+                position += i64::from(offset);
+                None
+            }
+            Instruction::Backward(offset) => {
+                position -= i64::from(offset);
+                None
+            }
+            Instruction::Increment { offset, .. }
+            | Instruction::SetCell { offset, .. }
+            | Instruction::MultiplyProduct { offset, .. } => Some(i64::from(offset)),
+            Instruction::MultiplyForward(offset, _) => Some(i64::from(offset)),
+            Instruction::MultiplyBackward(offset, _) => Some(-i64::from(offset)),
+            Instruction::Input | Instruction::IncrementLoop(_) => Some(0),
+            Instruction::LoopStart(_) => {
+                let end = loop_end(instructions, index);
+                if is_loop_cell(position)
+                    || may_change(instructions, index + 1, end, position, tape)
+                {
+                    return true;
+                }
+                index = end;
+                None
+            }
+            _ => None,
+        };
+        if written.is_some_and(|offset| is_loop_cell(position + offset)) {
+            return true;
+        }
+        index += 1;
+    }
+    false
+}
+
+/// Finds loops that never run, by following the values of cells known between loops, and
+/// `IncrementLoop`s that never reach 0.
+fn known_loops(
+    instructions: &[Instruction],
+    settings: &Settings,
+) -> (HashSet<usize>, HashSet<usize>) {
+    let mut known = KnownCells::new(settings.tape, settings.tape_limit, settings.cell_width);
+    let mut dead = HashSet::new();
+    let mut infinite = HashSet::new();
+    let mut index = 0;
+    while index < instructions.len() {
+        let instruction = &instructions[index];
+        if is_loop(instruction) {
+            match known.get(0) {
+                Some(0) => {
+                    dead.insert(index);
+                    index = loop_end(instructions, index) + 1;
+                    continue;
+                }
+                Some(value) => {
+                    if let Instruction::IncrementLoop(amount) = *instruction {
+                        // Adding `amount` only reaches 0 from multiples of its largest power of 2.
+                        let power = amount.trailing_zeros().min(settings.cell_width.bits());
+                        if u64::from(value) % (1 << power) != 0 {
+                            infinite.insert(index);
+                        }
+                    }
+                }
+                None => {}
+            }
+        }
+        known.update(instruction);
+        index += 1;
+    }
+    (dead, infinite)
+}
+
+/// Analyses compiled instructions without running them, for the settings they were compiled with.
+#[must_use]
+pub fn analyse(instructions: &[Instruction], settings: &Settings) -> Report {
+    let (dead, infinite) = known_loops(instructions, settings);
+    let mut walker = Walker {
+        instructions,
+        dead: &dead,
+        reached: HashSet::new(),
+        low: Some(0),
+        high: Some(0),
+        reads_input: false,
+    };
+    walker.walk(0, instructions.len(), Pointer::at(0));
+
+    let loops = (0..instructions.len())
+        .filter(|&index| is_loop(&instructions[index]))
+        .map(|start| {
+            let end = loop_end(instructions, start);
+            let shift = loop_shift(instructions, start);
+            let status = if !walker.reached.contains(&start) {
+                LoopStatus::Dead
+            } else if infinite.contains(&start)
+                || (matches!(instructions[start], Instruction::LoopStart(_))
+                    && shift == Some(0)
+                    && !may_change(instructions, start + 1, end, 0, settings.tape))
+            {
+                LoopStatus::Infinite
+            } else {
+                LoopStatus::Unknown
+            };
+            LoopReport {
+                start,
+                end,
+                shift,
+                status,
+            }
+        })
+        .collect();
+
+    Report {
+        loops,
+        min_offset: walker.low,
+        max_offset: walker.high,
+        reads_input: walker.reads_input,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        analysis::{analyse, LoopReport, LoopStatus, Report},
+        compile::Compiler,
+        lexer::lex,
+        settings::{Optimisations, Settings},
+    };
+
+    fn analyse_code(code: &str, level: u8) -> Report {
+        let instructions = Compiler::new(lex(code))
+            .with_optimisations(Optimisations::level(level))
+            .compile()
+            .unwrap();
+        analyse(&instructions, &Settings::default())
+    }
+
+    fn statuses(report: &Report) -> Vec<(Option<i64>, LoopStatus)> {
+        report
+            .loops
+            .iter()
+            .map(|report| (report.shift, report.status))
+            .collect()
+    }
+
+    #[test]
+    fn offsets_work() {
+        let report = analyse_code(",[->>+<<]>>.", 0);
+        assert_eq!((report.min_offset, report.max_offset), (Some(0), Some(2)));
+        assert!(report.reads_input);
+        assert!(!report.moves_left());
+
+        let report = analyse_code(">+[<<]", 0);
+        assert_eq!((report.min_offset, report.max_offset), (None, Some(1)));
+        assert!(!report.reads_input);
+        assert!(report.moves_left());
+
+        let report = analyse_code(",[>,]<[<]", 1);
+        assert_eq!((report.min_offset, report.max_offset), (None, None));
+    }
+
+    #[test]
+    fn loops_work() {
+        let report = analyse_code(",[>[-]<[>+<-]]", 0);
+        assert_eq!(
+            report.loops[0],
+            LoopReport {
+                start: 1,
+                end: 13,
+                shift: Some(0),
+                status: LoopStatus::Unknown,
+            }
+        );
+        assert!(report.loops.iter().all(LoopReport::is_balanced));
+
+        let report = analyse_code(",[>,]", 0);
+        assert_eq!(statuses(&report), [(Some(1), LoopStatus::Unknown)]);
+        let report = analyse_code(",[[>]<]", 1);
+        assert_eq!(
+            statuses(&report),
+            [(None, LoopStatus::Unknown), (Some(1), LoopStatus::Unknown)]
+        );
+    }
+
+    #[test]
+    fn dead_and_infinite_loops_work() {
+        let report = analyse_code("+[-][.>]", 0);
+        assert_eq!(
+            statuses(&report),
+            [(Some(0), LoopStatus::Unknown), (Some(1), LoopStatus::Dead)]
+        );
+        // Nothing in a dead loop is reached.
+        assert_eq!(report.max_offset, Some(0));
+
+        let report = analyse_code(",[>+<]", 0);
+        assert_eq!(statuses(&report), [(Some(0), LoopStatus::Infinite)]);
+        let report = analyse_code(",[>+<[-]]", 0);
+        assert_eq!(report.loops[0].status, LoopStatus::Unknown);
+        let report = analyse_code("+[++]", 1);
+        assert_eq!(statuses(&report), [(Some(0), LoopStatus::Infinite)]);
+        let report = analyse_code("++[++]", 1);
+        assert_eq!(statuses(&report), [(Some(0), LoopStatus::Unknown)]);
+    }
+
+    #[test]
+    fn json_works() {
+        let (instructions, spans) = Compiler::new(lex(",\n[.>]"))
+            .with_optimisations(Optimisations::level(0))
+            .compile_with_spans()
+            .unwrap();
+        let report = analyse(&instructions, &Settings::default());
+        assert_eq!(
+            report.to_json(Some(&spans)),
+            concat!(
+                r#"{"reads_input":true,"min_offset":0,"max_offset":null,"moves_left":false,"#,
+                r#""loops":[{"start":1,"end":4,"line":2,"column":1,"shift":1,"balanced":false,"#,
+                r#""status":"unknown"}]}"#,
+                "\n"
+            )
+        );
+    }
+}
@@ -0,0 +1,499 @@
+use core::fmt;
+
+use crate::compile::Instruction;
+use crate::settings::CellWidth;
+
+/// First bytes of every `.bfc` file.
+pub const MAGIC: [u8; 4] = *b"BFC\0";
+
+/// Version of the instruction set, raised whenever [`Instruction`] changes.
+pub const VERSION: u16 = 6;
+
+/// Bytes before the instructions: magic, version, cell width, instruction count and checksum.
+const HEADER_SIZE: usize = 15;
+
+/// Why loading bytecode failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoadError {
+    /// The bytes do not start with a `.bfc` header.
+    NotBytecode,
+
+    /// The bytecode is for another version of the instruction set.
+    UnsupportedVersion(u16),
+
+    /// The header has a cell width other than 8, 16 or 32 bits.
+    UnsupportedCellWidth(u8),
+
+    /// The instructions do not match the checksum in the header.
+    ChecksumMismatch,
+
+    /// An instruction is unknown or cut off, or the instruction count is wrong.
+    Malformed {
+        /// Offset of the byte where decoding failed.
+        offset: usize,
+    },
+
+    /// A value does not fit in a cell.
+    ValueOutOfRange {
+        /// Index of the instruction.
+        index: usize,
+    },
+
+    /// A loop instruction does not point just past its other end.
+    InvalidJump {
+        /// Index of the instruction.
+        index: usize,
+    },
+
+    /// The last instruction is not [`Instruction::Stop`].
+    MissingStop,
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotBytecode => f.write_str("not a `.bfc` file"),
+            Self::UnsupportedVersion(version) => {
+                write!(f, "unsupported bytecode version {version}")
+            }
+            Self::UnsupportedCellWidth(bits) => write!(f, "unsupported cell width of {bits} bits"),
+            Self::ChecksumMismatch => f.write_str("checksum does not match"),
+            Self::Malformed { offset } => write!(f, "malformed instruction at byte {offset}"),
+            Self::ValueOutOfRange { index } => {
+                write!(f, "value out of range in instruction {index}")
+            }
+            Self::InvalidJump { index } => write!(f, "invalid jump in instruction {index}"),
+            Self::MissingStop => f.write_str("instructions do not end with `Stop`"),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+/// CRC-32, as used by zip and PNG.
+fn checksum(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+/// Appends `value` as a LEB128 variable-length integer.
+fn push_value(bytes: &mut Vec<u8>, mut value: u32) {
+    while value >= 0x80 {
+        bytes.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    bytes.push(value as u8);
+}
+
+/// Maps an offset to an unsigned value, with small offsets either side of 0 staying small.
+const fn zigzag(offset: i32) -> u32 {
+    ((offset << 1) ^ (offset >> 31)) as u32
+}
+
+/// Reverses [`zigzag`].
+const fn unzigzag(value: u32) -> i32 {
+    (value >> 1) as i32 ^ -((value & 1) as i32)
+}
+
+/// Serialises instructions compiled for `cell_width` into `.bfc` bytecode.
+///
+/// # Errors
+///
+/// Returns the error [`from_bytecode`] would give if the instructions could not run safely,
+/// so no bytecode is written that would be rejected when loaded.
+pub fn to_bytecode(
+    instructions: &[Instruction],
+    cell_width: CellWidth,
+) -> Result<Vec<u8>, LoadError> {
+    validate(instructions, cell_width)?;
+    Ok(encode(instructions, cell_width))
+}
+
+/// Serialises instructions without checking them.
+fn encode(instructions: &[Instruction], cell_width: CellWidth) -> Vec<u8> {
+    let mut body = Vec::with_capacity(instructions.len() * 2);
+    for instruction in instructions {
+        let (opcode, operands): (u8, &[u32]) = match instruction {
+            Instruction::Forward(offset) => (0, &[*offset]),
+            Instruction::Backward(offset) => (1, &[*offset]),
+            Instruction::Increment { offset, amount } => (2, &[zigzag(*offset), *amount]),
+            Instruction::SetCell { offset, value } => (3, &[zigzag(*offset), *value]),
+            Instruction::IncrementLoop(value) => (4, &[*value]),
+            Instruction::MultiplyForward(offset, multiplier) => (5, &[*offset, *multiplier]),
+            Instruction::MultiplyBackward(offset, multiplier) => (6, &[*offset, *multiplier]),
+            Instruction::ForwardLoop(offset) => (7, &[*offset]),
+            Instruction::BackwardLoop(offset) => (8, &[*offset]),
+            Instruction::LoopStart(loop_end) => (9, &[*loop_end]),
+            Instruction::LoopEnd(loop_start) => (10, &[*loop_start]),
+            Instruction::Output { offset } => (11, &[zigzag(*offset)]),
+            Instruction::Input => (12, &[]),
+            Instruction::Stop => (13, &[]),
+            Instruction::IncrementForwardLoop(offset, value) => (14, &[*offset, *value]),
+            Instruction::IncrementBackwardLoop(offset, value) => (15, &[*offset, *value]),
+            Instruction::Print { offset, value } => (16, &[zigzag(*offset), u32::from(*value)]),
+            Instruction::OutputBytes(bytes) => (17, &[bytes.len() as u32]),
+            Instruction::MultiplyProduct {
+                offset,
+                factor,
+                multiplier,
+            } => (18, &[zigzag(*offset), zigzag(*factor), *multiplier]),
+        };
+        body.push(opcode);
+        for &operand in operands {
+            push_value(&mut body, operand);
+        }
+        if let Instruction::OutputBytes(bytes) = instruction {
+            body.extend_from_slice(bytes);
+        }
+    }
+
+    let mut bytes = Vec::with_capacity(HEADER_SIZE + body.len());
+    bytes.extend_from_slice(&MAGIC);
+    bytes.extend_from_slice(&VERSION.to_le_bytes());
+    bytes.push(cell_width.bits() as u8);
+    bytes.extend_from_slice(&(instructions.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(&checksum(&body).to_le_bytes());
+    bytes.extend_from_slice(&body);
+    bytes
+}
+
+/// Reads bytecode, one instruction at a time.
+struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl Reader<'_> {
+    fn byte(&mut self) -> Result<u8, LoadError> {
+        let byte = *self.bytes.get(self.offset).ok_or(LoadError::Malformed {
+            offset: self.offset,
+        })?;
+        self.offset += 1;
+        Ok(byte)
+    }
+    fn value(&mut self) -> Result<u32, LoadError> {
+        let start = self.offset;
+        let mut value = 0;
+        for shift in (0..35).step_by(7) {
+            let byte = self.byte()?;
+            if shift == 28 && byte > 0x0f {
+                return Err(LoadError::Malformed { offset: start });
+            }
+            value |= u32::from(byte & 0x7f) << shift;
+            if byte < 0x80 {
+                break;
+            }
+        }
+        Ok(value)
+    }
+    fn offset(&mut self) -> Result<i32, LoadError> {
+        Ok(unzigzag(self.value()?))
+    }
+    fn bytes(&mut self) -> Result<Vec<u8>, LoadError> {
+        let start = self.offset;
+        let length = self.value()? as usize;
+        let bytes = self.bytes[self.offset..]
+            .get(..length)
+            .ok_or(LoadError::Malformed { offset: start })?;
+        self.offset += length;
+        Ok(bytes.to_vec())
+    }
+    fn instruction(&mut self) -> Result<Instruction, LoadError> {
+        let offset = self.offset;
+        Ok(match self.byte()? {
+            0 => Instruction::Forward(self.value()?),
+            1 => Instruction::Backward(self.value()?),
+            2 => Instruction::Increment {
+                offset: self.offset()?,
+                amount: self.value()?,
+            },
+            3 => Instruction::SetCell {
+                offset: self.offset()?,
+                value: self.value()?,
+            },
+            4 => Instruction::IncrementLoop(self.value()?),
+            5 => Instruction::MultiplyForward(self.value()?, self.value()?),
+            6 => Instruction::MultiplyBackward(self.value()?, self.value()?),
+            7 => Instruction::ForwardLoop(self.value()?),
+            8 => Instruction::BackwardLoop(self.value()?),
+            9 => Instruction::LoopStart(self.value()?),
+            10 => Instruction::LoopEnd(self.value()?),
+            11 => Instruction::Output {
+                offset: self.offset()?,
+            },
+            12 => Instruction::Input,
+            13 => Instruction::Stop,
+            14 => Instruction::IncrementForwardLoop(self.value()?, self.value()?),
+            15 => Instruction::IncrementBackwardLoop(self.value()?, self.value()?),
+            16 => Instruction::Print {
+                offset: self.offset()?,
+                value: u8::try_from(self.value()?).map_err(|_| LoadError::Malformed { offset })?,
+            },
+            17 => Instruction::OutputBytes(self.bytes()?),
+            18 => Instruction::MultiplyProduct {
+                offset: self.offset()?,
+                factor: self.offset()?,
+                multiplier: self.value()?,
+            },
+            _ => return Err(LoadError::Malformed { offset }),
+        })
+    }
+}
+
+/// Checks instructions can be executed safely: values fit in cells,
+/// loops jump to each other's ends, and the program ends with [`Instruction::Stop`].
+pub(crate) fn validate(
+    instructions: &[Instruction],
+    cell_width: CellWidth,
+) -> Result<(), LoadError> {
+    let mut loop_starts = Vec::new();
+    for (index, instruction) in instructions.iter().enumerate() {
+        match *instruction {
+            Instruction::Increment { amount: value, .. }
+            | Instruction::SetCell { value, .. }
+            | Instruction::IncrementLoop(value)
+            | Instruction::MultiplyForward(_, value)
+            | Instruction::MultiplyBackward(_, value)
+            | Instruction::MultiplyProduct {
+                multiplier: value, ..
+            }
+            | Instruction::IncrementForwardLoop(_, value)
+            | Instruction::IncrementBackwardLoop(_, value)
+                if value > cell_width.max() =>
+            {
+                return Err(LoadError::ValueOutOfRange { index });
+            }
+            Instruction::LoopStart(_) => loop_starts.push(index),
+            Instruction::LoopEnd(loop_start) => {
+                let Some(start) = loop_starts.pop() else {
+                    return Err(LoadError::InvalidJump { index });
+                };
+                if loop_start as usize != start + 1 {
+                    return Err(LoadError::InvalidJump { index });
+                }
+                if instructions[start] != Instruction::LoopStart((index + 1) as u32) {
+                    return Err(LoadError::InvalidJump { index: start });
+                }
+            }
+            _ => {}
+        }
+    }
+    if let Some(&index) = loop_starts.last() {
+        return Err(LoadError::InvalidJump { index });
+    }
+    if instructions.last() != Some(&Instruction::Stop) {
+        return Err(LoadError::MissingStop);
+    }
+    Ok(())
+}
+
+/// Loads `.bfc` bytecode, returning the instructions and the cell width they were compiled for.
+///
+/// # Errors
+///
+/// Returns an error if the header is wrong, the checksum does not match,
+/// or the instructions could not run safely, such as a loop that jumps elsewhere.
+pub fn from_bytecode(bytes: &[u8]) -> Result<(Vec<Instruction>, CellWidth), LoadError> {
+    if bytes.len() < HEADER_SIZE || bytes[..4] != MAGIC {
+        return Err(LoadError::NotBytecode);
+    }
+    let version = u16::from_le_bytes([bytes[4], bytes[5]]);
+    if version != VERSION {
+        return Err(LoadError::UnsupportedVersion(version));
+    }
+    let cell_width = match bytes[6] {
+        8 => CellWidth::U8,
+        16 => CellWidth::U16,
+        32 => CellWidth::U32,
+        bits => return Err(LoadError::UnsupportedCellWidth(bits)),
+    };
+    let count = u32::from_le_bytes([bytes[7], bytes[8], bytes[9], bytes[10]]) as usize;
+    let expected_checksum = u32::from_le_bytes([bytes[11], bytes[12], bytes[13], bytes[14]]);
+    if checksum(&bytes[HEADER_SIZE..]) != expected_checksum {
+        return Err(LoadError::ChecksumMismatch);
+    }
+
+    let mut reader = Reader {
+        bytes,
+        offset: HEADER_SIZE,
+    };
+    // Every instruction takes at least a byte, so this cannot allocate more than the input.
+    let mut instructions = Vec::with_capacity(count.min(bytes.len() - HEADER_SIZE));
+    for _ in 0..count {
+        instructions.push(reader.instruction()?);
+    }
+    if reader.offset != bytes.len() {
+        return Err(LoadError::Malformed {
+            offset: reader.offset,
+        });
+    }
+
+    validate(&instructions, cell_width)?;
+    Ok((instructions, cell_width))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        bytecode::{checksum, encode, from_bytecode, to_bytecode, LoadError, HEADER_SIZE},
+        compile::{Compiler, Instruction},
+        lexer::lex,
+        settings::CellWidth,
+    };
+
+    #[test]
+    fn checksum_works() {
+        assert_eq!(checksum(b"123456789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn round_trips() {
+        for (code, cell_width) in [
+            (include_str!("../samples/TowersOfHanoi.bf"), CellWidth::U8),
+            (include_str!("../samples/Mandelbrot.bf"), CellWidth::U16),
+            (",[->+<]>[-]+++[>>,.<<-]", CellWidth::U32),
+        ] {
+            let instructions = Compiler::new(lex(code))
+                .with_cell_width(cell_width)
+                .compile()
+                .unwrap();
+            let bytes = to_bytecode(&instructions, cell_width).unwrap();
+            assert_eq!(from_bytecode(&bytes), Ok((instructions, cell_width)));
+        }
+    }
+
+    #[test]
+    fn large_values_round_trip() {
+        let instructions = vec![
+            Instruction::Forward(u32::MAX),
+            Instruction::Increment {
+                offset: i32::MIN,
+                amount: u32::MAX,
+            },
+            Instruction::Output { offset: i32::MAX },
+            Instruction::SetCell {
+                offset: -1,
+                value: 0,
+            },
+            Instruction::MultiplyBackward(300, 1 << 31),
+            Instruction::MultiplyProduct {
+                offset: i32::MIN,
+                factor: i32::MAX,
+                multiplier: u32::MAX,
+            },
+            Instruction::IncrementBackwardLoop(u32::MAX, 1 << 31),
+            Instruction::Print {
+                offset: i32::MIN,
+                value: 255,
+            },
+            Instruction::OutputBytes((0..=255).collect()),
+            Instruction::Stop,
+        ];
+        let bytes = to_bytecode(&instructions, CellWidth::U32).unwrap();
+        assert_eq!(from_bytecode(&bytes), Ok((instructions, CellWidth::U32)));
+    }
+
+    #[test]
+    fn bad_headers_error() {
+        let bytes = to_bytecode(
+            &[Instruction::Output { offset: 0 }, Instruction::Stop],
+            CellWidth::U8,
+        )
+        .unwrap();
+
+        assert_eq!(from_bytecode(b"+[.]"), Err(LoadError::NotBytecode));
+
+        let mut version = bytes.clone();
+        version[4] = 9;
+        assert_eq!(
+            from_bytecode(&version),
+            Err(LoadError::UnsupportedVersion(9))
+        );
+
+        let mut cell_width = bytes.clone();
+        cell_width[6] = 12;
+        assert_eq!(
+            from_bytecode(&cell_width),
+            Err(LoadError::UnsupportedCellWidth(12))
+        );
+
+        let mut corrupted = bytes.clone();
+        corrupted[HEADER_SIZE] = 12;
+        assert_eq!(from_bytecode(&corrupted), Err(LoadError::ChecksumMismatch));
+
+        let mut count = bytes;
+        count[7] = 3;
+        assert_eq!(
+            from_bytecode(&count),
+            Err(LoadError::Malformed {
+                offset: HEADER_SIZE + 3
+            })
+        );
+    }
+
+    #[test]
+    fn bad_instructions_error() {
+        let load = |instructions: &[Instruction]| {
+            let checked = to_bytecode(instructions, CellWidth::U8).map(|_| ());
+            let loaded = from_bytecode(&encode(instructions, CellWidth::U8)).map(|_| ());
+            assert_eq!(checked, loaded);
+            loaded
+        };
+
+        assert_eq!(
+            load(&[
+                Instruction::LoopStart(3),
+                Instruction::Output { offset: 0 },
+                Instruction::LoopEnd(1),
+                Instruction::Stop
+            ]),
+            Ok(())
+        );
+        assert_eq!(
+            load(&[
+                Instruction::LoopStart(0),
+                Instruction::Output { offset: 0 },
+                Instruction::LoopEnd(1),
+                Instruction::Stop
+            ]),
+            Err(LoadError::InvalidJump { index: 0 })
+        );
+        assert_eq!(
+            load(&[
+                Instruction::LoopStart(3),
+                Instruction::Output { offset: 0 },
+                Instruction::LoopEnd(0),
+                Instruction::Stop
+            ]),
+            Err(LoadError::InvalidJump { index: 2 })
+        );
+        assert_eq!(
+            load(&[Instruction::LoopStart(2), Instruction::Stop]),
+            Err(LoadError::InvalidJump { index: 0 })
+        );
+        assert_eq!(
+            load(&[Instruction::LoopEnd(0), Instruction::Stop]),
+            Err(LoadError::InvalidJump { index: 0 })
+        );
+        assert_eq!(
+            load(&[
+                Instruction::SetCell {
+                    offset: 0,
+                    value: 256
+                },
+                Instruction::Stop
+            ]),
+            Err(LoadError::ValueOutOfRange { index: 0 })
+        );
+        assert_eq!(
+            load(&[Instruction::Output { offset: 0 }]),
+            Err(LoadError::MissingStop)
+        );
+    }
+}
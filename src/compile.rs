@@ -1,9 +1,18 @@
-use core::num::Wrapping;
+use core::{fmt, mem, num::TryFromIntError};
+use std::collections::{BTreeMap, HashMap};
 
-use crate::lexer::Token;
+use crate::interpreter;
+use crate::lexer::{Position, Token};
+use crate::settings::{Budget, CellWidth, Optimisations, Settings, TapePolicy};
+
+/// Most steps loops may run for while compiling, between them.
+const EVALUATION_STEPS: u64 = 1 << 20;
+
+/// Most cells a tape may have while compiling.
+const EVALUATION_CELLS: usize = 1 << 16;
 
 /// An instruction.
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Eq)]
 pub enum Instruction {
     /// Move pointer right.
     Forward(u32),
@@ -11,20 +20,44 @@ pub enum Instruction {
     /// Move pointer left.
     Backward(u32),
 
-    /// Add to cell.
-    Increment(u8),
+    /// Add to a cell.
+    Increment {
+        /// Cells right of the pointer, or left if negative.
+        offset: i32,
+
+        /// Amount to add.
+        amount: u32,
+    },
 
-    /// Set cell.
-    SetCell(u8),
+    /// Set a cell.
+    SetCell {
+        /// Cells right of the pointer, or left if negative.
+        offset: i32,
+
+        /// Value to set.
+        value: u32,
+    },
 
     /// Increment in a loop.
-    IncrementLoop(u8),
+    IncrementLoop(u32),
 
     /// Add (current cell * multiplier) to cell on the right
-    MultiplyForward(u32, u8),
+    MultiplyForward(u32, u32),
 
     /// Add (current cell * multiplier) to cell on the left
-    MultiplyBackward(u32, u8),
+    MultiplyBackward(u32, u32),
+
+    /// Add (current cell * another cell * multiplier) to a cell.
+    MultiplyProduct {
+        /// Cells right of the pointer, or left if negative, of the cell to add to.
+        offset: i32,
+
+        /// Cells right of the pointer, or left if negative, of the other cell to multiply by.
+        factor: i32,
+
+        /// Amount to multiply by as well.
+        multiplier: u32,
+    },
 
     /// Move pointer right until cell is 0.
     ForwardLoop(u32),
@@ -32,6 +65,12 @@ pub enum Instruction {
     /// Move pointer left until cell is 0.
     BackwardLoop(u32),
 
+    /// Add the second operand to cell and move pointer right by the first, until cell is 0.
+    IncrementForwardLoop(u32, u32),
+
+    /// Add the second operand to cell and move pointer left by the first, until cell is 0.
+    IncrementBackwardLoop(u32, u32),
+
     /// Jump if cell is 0.
     LoopStart(u32),
 
@@ -39,7 +78,24 @@ pub enum Instruction {
     LoopEnd(u32),
 
     /// Output a character.
-    Output,
+    Output {
+        /// Cells right of the pointer, or left if negative.
+        offset: i32,
+    },
+
+    /// Output a character known while compiling, without reading the cell it is in.
+    ///
+    /// Only made for cells known to be on the tape, so the pointer is not checked.
+    Print {
+        /// Cells right of the pointer, or left if negative, of the cell that has the character.
+        offset: i32,
+
+        /// Character to output.
+        value: u8,
+    },
+
+    /// Output characters known while compiling, all at once.
+    OutputBytes(Vec<u8>),
 
     /// Read a character from input.
     Input,
@@ -48,6 +104,68 @@ pub enum Instruction {
     Stop,
 }
 
+/// Characters of the source an instruction came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    /// First character.
+    pub start: Position,
+
+    /// Last character, inclusive.
+    pub end: Position,
+}
+
+impl Span {
+    /// Span of a single character.
+    #[must_use]
+    pub const fn at(position: Position) -> Self {
+        Self {
+            start: position,
+            end: position,
+        }
+    }
+}
+
+/// Why compiling failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompileErrorKind {
+    /// A `[` has no matching `]`.
+    UnclosedLoop,
+
+    /// A `]` has no matching `[`.
+    UnopenedLoop,
+}
+
+/// Error returned when the program is malformed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompileError {
+    /// Why compiling failed.
+    pub kind: CompileErrorKind,
+
+    /// Where the unmatched bracket is.
+    pub position: Position,
+}
+
+impl fmt::Display for CompileErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::UnclosedLoop => "unclosed loop",
+            Self::UnopenedLoop => "loop end without start",
+        })
+    }
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} at line {}, column {}",
+            self.kind, self.position.line, self.position.column
+        )
+    }
+}
+
+impl std::error::Error for CompileError {}
+
 #[derive(Debug, PartialEq)]
 enum CompilingInstruction {
     Move,
@@ -56,67 +174,464 @@ enum CompilingInstruction {
     None,
 }
 
+/// Values of cells known while compiling, like every cell being 0 at the start.
+#[derive(Debug, Clone)]
+pub(crate) struct KnownCells {
+    /// Cells set since the pointer was last lost track of, by where they are from where it was then.
+    cells: HashMap<i64, Option<u32>>,
+
+    /// Value of every other cell, if known.
+    rest: Option<u32>,
+
+    /// Where the pointer is, from where it was when it was last lost track of.
+    pointer: i64,
+
+    /// Size of a wrapping tape, where cells that many apart are the same cell.
+    wrap: Option<i64>,
+
+    /// Whether the tape has no end to the left and to the right, as it wraps or grows without limit.
+    endless: (bool, bool),
+
+    /// Largest value of a cell.
+    max: u32,
+}
+
+impl KnownCells {
+    /// Knowledge at the start of a program, where every cell is 0.
+    pub(crate) fn new(tape: TapePolicy, tape_limit: Option<usize>, cell_width: CellWidth) -> Self {
+        Self {
+            // The first cell is always on the tape.
+            cells: HashMap::from([(0, Some(0))]),
+            rest: Some(0),
+            pointer: 0,
+            wrap: match tape {
+                TapePolicy::Wrap(size) => Some(size as i64),
+                _ => None,
+            },
+            endless: match (tape, tape_limit) {
+                (TapePolicy::Wrap(_), _) | (TapePolicy::Bidirectional, None) => (true, true),
+                (TapePolicy::Grow, None) => (false, true),
+                _ => (false, false),
+            },
+            max: cell_width.max(),
+        }
+    }
+
+    fn key(&self, offset: i64) -> i64 {
+        let key = self.pointer + offset;
+        self.wrap.map_or(key, |size| key.rem_euclid(size))
+    }
+
+    /// Value of the cell `offset` cells right of the pointer, or left if negative, if known.
+    pub(crate) fn get(&self, offset: i64) -> Option<u32> {
+        self.cells
+            .get(&self.key(offset))
+            .copied()
+            .unwrap_or(self.rest)
+    }
+
+    /// Whether the cell `offset` cells right of the pointer, or left if negative, is known to be
+    /// on the tape, so moving to it cannot fail.
+    ///
+    /// Cells set since the pointer was last lost track of are on the tape, as are those right of
+    /// them when the tape grows without limit.
+    pub(crate) fn is_on_tape(&self, offset: i64) -> bool {
+        let key = self.key(offset);
+        match self.endless {
+            (true, true) => true,
+            (_, true) => self.cells.keys().any(|&set| set <= key),
+            _ => self.cells.contains_key(&key),
+        }
+    }
+
+    /// Value of the cell `offset` cells right of the pointer, or left if negative, if known and the
+    /// cell is known to be on the tape, so reading it cannot fail.
+    fn get_on_tape(&self, offset: i64) -> Option<u32> {
+        self.get(offset).filter(|_| self.is_on_tape(offset))
+    }
+
+    /// Whether the cell `offset` cells right of the pointer, or left if negative, was set since
+    /// the pointer was last lost track of.
+    fn is_set(&self, offset: i64) -> bool {
+        self.cells.contains_key(&self.key(offset))
+    }
+
+    /// Puts back a cell's value, forgetting it was set if it was not, so it is not taken to be on
+    /// the tape.
+    fn restore(&mut self, offset: i64, value: Option<u32>, set: bool) {
+        if set {
+            self.set(offset, value);
+        } else {
+            let key = self.key(offset);
+            self.cells.remove(&key);
+        }
+    }
+
+    fn set(&mut self, offset: i64, value: Option<u32>) {
+        let key = self.key(offset);
+        self.cells.insert(key, value.map(|value| value & self.max));
+    }
+
+    /// Every cell that is not 0 and where the pointer is, from where it started, if all are known.
+    fn exact(&self) -> Option<(Vec<(i64, u32)>, i64)> {
+        if self.rest != Some(0) {
+            return None;
+        }
+        let mut cells = Vec::new();
+        for (&key, &value) in &self.cells {
+            match value? {
+                0 => {}
+                value => cells.push((key, value)),
+            }
+        }
+        Some((cells, self.pointer))
+    }
+
+    /// A cell with a known value, preferably 0, nearest the pointer but not `offsets` cells from
+    /// it, for borrowing to work in.
+    ///
+    /// Only cells between those set or in `offsets` are borrowed, as others may be off the tape,
+    /// or right of them when the tape has no end to the right.
+    pub(crate) fn spare(&self, offsets: &[i64]) -> Option<(i64, u32)> {
+        let taken: Vec<i64> = offsets.iter().map(|&offset| self.key(offset)).collect();
+        let set = self.cells.keys().map(|&key| key - self.pointer);
+        let used: Vec<i64> = set.chain(offsets.iter().copied()).collect();
+        let low = used.iter().copied().min().unwrap_or(0);
+        let high = used.iter().copied().max().unwrap_or(0);
+        let mut candidates: Vec<i64> = used
+            .iter()
+            .flat_map(|&offset| [offset, offset + 1])
+            .collect();
+        candidates.sort_unstable_by_key(|&offset| (offset > high, offset.abs(), offset));
+        let mut spares = candidates
+            .into_iter()
+            .filter(|&offset| offset >= low && (offset <= high || self.endless.1))
+            .filter(|&offset| !taken.contains(&self.key(offset)))
+            .filter_map(|offset| Some((offset, self.get(offset)?)));
+        // One that is 0 does not need clearing first.
+        spares
+            .clone()
+            .find(|&(_, value)| value == 0)
+            .or_else(|| spares.next())
+    }
+
+    /// Forgets every cell, for when the pointer could be anywhere.
+    fn forget(&mut self) {
+        self.cells.clear();
+        self.rest = None;
+        self.pointer = 0;
+    }
+
+    /// Learns what running an instruction does, as long as it does not jump.
+    pub(crate) fn update(&mut self, instruction: &Instruction) {
+        match *instruction {
+            Instruction::Forward(offset) => self.pointer += i64::from(offset),
+            Instruction::Backward(offset) => self.pointer -= i64::from(offset),
+            Instruction::Increment { offset, amount } => {
+                let offset = i64::from(offset);
+                self.set(
+                    offset,
+                    self.get(offset).map(|value| value.wrapping_add(amount)),
+                );
+            }
+            Instruction::SetCell { offset, value } => self.set(i64::from(offset), Some(value)),
+            Instruction::MultiplyForward(offset, multiplier)
+            | Instruction::MultiplyBackward(offset, multiplier) => {
+                let offset = if matches!(instruction, Instruction::MultiplyForward(..)) {
+                    i64::from(offset)
+                } else {
+                    -i64::from(offset)
+                };
+                let value = self
+                    .get(0)
+                    .zip(self.get(offset))
+                    .map(|(source, target)| target.wrapping_add(source.wrapping_mul(multiplier)));
+                self.set(offset, value);
+            }
+            Instruction::MultiplyProduct {
+                offset,
+                factor,
+                multiplier,
+            } => {
+                let offset = i64::from(offset);
+                let value = self
+                    .get(0)
+                    .zip(self.get(i64::from(factor)))
+                    .zip(self.get(offset))
+                    .map(|((source, factor), target)| {
+                        target.wrapping_add(source.wrapping_mul(factor).wrapping_mul(multiplier))
+                    });
+                self.set(offset, value);
+            }
+            Instruction::IncrementLoop(_) => self.set(0, Some(0)),
+            Instruction::ForwardLoop(_)
+            | Instruction::BackwardLoop(_)
+            | Instruction::IncrementForwardLoop(..)
+            | Instruction::IncrementBackwardLoop(..)
+            | Instruction::LoopEnd(_) => {
+                self.forget();
+                self.set(0, Some(0));
+            }
+            Instruction::LoopStart(_) => {
+                // The loop's cell was checked, so it is on the tape.
+                self.forget();
+                self.set(0, None);
+            }
+            Instruction::Input => self.set(0, None),
+            Instruction::Output { .. }
+            | Instruction::Print { .. }
+            | Instruction::OutputBytes(_)
+            | Instruction::Stop => {}
+        }
+    }
+}
+
+/// A cell's value after a loop body runs once, in terms of the values cells had before it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Affine {
+    constant: u32,
+
+    /// Amount each cell's value is multiplied by, by where it is from the loop's cell.
+    terms: BTreeMap<i64, u32>,
+}
+
+impl Affine {
+    const fn constant(constant: u32) -> Self {
+        Self {
+            constant,
+            terms: BTreeMap::new(),
+        }
+    }
+
+    /// The value the cell at `position` had.
+    fn cell(position: i64) -> Self {
+        Self {
+            constant: 0,
+            terms: BTreeMap::from([(position, 1)]),
+        }
+    }
+
+    /// Adds `other` times `multiplier`.
+    fn add(&mut self, other: &Self, multiplier: u32, mask: u32) {
+        self.constant = self
+            .constant
+            .wrapping_add(other.constant.wrapping_mul(multiplier))
+            & mask;
+        for (&position, &amount) in &other.terms {
+            let term = self.terms.entry(position).or_insert(0);
+            *term = term.wrapping_add(amount.wrapping_mul(multiplier)) & mask;
+            if *term == 0 {
+                self.terms.remove(&position);
+            }
+        }
+    }
+}
+
+/// Number that an odd number multiplies with to give 1, modulo 2^32 and so every cell width.
+pub(crate) const fn inverse(odd: u32) -> u32 {
+    // Each round doubles the bits that are right, from the 3 an odd number is its own inverse to.
+    let mut inverse = odd;
+    let mut round = 0;
+    while round < 4 {
+        inverse = inverse.wrapping_mul(2u32.wrapping_sub(odd.wrapping_mul(inverse)));
+        round += 1;
+    }
+    inverse
+}
+
 /// Compiles tokens into instructions.
 pub struct Compiler<'a> {
-    tokens: Box<dyn Iterator<Item = Token> + 'a>,
+    tokens: Box<dyn Iterator<Item = (Position, Token)> + 'a>,
     instructions: Vec<Instruction>,
-    loop_stack: Vec<usize>,
+    spans: Vec<Span>,
+    /// Index and position of each open loop, with what was known before it.
+    loop_stack: Vec<(usize, Position, KnownCells)>,
     compiling_instruction: CompilingInstruction,
     value: i64,
-    cell_guarantee: Option<u8>,
+    value_span: Option<Span>,
+    last_position: Position,
+    known: KnownCells,
+    /// Whether loops at the top level are run while compiling, until the first input or
+    /// loop that cannot be.
+    evaluating: bool,
+    /// Steps left for running loops while compiling.
+    evaluation_steps: u64,
+    /// Value the current cell had before each run since the last loop or I/O, and whether it was
+    /// set, so it can be reopened.
+    run_guarantees: Vec<(Option<u32>, bool)>,
+    cell_width: CellWidth,
+    tape: TapePolicy,
+    tape_limit: Option<usize>,
+    budget: Budget,
+    optimisations: Optimisations,
 }
 
 impl<'a> Compiler<'a> {
     /// Creates a new compiler.
-    pub fn new(tokens: impl Iterator<Item = Token> + 'a) -> Self {
+    pub fn new(tokens: impl Iterator<Item = (Position, Token)> + 'a) -> Self {
         Self {
             tokens: Box::new(tokens),
             instructions: vec![],
+            spans: vec![],
             loop_stack: vec![],
             compiling_instruction: CompilingInstruction::None,
             value: 0,
-            cell_guarantee: Some(0),
+            value_span: None,
+            last_position: Position {
+                offset: 0,
+                line: 1,
+                column: 1,
+            },
+            known: KnownCells::new(TapePolicy::default(), None, CellWidth::U8),
+            evaluating: false,
+            evaluation_steps: EVALUATION_STEPS,
+            run_guarantees: vec![],
+            cell_width: CellWidth::U8,
+            tape: TapePolicy::default(),
+            tape_limit: None,
+            budget: Budget::default(),
+            optimisations: Optimisations::ALL,
         }
     }
+
+    /// Sets how many bits each cell has, so arithmetic wraps at the right place.
+    #[must_use]
+    pub fn with_cell_width(mut self, cell_width: CellWidth) -> Self {
+        self.cell_width = cell_width;
+        self
+    }
+
+    /// Sets what happens at the ends of the tape, so cells a wrapping tape makes the same are
+    /// known to be the same.
+    #[must_use]
+    pub fn with_tape(mut self, tape: TapePolicy) -> Self {
+        self.tape = tape;
+        self
+    }
+
+    /// Sets the most cells a growing tape may have, so the start of the program is not run
+    /// further while compiling than it could be.
+    #[must_use]
+    pub fn with_tape_limit(mut self, tape_limit: Option<usize>) -> Self {
+        self.tape_limit = tape_limit;
+        self
+    }
+
+    /// Sets how long the program may run for, so the start of the program is not run while
+    /// compiling, where the budget would not count the steps it takes.
+    #[must_use]
+    pub fn with_budget(mut self, budget: Budget) -> Self {
+        self.budget = budget;
+        self
+    }
+
+    /// Sets which optimisations to make, so optimiser bugs can be narrowed down.
+    #[must_use]
+    pub fn with_optimisations(mut self, optimisations: Optimisations) -> Self {
+        self.optimisations = optimisations;
+        self
+    }
+
+    /// Wraps a value to the cell width.
+    fn wrap(&self, value: i64) -> u32 {
+        (value as u32) & self.cell_width.max()
+    }
+    /// Adds to a cell, or sets it when its value is known.
+    fn add_to_cell(&self, offset: i32, amount: u32) -> Instruction {
+        let known = self.known.get(i64::from(offset));
+        match known.filter(|_| self.optimisations.known_cells) {
+            Some(value) => Instruction::SetCell {
+                offset,
+                value: value.wrapping_add(amount) & self.cell_width.max(),
+            },
+            None => Instruction::Increment { offset, amount },
+        }
+    }
+    fn push(&mut self, instruction: Instruction, span: Span) {
+        self.instructions.push(instruction);
+        self.spans.push(span);
+    }
     fn compile_compiling_instruction(&mut self) {
+        let span = self.value_span.take();
         match self.compiling_instruction {
             CompilingInstruction::None => return,
 
             CompilingInstruction::Move => {
                 if self.value != 0 {
-                    if self.value.is_positive() {
-                        self.instructions
-                            .push(Instruction::Forward(self.value as u32));
+                    let span = span.unwrap();
+                    let run_guarantee = (self.known.get(0), self.known.is_set(0));
+                    let instruction = if self.value.is_positive() {
+                        Instruction::Forward(self.value as u32)
                     } else {
-                        self.instructions
-                            .push(Instruction::Backward(self.value.unsigned_abs() as u32));
-                    }
-                    self.cell_guarantee = None;
+                        Instruction::Backward(self.value.unsigned_abs() as u32)
+                    };
+                    self.known.update(&instruction);
+                    self.push(instruction, span);
+                    self.run_guarantees.push(run_guarantee);
                 }
             }
             CompilingInstruction::Increment => {
-                if self.value != 0 {
-                    if let Some(cell_guarantee) = self.cell_guarantee {
-                        self.cell_guarantee = Some(((cell_guarantee as i64) + self.value) as u8);
-                        self.instructions
-                            .push(Instruction::SetCell(self.cell_guarantee.unwrap()));
-                    } else {
-                        self.instructions.push(if self.value.is_positive() {
-                            Instruction::Increment(self.value as u8)
-                        } else {
-                            Instruction::Increment((self.value.unsigned_abs() as u8).wrapping_neg())
-                        });
-                    }
+                if self.wrap(self.value) != 0 {
+                    let span = span.unwrap();
+                    let run_guarantee = (self.known.get(0), self.known.is_set(0));
+                    let instruction = self.add_to_cell(0, self.wrap(self.value));
+                    self.known.update(&instruction);
+                    self.push(instruction, span);
+                    self.run_guarantees.push(run_guarantee);
                 }
             }
-        };
+        }
         self.compiling_instruction = CompilingInstruction::None;
         self.value = 0;
     }
+    /// Reopens the last run when the runs after it cancelled out, so `>+-<` compiles to nothing.
+    fn resume_run(&mut self) {
+        if !self.optimisations.merge_runs {
+            return;
+        }
+        let Some(&(run_guarantee, set)) = self.run_guarantees.last() else {
+            return;
+        };
+        let value = match (&self.compiling_instruction, self.instructions.last()) {
+            (CompilingInstruction::Move, Some(Instruction::Forward(offset))) => i64::from(*offset),
+            (CompilingInstruction::Move, Some(Instruction::Backward(offset))) => {
+                -i64::from(*offset)
+            }
+            (CompilingInstruction::Increment, Some(Instruction::Increment { amount, .. })) => {
+                i64::from(*amount)
+            }
+            (CompilingInstruction::Increment, Some(Instruction::SetCell { value, .. })) => {
+                i64::from(self.wrap(i64::from(*value) - i64::from(run_guarantee.unwrap_or(0))))
+            }
+            _ => return,
+        };
+        self.instructions.pop();
+        self.value_span = self.spans.pop();
+        self.value = value;
+        if self.compiling_instruction == CompilingInstruction::Move {
+            self.known.pointer -= value;
+        } else {
+            self.known.restore(0, run_guarantee, set);
+        }
+        self.run_guarantees.pop();
+    }
+    /// Adds a merged character to the span of the instruction being compiled.
+    fn extend_value_span(&mut self, position: Position) {
+        self.value_span = Some(match self.value_span {
+            Some(span) => Span {
+                start: span.start,
+                end: position,
+            },
+            None => Span::at(position),
+        });
+    }
     fn forward_backward(&mut self, token: &Token) {
-        if self.compiling_instruction != CompilingInstruction::Move {
+        if !self.optimisations.merge_runs
+            || self.compiling_instruction != CompilingInstruction::Move
+        {
             self.compile_compiling_instruction();
             self.compiling_instruction = CompilingInstruction::Move;
+            self.resume_run();
         }
         self.value += if matches!(token, Token::Forward) {
             1
@@ -125,9 +640,12 @@ impl<'a> Compiler<'a> {
         };
     }
     fn increment_decrement(&mut self, token: &Token) {
-        if self.compiling_instruction != CompilingInstruction::Increment {
+        if !self.optimisations.merge_runs
+            || self.compiling_instruction != CompilingInstruction::Increment
+        {
             self.compile_compiling_instruction();
             self.compiling_instruction = CompilingInstruction::Increment;
+            self.resume_run();
         }
         self.value += if matches!(token, Token::Increment) {
             1
@@ -135,10 +653,11 @@ impl<'a> Compiler<'a> {
             -1
         };
     }
-    fn start_loop(&mut self) {
-        if self.cell_guarantee == Some(0) {
+    fn start_loop(&mut self, position: Position) -> Result<(), CompileError> {
+        if self.optimisations.known_cells && self.known.get_on_tape(0) == Some(0) {
+            // Runs either side of a loop that never runs can still merge.
             let mut count = 1;
-            for token in self.tokens.by_ref() {
+            for (_, token) in self.tokens.by_ref() {
                 match token {
                     Token::LoopStart => {
                         count += 1;
@@ -149,171 +668,373 @@ impl<'a> Compiler<'a> {
                     _ => {}
                 }
                 if count == 0 {
-                    return;
+                    return Ok(());
                 }
             }
-            panic!("Unclosed loop")
+            Err(CompileError {
+                kind: CompileErrorKind::UnclosedLoop,
+                position,
+            })
         } else {
-            self.loop_stack.push(self.instructions.len());
-            self.instructions.push(Instruction::LoopStart(0)); // temp 0
-            self.cell_guarantee = None;
+            self.run_guarantees.clear();
+            // Nothing is known inside the loop, so what was known before it is put aside.
+            let mut inside = KnownCells {
+                cells: HashMap::new(),
+                ..self.known
+            };
+            inside.update(&Instruction::LoopStart(0));
+            let known = mem::replace(&mut self.known, inside);
+            self.loop_stack
+                .push((self.instructions.len(), position, known));
+            self.push(Instruction::LoopStart(0), Span::at(position)); // temp 0
+            Ok(())
         }
     }
-    fn end_loop(&mut self) {
-        let loop_start = self.loop_stack.pop().expect("loop end without start"); // Index of loop start instruction
+    #[allow(clippy::too_many_lines)]
+    fn end_loop(&mut self, position: Position) -> Result<(), CompileError> {
+        self.run_guarantees.clear();
+        // Index of loop start instruction
+        let Some((loop_start, _, known)) = self.loop_stack.pop() else {
+            return Err(CompileError {
+                kind: CompileErrorKind::UnopenedLoop,
+                position,
+            });
+        };
 
         let loop_end = self.instructions.len(); // Index of loop end instruction
 
+        // Span of the whole loop, for when it is replaced.
+        let loop_span = Span {
+            start: self.spans[loop_start].start,
+            end: position,
+        };
+
         if loop_end - loop_start - 1 == 0 {
-            self.instructions
-                .push(Instruction::LoopEnd((loop_start + 1) as u32));
-            return;
+            self.push(
+                Instruction::LoopEnd((loop_start + 1) as u32),
+                Span::at(position),
+            );
+            self.instructions[loop_start] = Instruction::LoopStart((loop_end + 1) as u32);
+            self.known.update(&Instruction::LoopEnd(0));
+            return Ok(());
         }
         if loop_end - loop_start - 1 == 1 {
             // Only one type of instruction there
-            self.instructions[loop_start] = match *self.instructions.get(loop_start + 1).unwrap() {
-                Instruction::Increment(value) => {
+            let instruction = match *self.instructions.get(loop_start + 1).unwrap() {
+                Instruction::Increment { offset: 0, amount } if self.optimisations.clear_loops => {
                     self.instructions.remove(loop_start + 1);
+                    self.spans.remove(loop_start + 1);
 
-                    if value == 1 || value == u8::MAX {
-                        Instruction::SetCell(0)
+                    if amount == 1 || amount == self.cell_width.max() {
+                        Instruction::SetCell {
+                            offset: 0,
+                            value: 0,
+                        }
                     } else {
-                        Instruction::IncrementLoop(value)
+                        Instruction::IncrementLoop(amount)
                     }
                 }
-                Instruction::Forward(offset) => {
+                Instruction::Forward(offset) if self.optimisations.scan_loops => {
                     self.instructions.remove(loop_start + 1);
+                    self.spans.remove(loop_start + 1);
 
                     Instruction::ForwardLoop(offset)
                 }
-                Instruction::Backward(offset) => {
+                Instruction::Backward(offset) if self.optimisations.scan_loops => {
                     self.instructions.remove(loop_start + 1);
+                    self.spans.remove(loop_start + 1);
 
                     Instruction::BackwardLoop(offset)
                 }
                 _ => {
-                    self.instructions
-                        .push(Instruction::LoopEnd((loop_start + 1) as u32));
-                    Instruction::LoopStart((loop_end + 1) as u32)
+                    self.push(
+                        Instruction::LoopEnd((loop_start + 1) as u32),
+                        Span::at(position),
+                    );
+                    self.instructions[loop_start] = Instruction::LoopStart((loop_end + 1) as u32);
+                    self.known.update(&Instruction::LoopEnd(0));
+                    return Ok(());
                 }
-            }
+            };
+            self.known = known;
+            self.known.update(&instruction);
+            self.instructions[loop_start] = instruction;
+            self.spans[loop_start] = loop_span;
+        } else if let Some(instruction) = self.increment_scan(loop_start, loop_end) {
+            self.instructions.truncate(loop_start);
+            self.spans.truncate(loop_start);
+            self.known.update(&instruction);
+            self.push(instruction, loop_span);
         } else {
-            let multipliers = 'out: {
-                let mut all_increments: Vec<(isize, Wrapping<u8>)> = Vec::new();
-                let mut total_offset: isize = 0;
-                let mut total_increment: Wrapping<u8> = Wrapping(0);
-
-                for index in loop_start + 1..loop_end {
-                    // Iterate all instructions inside the loop
-                    match self.instructions.get(index).unwrap() {
-                        inner @ (Instruction::Forward(offset) | Instruction::Backward(offset)) => {
-                            if total_increment != Wrapping(0) {
-                                let already =
-                                    all_increments.iter().position(|t| t.0 == total_offset);
-                                if let Some(already) = already {
-                                    all_increments.get_mut(already).unwrap().1 += total_increment;
-                                } else {
-                                    all_increments.push((total_offset, total_increment));
-                                }
-
-                                total_increment = Wrapping(0);
-                            }
-                            if matches!(inner, Instruction::Forward(_)) {
-                                total_offset += *offset as isize;
-                            } else {
-                                total_offset -= *offset as isize;
+            let mask = self.cell_width.max();
+            if let Some(multipliers) = self.multiply_loop(loop_start, loop_end, &known) {
+                self.instructions.truncate(loop_start);
+                self.spans.truncate(loop_start);
+
+                self.known = known;
+                let source = self.known.get(0).filter(|_| self.optimisations.known_cells);
+                for (offset, factor, multiplier) in multipliers {
+                    let value = source.and_then(|source| match factor {
+                        Some(factor) => Some(source.wrapping_mul(self.known.get(factor)?)),
+                        None => Some(source),
+                    });
+                    let instruction = match (value, i32::try_from(offset), factor) {
+                        // Multiplying a known value is just adding.
+                        (Some(value), Ok(offset), _) => {
+                            let amount = value.wrapping_mul(multiplier) & mask;
+                            if amount == 0 {
+                                continue;
                             }
+                            self.add_to_cell(offset, amount)
                         }
-                        Instruction::Increment(increment) => {
-                            total_increment += increment;
+                        (_, Ok(offset), Some(factor)) => Instruction::MultiplyProduct {
+                            offset,
+                            factor: factor as i32,
+                            multiplier,
+                        },
+                        _ if offset.is_positive() => {
+                            Instruction::MultiplyForward(offset as u32, multiplier)
                         }
-                        _ => break 'out None,
-                    }
+                        _ => {
+                            Instruction::MultiplyBackward(offset.unsigned_abs() as u32, multiplier)
+                        }
+                    };
+                    self.known.update(&instruction);
+                    self.push(instruction, loop_span);
                 }
-                if total_increment != Wrapping(0) {
-                    let already = all_increments.iter().position(|t| t.0 == total_offset);
-                    if let Some(already) = already {
-                        all_increments.get_mut(already).unwrap().1 += total_increment;
-                    } else {
-                        all_increments.push((total_offset, total_increment));
-                    }
+                let clear = Instruction::SetCell {
+                    offset: 0,
+                    value: 0,
+                };
+                self.known.update(&clear);
+                self.push(clear, loop_span);
+            } else {
+                self.push(
+                    Instruction::LoopEnd((loop_start + 1) as u32),
+                    Span::at(position),
+                );
+                self.instructions[loop_start] = Instruction::LoopStart((loop_end + 1) as u32);
+                self.known.update(&Instruction::LoopEnd(0));
+            }
+        }
 
-                    #[allow(unused_assignments)]
-                    {
-                        total_increment = Wrapping(0);
-                    }
-                }
+        Ok(())
+    }
 
-                if !all_increments.is_empty() && // There are multipliers
-                    total_offset == 0
-                // We are on the starting cell
-                {
-                    for (index, increment) in all_increments.iter().enumerate() {
-                        if increment.0 == 0 {
-                            if increment.1 == Wrapping(u8::MAX) {
-                                all_increments.swap_remove(index);
+    /// Recognises a loop that counts its cell down to 0 by an odd step, adding the same amounts
+    /// to other cells each time, like `[->+<]`, `[--->+<]` or `[->[->+>+<<]>>[-<<+>>]<<<]`.
+    ///
+    /// Returns what each cell gains for every step the loop's cell is counted down by: the
+    /// multiplier, times another cell the loop leaves alone if there is one.
+    #[allow(clippy::too_many_lines)]
+    fn multiply_loop(
+        &self,
+        loop_start: usize,
+        loop_end: usize,
+        known: &KnownCells,
+    ) -> Option<Vec<(i64, Option<i64>, u32)>> {
+        if !self.optimisations.multiply_loops {
+            return None;
+        }
+        let mask = self.cell_width.max();
 
-                                break 'out Some(all_increments);
-                            }
-                            break;
-                        }
-                    }
+        let mut cells: BTreeMap<i64, Affine> = BTreeMap::new();
+        let mut pointer: i64 = 0;
+        for instruction in &self.instructions[loop_start + 1..loop_end] {
+            match *instruction {
+                Instruction::Forward(offset) => pointer += i64::from(offset),
+                Instruction::Backward(offset) => pointer -= i64::from(offset),
+                Instruction::Increment { offset, amount } => {
+                    let value = cells
+                        .entry(pointer + i64::from(offset))
+                        .or_insert_with_key(|&position| Affine::cell(position));
+                    value.constant = value.constant.wrapping_add(amount) & mask;
                 }
+                Instruction::SetCell { offset, value } => {
+                    cells.insert(pointer + i64::from(offset), Affine::constant(value));
+                }
+                Instruction::MultiplyForward(offset, multiplier)
+                | Instruction::MultiplyBackward(offset, multiplier) => {
+                    let source = cells
+                        .get(&pointer)
+                        .cloned()
+                        .unwrap_or_else(|| Affine::cell(pointer));
+                    let target = if matches!(instruction, Instruction::MultiplyForward(..)) {
+                        pointer + i64::from(offset)
+                    } else {
+                        pointer - i64::from(offset)
+                    };
+                    cells
+                        .entry(target)
+                        .or_insert_with_key(|&position| Affine::cell(position))
+                        .add(&source, multiplier, mask);
+                }
+                _ => return None,
+            }
+        }
+        if pointer != 0 {
+            return None;
+        }
 
-                None
-            };
-
-            if let Some(mut multipliers) = multipliers {
-                self.instructions.truncate(loop_start);
+        // An odd step reaches 0 from any value, after the value divided by minus the step passes.
+        let step = match cells.remove(&0) {
+            Some(Affine { constant, terms })
+                if constant % 2 == 1 && terms == BTreeMap::from([(0, 1)]) =>
+            {
+                constant
+            }
+            _ => return None,
+        };
+        let passes = inverse(step).wrapping_neg() & mask;
 
-                multipliers.retain_mut(|t| t.1 != Wrapping(0));
+        // Cells set to a value they already had are the same on every pass.
+        let mut constants = BTreeMap::new();
+        for (&position, value) in &cells {
+            if value.terms.is_empty() {
+                let known = known
+                    .get(position)
+                    .filter(|_| self.optimisations.known_cells);
+                if known != Some(value.constant) {
+                    return None;
+                }
+                constants.insert(position, value.constant);
+            }
+        }
+        let mut gains = BTreeMap::new();
+        for (&position, value) in &cells {
+            if value.terms.is_empty() {
+                continue;
+            }
+            let mut gain = Affine::constant(value.constant);
+            for (&factor, &amount) in &value.terms {
+                match constants.get(&factor) {
+                    Some(&constant) => gain.add(&Affine::constant(constant), amount, mask),
+                    None => gain.add(&Affine::cell(factor), amount, mask),
+                }
+            }
+            // The cell keeps its value, adding the same each pass.
+            if gain.terms.remove(&position) != Some(1) {
+                return None;
+            }
+            if gain != Affine::constant(0) {
+                gains.insert(position, gain);
+            }
+        }
 
-                multipliers.sort_unstable_by_key(|t| -t.0);
-                for (offset, multiplier) in multipliers {
-                    if offset.is_positive() {
-                        self.instructions
-                            .push(Instruction::MultiplyForward(offset as u32, multiplier.0));
-                    } else {
-                        self.instructions.push(Instruction::MultiplyBackward(
-                            offset.unsigned_abs() as u32,
-                            multiplier.0,
-                        ));
-                    }
+        let mut multipliers = Vec::new();
+        for (&position, gain) in &gains {
+            if gain.constant != 0 {
+                multipliers.push((position, None, gain.constant.wrapping_mul(passes) & mask));
+            }
+            for (&factor, &amount) in &gain.terms {
+                // Cells that change cannot be multiplied by, as they differ from pass to pass.
+                if factor == 0
+                    || gains.contains_key(&factor)
+                    || i32::try_from(position).is_err()
+                    || i32::try_from(factor).is_err()
+                {
+                    return None;
                 }
-                self.instructions.push(Instruction::SetCell(0));
-            } else {
-                self.instructions
-                    .push(Instruction::LoopEnd((loop_start + 1) as u32));
-                self.instructions[loop_start] = Instruction::LoopStart((loop_end + 1) as u32);
+                // A known count turns these into additions only if the factor is known too.
+                if self.optimisations.known_cells
+                    && known.get(0).is_some()
+                    && known.get(factor).is_none()
+                {
+                    return None;
+                }
+                multipliers.push((position, Some(factor), amount.wrapping_mul(passes) & mask));
             }
-        };
+        }
+        multipliers.sort_unstable_by_key(|&(position, factor, _)| (-position, factor));
+        Some(multipliers)
+    }
 
-        self.cell_guarantee = Some(0);
+    /// Recognises a loop that adds to each cell as it scans, like `[-<]`.
+    fn increment_scan(&self, loop_start: usize, loop_end: usize) -> Option<Instruction> {
+        if !self.optimisations.scan_loops {
+            return None;
+        }
+        match self.instructions[loop_start + 1..loop_end] {
+            [Instruction::Increment { offset: 0, amount }, Instruction::Forward(offset)] => {
+                Some(Instruction::IncrementForwardLoop(offset, amount))
+            }
+            [Instruction::Increment { offset: 0, amount }, Instruction::Backward(offset)] => {
+                Some(Instruction::IncrementBackwardLoop(offset, amount))
+            }
+            _ => None,
+        }
     }
 
     /// Compile into instructions.
-    pub fn compile(&mut self) -> &Vec<Instruction> {
-        while let Some(token) = self.tokens.next() {
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a loop is left unclosed or closed without being opened.
+    pub fn compile(self) -> Result<Vec<Instruction>, CompileError> {
+        Ok(self.compile_with_spans()?.0)
+    }
+
+    /// Compile into instructions, along with the span of source each instruction came from.
+    ///
+    /// Instructions merged from a loop share the span of the whole loop.
+    /// [`Instruction::Stop`] has the span of the last character.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a loop is left unclosed or closed without being opened.
+    pub fn compile_with_spans(mut self) -> Result<(Vec<Instruction>, Vec<Span>), CompileError> {
+        self.known = KnownCells::new(self.tape, self.tape_limit, self.cell_width);
+        let evaluate = self.optimisations.evaluate_prefix && self.budget == Budget::default();
+        self.evaluating = evaluate;
+        while let Some((position, token)) = self.tokens.next() {
+            self.last_position = position;
             match token {
-                Token::Increment | Token::Decrement => self.increment_decrement(&token),
-                Token::Forward | Token::Backward => self.forward_backward(&token),
+                Token::Increment | Token::Decrement => {
+                    self.increment_decrement(&token);
+                    self.extend_value_span(position);
+                }
+                Token::Forward | Token::Backward => {
+                    self.forward_backward(&token);
+                    self.extend_value_span(position);
+                }
 
                 Token::LoopStart => {
                     self.compile_compiling_instruction();
-                    self.start_loop();
+                    self.start_loop(position)?;
                 }
                 Token::LoopEnd => {
                     self.compile_compiling_instruction();
-                    self.end_loop();
+                    let exact = match self.loop_stack.as_slice() {
+                        [(loop_start, _, known)] if self.evaluating => {
+                            known.exact().map(|exact| (*loop_start, exact))
+                        }
+                        _ => None,
+                    };
+                    self.end_loop(position)?;
+                    if let Some((loop_start, (cells, pointer))) = exact {
+                        self.evaluate_loop(loop_start, cells, pointer, position);
+                    }
                 }
                 Token::Input => {
                     self.compile_compiling_instruction();
-                    self.instructions.push(Instruction::Input);
-                    self.cell_guarantee = None;
+                    self.evaluating = false;
+                    self.push(Instruction::Input, Span::at(position));
+                    self.run_guarantees.clear();
+                    self.known.update(&Instruction::Input);
                 }
                 Token::Output => {
                     self.compile_compiling_instruction();
-                    self.instructions.push(Instruction::Output);
+                    // Printing cannot check the pointer, so only cells known to be on the tape are printed.
+                    let known = self.known.get_on_tape(0);
+                    let instruction = match known.filter(|_| self.optimisations.known_cells) {
+                        Some(value) => Instruction::Print {
+                            offset: 0,
+                            value: value as u8,
+                        },
+                        None => Instruction::Output { offset: 0 },
+                    };
+                    self.push(instruction, Span::at(position));
+                    self.run_guarantees.clear();
                 }
 
                 Token::Comment => {}
@@ -321,31 +1042,313 @@ impl<'a> Compiler<'a> {
         }
 
         self.compile_compiling_instruction();
-        self.instructions.push(Instruction::Stop);
+        self.push(Instruction::Stop, Span::at(self.last_position));
 
-        assert!(self.loop_stack.is_empty(), "Unclosed loop");
+        if let Some(&(_, position, _)) = self.loop_stack.last() {
+            return Err(CompileError {
+                kind: CompileErrorKind::UnclosedLoop,
+                position,
+            });
+        }
 
-        &self.instructions
+        if self.optimisations.fold_moves {
+            self.fold_moves();
+        }
+        if evaluate {
+            self.evaluate_prefix();
+        }
+        Ok((self.instructions, self.spans))
+    }
+
+    /// Defers pointer moves to the end of each stretch of code without loops or input,
+    /// giving the instructions in between the offset of their cell instead.
+    fn fold_moves(&mut self) {
+        let instructions = mem::take(&mut self.instructions);
+        let spans = mem::take(&mut self.spans);
+
+        // Pointer move not made yet, with the span of the moves that make it up.
+        let mut pending: i64 = 0;
+        let mut pending_span: Option<Span> = None;
+        let mut loop_stack = Vec::new();
+        for (instruction, span) in instructions.into_iter().zip(spans) {
+            let step = match instruction {
+                Instruction::Forward(offset) => i64::from(offset),
+                Instruction::Backward(offset) => -i64::from(offset),
+                _ => 0,
+            };
+            if step != 0 {
+                // Keep offsets in range of `i32` by moving early.
+                if i32::try_from(pending + step).is_err() {
+                    self.push_move(pending, pending_span.take());
+                    pending = 0;
+                }
+                pending += step;
+                pending_span = Some(match pending_span {
+                    Some(pending_span) => Span {
+                        start: pending_span.start,
+                        end: span.end,
+                    },
+                    None => span,
+                });
+                continue;
+            }
+
+            // Additions from multiplying known values already have an offset.
+            let own_offset = match instruction {
+                Instruction::Increment { offset, .. }
+                | Instruction::SetCell { offset, .. }
+                | Instruction::Output { offset }
+                | Instruction::Print { offset, .. } => i64::from(offset),
+                _ => 0,
+            };
+            if i32::try_from(pending + own_offset).is_err() {
+                self.push_move(pending, pending_span.take());
+                pending = 0;
+            }
+            let offset = (pending + own_offset) as i32;
+            let instruction = match instruction {
+                Instruction::Increment { amount, .. } => Instruction::Increment { offset, amount },
+                Instruction::SetCell { value, .. } => Instruction::SetCell { offset, value },
+                Instruction::Output { .. } => Instruction::Output { offset },
+                Instruction::Print { value, .. } => Instruction::Print { offset, value },
+                instruction => {
+                    self.push_move(pending, pending_span.take());
+                    pending = 0;
+                    match instruction {
+                        Instruction::LoopStart(_) => {
+                            loop_stack.push(self.instructions.len());
+                            instruction
+                        }
+                        Instruction::LoopEnd(_) => {
+                            let loop_start = loop_stack.pop().unwrap();
+                            self.instructions[loop_start] =
+                                Instruction::LoopStart((self.instructions.len() + 1) as u32);
+                            Instruction::LoopEnd((loop_start + 1) as u32)
+                        }
+                        instruction => instruction,
+                    }
+                }
+            };
+            self.push(instruction, span);
+        }
+    }
+
+    /// Settings to run parts of the program with while compiling, unless the tape is too big.
+    fn evaluation_settings(&self) -> Option<Settings> {
+        let settings = Settings {
+            cell_width: self.cell_width,
+            tape: self.tape,
+            budget: Budget {
+                max_steps: Some(self.evaluation_steps),
+                timeout: None,
+            },
+            tape_limit: Some(self.tape_limit.unwrap_or(usize::MAX).min(EVALUATION_CELLS)),
+            ..Settings::default()
+        };
+        (settings.initial_tape_size() <= EVALUATION_CELLS).then_some(settings)
+    }
+
+    /// Runs a loop that has just ended, when every cell was known before it, and replaces it
+    /// with what it prints and the cells it changes, so every cell is still known after it.
+    ///
+    /// Once a loop cannot be run, like when it never ends, no later loops are.
+    fn evaluate_loop(
+        &mut self,
+        loop_start: usize,
+        cells: Vec<(i64, u32)>,
+        pointer: i64,
+        position: Position,
+    ) {
+        self.evaluating = false;
+        let Some(settings) = self.evaluation_settings() else {
+            return;
+        };
+        let Some(evaluation) =
+            interpreter::evaluate(&self.instructions, loop_start, &cells, pointer, &settings)
+        else {
+            return;
+        };
+        self.evaluation_steps -= evaluation.steps;
+
+        // Set the cells whose values changed, from where the pointer is.
+        let before: HashMap<i64, u32> = cells.into_iter().collect();
+        let after: HashMap<i64, u32> = evaluation.cells.iter().copied().collect();
+        let mut changed: Vec<i64> = before.keys().chain(after.keys()).copied().collect();
+        changed.sort_unstable();
+        changed.dedup();
+        changed.retain(|position| before.get(position) != after.get(position));
+        let Ok(set_cells) = changed
+            .into_iter()
+            .map(|position| {
+                Ok(Instruction::SetCell {
+                    offset: i32::try_from(position - pointer)?,
+                    value: after.get(&position).copied().unwrap_or(0),
+                })
+            })
+            .collect::<Result<Vec<_>, TryFromIntError>>()
+        else {
+            return;
+        };
+
+        self.instructions.truncate(loop_start);
+        let mut start = self.spans[loop_start].start;
+        self.spans.truncate(loop_start);
+        // Cells set just before the loop and changed by it are set again after it.
+        while let Some(Instruction::SetCell { offset, .. }) = self.instructions.last() {
+            if !set_cells.iter().any(|set_cell| {
+                matches!(set_cell, Instruction::SetCell { offset: changed, .. } if changed == offset)
+            }) {
+                break;
+            }
+            self.instructions.pop();
+            start = self.spans.pop().map_or(start, |span| span.start);
+        }
+        let span = Span {
+            start,
+            end: position,
+        };
+        if !evaluation.output.is_empty() {
+            self.push(Instruction::OutputBytes(evaluation.output), span);
+        }
+        for instruction in set_cells {
+            self.push(instruction, span);
+        }
+        self.push_move(evaluation.pointer - pointer, Some(span));
+        self.known = KnownCells::new(self.tape, self.tape_limit, self.cell_width);
+        self.known.cells.extend(
+            evaluation
+                .cells
+                .into_iter()
+                .map(|(key, value)| (key, Some(value))),
+        );
+        self.known.pointer = evaluation.pointer;
+        self.evaluating = true;
+    }
+
+    /// Replaces the instructions at the start of the program, up to the first loop or input,
+    /// with what they print and the cells and pointer they leave.
+    fn evaluate_prefix(&mut self) {
+        let Some(settings) = self.evaluation_settings() else {
+            return;
+        };
+        let (length, evaluation) = interpreter::evaluate_prefix(&self.instructions, &settings);
+        if length == 0 {
+            return;
+        }
+
+        let instructions = mem::take(&mut self.instructions);
+        let spans = mem::take(&mut self.spans);
+        let span = Span {
+            start: spans[0].start,
+            end: spans[length - 1].end,
+        };
+        if !evaluation.output.is_empty() {
+            self.push(Instruction::OutputBytes(evaluation.output), span);
+        }
+        for (offset, value) in evaluation.cells {
+            let offset = offset as i32;
+            self.push(Instruction::SetCell { offset, value }, span);
+        }
+        self.push_move(evaluation.pointer, Some(span));
+
+        let shift = self.instructions.len() as i64 - length as i64;
+        let shifted = |target: u32| (i64::from(target) + shift) as u32;
+        for (instruction, span) in instructions.into_iter().zip(spans).skip(length) {
+            let instruction = match instruction {
+                Instruction::LoopStart(loop_exit) => Instruction::LoopStart(shifted(loop_exit)),
+                Instruction::LoopEnd(loop_body) => Instruction::LoopEnd(shifted(loop_body)),
+                instruction => instruction,
+            };
+            self.push(instruction, span);
+        }
+    }
+
+    /// Pushes a move deferred by [`Self::fold_moves`].
+    fn push_move(&mut self, offset: i64, span: Option<Span>) {
+        let Some(span) = span else {
+            return;
+        };
+        if offset.is_positive() {
+            self.push(Instruction::Forward(offset as u32), span);
+        } else if offset.is_negative() {
+            self.push(Instruction::Backward(offset.unsigned_abs() as u32), span);
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
     use crate::{
-        compile::{Compiler, Instruction},
+        compile::{CompileErrorKind, Compiler, Instruction},
         lexer::lex,
+        settings::{Optimisations, TapePolicy},
+    };
+
+    /// Every optimisation but running the start of the program, which would leave little to check.
+    const UNEVALUATED: Optimisations = Optimisations {
+        evaluate_prefix: false,
+        ..Optimisations::ALL
     };
 
     macro_rules! compile {
         ($code:expr) => {
-            Compiler::new(lex($code)).compile()
+            Compiler::new(lex($code))
+                .with_optimisations(UNEVALUATED)
+                .compile()
+                .unwrap()
         };
     }
 
+    #[test]
+    fn unclosed_loop_errors() {
+        let error = Compiler::new(lex(",\n>[+[-]")).compile().unwrap_err();
+        assert_eq!(error.kind, CompileErrorKind::UnclosedLoop);
+        assert_eq!(error.position.offset, 3);
+        assert_eq!((error.position.line, error.position.column), (2, 2));
+
+        let error = Compiler::new(lex("[[+]")).compile().unwrap_err();
+        assert_eq!(error.kind, CompileErrorKind::UnclosedLoop);
+        assert_eq!(error.position.offset, 0);
+    }
+
+    #[test]
+    fn unopened_loop_errors() {
+        let error = Compiler::new(lex("+\n+-]")).compile().unwrap_err();
+        assert_eq!(error.kind, CompileErrorKind::UnopenedLoop);
+        assert_eq!(error.position.offset, 4);
+        assert_eq!((error.position.line, error.position.column), (2, 3));
+    }
+
+    #[test]
+    fn spans_work() {
+        let (instructions, spans) = Compiler::new(lex(",>>,\n[->+<].#"))
+            .compile_with_spans()
+            .unwrap();
+        assert_eq!(instructions.len(), spans.len());
+
+        let columns: Vec<_> = spans
+            .iter()
+            .map(|span| (span.start.line, span.start.column, span.end.column))
+            .collect();
+        // Input, Forward, Input, the multiply loop as MultiplyForward and SetCell, Print, then Stop.
+        assert_eq!(
+            columns,
+            [
+                (1, 1, 1),
+                (1, 2, 3),
+                (1, 4, 4),
+                (2, 1, 6),
+                (2, 1, 6),
+                (2, 7, 7),
+                (2, 8, 8)
+            ]
+        );
+    }
+
     #[test]
     fn set_works() {
         assert!(
-            matches!(compile!(",[-].")[1], Instruction::SetCell(0)),
+            matches!(compile!(",[-].")[1], Instruction::SetCell { value: 0, .. }),
             "did not optimise zero"
         );
     }
@@ -355,11 +1358,257 @@ mod tests {
         assert!(
             compile!(".[+].[-].[>].-+><.")
                 .iter()
-                .all(|instruction| matches!(instruction, Instruction::Output | Instruction::Stop)),
+                .all(|instruction| matches!(
+                    instruction,
+                    Instruction::Print { .. } | Instruction::Stop
+                )),
             "did not remove dead code"
         );
     }
 
+    #[test]
+    fn known_cells_work() {
+        assert_eq!(
+            compile!("++[->+++<]>."),
+            [
+                Instruction::SetCell {
+                    offset: 0,
+                    value: 2
+                },
+                Instruction::SetCell {
+                    offset: 1,
+                    value: 6
+                },
+                Instruction::SetCell {
+                    offset: 0,
+                    value: 0
+                },
+                Instruction::Print {
+                    offset: 1,
+                    value: 6
+                },
+                Instruction::Forward(1),
+                Instruction::Stop,
+            ]
+        );
+        assert_eq!(
+            compile!(",>[-]>[<]<<[.,]+."),
+            [
+                Instruction::Input,
+                Instruction::LoopStart(5),
+                Instruction::Output { offset: 0 },
+                Instruction::Input,
+                Instruction::LoopEnd(2),
+                Instruction::SetCell {
+                    offset: 0,
+                    value: 1
+                },
+                Instruction::Print {
+                    offset: 0,
+                    value: 1
+                },
+                Instruction::Stop,
+            ]
+        );
+
+        // Cells two apart are the same cell on a tape of two.
+        let compile = |tape| {
+            Compiler::new(lex("+>>."))
+                .with_tape(tape)
+                .with_optimisations(UNEVALUATED)
+                .compile()
+                .unwrap()
+        };
+        assert!(matches!(
+            compile(TapePolicy::Wrap(2))[1],
+            Instruction::Print { value: 1, .. }
+        ));
+        assert!(matches!(
+            compile(TapePolicy::Grow)[1],
+            Instruction::Print { value: 0, .. }
+        ));
+    }
+
+    #[test]
+    fn evaluation_works() {
+        let compile = |code| Compiler::new(lex(code)).compile().unwrap();
+        assert_eq!(
+            compile("+++[>++[>+++<-]<-]>>.,"),
+            [
+                Instruction::OutputBytes(vec![18]),
+                Instruction::SetCell {
+                    offset: 2,
+                    value: 18
+                },
+                Instruction::Forward(2),
+                Instruction::Input,
+                Instruction::Stop,
+            ]
+        );
+
+        // Output before an instruction that fails is kept, and the rest runs as usual.
+        assert_eq!(
+            compile(".<+"),
+            [
+                Instruction::OutputBytes(vec![0]),
+                Instruction::SetCell {
+                    offset: -1,
+                    value: 1
+                },
+                Instruction::Backward(1),
+                Instruction::Stop,
+            ]
+        );
+
+        // A loop that never ends is left to run, along with everything after it.
+        assert_eq!(
+            compile("+[]+[.]"),
+            [
+                Instruction::SetCell {
+                    offset: 0,
+                    value: 1
+                },
+                Instruction::LoopStart(3),
+                Instruction::LoopEnd(2),
+                Instruction::SetCell {
+                    offset: 0,
+                    value: 1
+                },
+                Instruction::LoopStart(7),
+                Instruction::Output { offset: 0 },
+                Instruction::LoopEnd(5),
+                Instruction::Stop,
+            ]
+        );
+    }
+
+    #[test]
+    fn optimisations_can_be_turned_off() {
+        let compile = |code, optimisations| {
+            Compiler::new(lex(code))
+                .with_optimisations(optimisations)
+                .compile()
+                .unwrap()
+        };
+        assert_eq!(
+            compile("++>-[-][>]", Optimisations::NONE),
+            [
+                Instruction::Increment {
+                    offset: 0,
+                    amount: 1
+                },
+                Instruction::Increment {
+                    offset: 0,
+                    amount: 1
+                },
+                Instruction::Forward(1),
+                Instruction::Increment {
+                    offset: 0,
+                    amount: 255
+                },
+                Instruction::LoopStart(7),
+                Instruction::Increment {
+                    offset: 0,
+                    amount: 255
+                },
+                Instruction::LoopEnd(5),
+                Instruction::LoopStart(10),
+                Instruction::Forward(1),
+                Instruction::LoopEnd(8),
+                Instruction::Stop,
+            ]
+        );
+        assert_eq!(
+            compile("[.]++[->+<]", Optimisations::level(1)),
+            [
+                Instruction::LoopStart(3),
+                Instruction::Output { offset: 0 },
+                Instruction::LoopEnd(1),
+                Instruction::Increment {
+                    offset: 0,
+                    amount: 2
+                },
+                Instruction::LoopStart(10),
+                Instruction::Increment {
+                    offset: 0,
+                    amount: 255
+                },
+                Instruction::Forward(1),
+                Instruction::Increment {
+                    offset: 0,
+                    amount: 1
+                },
+                Instruction::Backward(1),
+                Instruction::LoopEnd(5),
+                Instruction::Stop,
+            ]
+        );
+    }
+
+    #[test]
+    fn empty_loop_works() {
+        assert!(
+            matches!(compile!(",[]")[1], Instruction::LoopStart(3)),
+            "did not jump past empty loop"
+        );
+        assert!(
+            matches!(
+                compile!(",>+-<").as_slice(),
+                [Instruction::Input, Instruction::Stop]
+            ),
+            "did not merge moves around cancelled increments"
+        );
+    }
+
+    #[test]
+    fn moves_fold() {
+        assert_eq!(
+            compile!(",>+>++<<-.>[<.]"),
+            [
+                Instruction::Input,
+                Instruction::SetCell {
+                    offset: 1,
+                    value: 1
+                },
+                Instruction::SetCell {
+                    offset: 2,
+                    value: 2
+                },
+                Instruction::Increment {
+                    offset: 0,
+                    amount: 255
+                },
+                Instruction::Output { offset: 0 },
+                Instruction::Forward(1),
+                Instruction::LoopStart(10),
+                Instruction::Output { offset: -1 },
+                Instruction::Backward(1),
+                Instruction::LoopEnd(7),
+                Instruction::Stop,
+            ]
+        );
+    }
+
+    #[test]
+    fn scans_work() {
+        assert_eq!(
+            compile!(",[>>>],[-<],[++>>]"),
+            [
+                Instruction::Input,
+                Instruction::ForwardLoop(3),
+                Instruction::Input,
+                Instruction::IncrementBackwardLoop(1, 255),
+                Instruction::Input,
+                Instruction::IncrementForwardLoop(2, 2),
+                Instruction::Stop,
+            ]
+        );
+        assert!(
+            matches!(compile!(",[>-]")[1], Instruction::LoopStart(_)),
+            "scanned a loop that moves before adding"
+        );
+    }
+
     #[test]
     fn backward_works() {
         assert!(
@@ -387,7 +1636,13 @@ mod tests {
     #[test]
     fn decrement_works() {
         assert!(
-            matches!(compile!(">-")[1], Instruction::Increment(u8::MAX)),
+            matches!(
+                compile!(",-")[1],
+                Instruction::Increment {
+                    offset: 0,
+                    amount: 255
+                }
+            ),
             "did not decrement"
         );
     }
@@ -440,4 +1695,52 @@ mod tests {
             "false positive"
         );
     }
+
+    #[test]
+    fn odd_step_multiply_works() {
+        assert_eq!(
+            compile!(",[--->+<]")[1..3],
+            [
+                Instruction::MultiplyForward(1, 171),
+                Instruction::SetCell {
+                    offset: 0,
+                    value: 0
+                }
+            ],
+            "did not divide by the step"
+        );
+        assert!(
+            matches!(compile!(",[+>+<]")[1], Instruction::MultiplyForward(1, 255)),
+            "did not detect counting up"
+        );
+        assert!(
+            !matches!(compile!(",[-->+<]")[1], Instruction::MultiplyForward(..)),
+            "even steps can miss 0"
+        );
+    }
+
+    #[test]
+    fn multiply_product_works() {
+        assert_eq!(
+            compile!(",>,>>,[-<<[->+<<+>]>[-<+>]>]")[5],
+            Instruction::MultiplyProduct {
+                offset: -3,
+                factor: -2,
+                multiplier: 1
+            },
+            "did not detect nested multiply"
+        );
+        assert!(
+            !compile!(",>,>,>,[-<<[->+<<+>]>[-<+>]>]")
+                .iter()
+                .any(|instruction| matches!(instruction, Instruction::MultiplyProduct { .. })),
+            "the cell copied through is not known to be 0"
+        );
+        assert!(
+            !compile!(",>,>>,[-<<[->+<<+>>>+<]>[-<+>]>]")
+                .iter()
+                .any(|instruction| matches!(instruction, Instruction::MultiplyProduct { .. })),
+            "the count changes by more than the step"
+        );
+    }
 }
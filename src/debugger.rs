@@ -0,0 +1,377 @@
+use std::collections::BTreeSet;
+use std::io::{Read, Write};
+
+use crate::compile::{CompileError, Compiler, Instruction, Span};
+use crate::interpreter::{step, Cell, Metered, RuntimeErrorKind, Tape};
+use crate::lexer::lex;
+use crate::settings::{CellWidth, EofBehavior, Optimisations, Settings};
+
+/// Tape of any cell width.
+trait Machine {
+    fn step(
+        &mut self,
+        instructions: &[Instruction],
+        instruction_index: usize,
+        eof: EofBehavior,
+        meter: &mut Metered,
+        input: &mut dyn Read,
+        output: &mut dyn Write,
+    ) -> Result<Option<usize>, RuntimeErrorKind>;
+
+    /// Value of the cell at `index` in memory.
+    fn get(&self, index: usize) -> Option<u32>;
+
+    fn pointer(&self) -> usize;
+
+    fn origin(&self) -> usize;
+}
+
+impl<C: Cell> Machine for Tape<C> {
+    fn step(
+        &mut self,
+        instructions: &[Instruction],
+        instruction_index: usize,
+        eof: EofBehavior,
+        meter: &mut Metered,
+        input: &mut dyn Read,
+        output: &mut dyn Write,
+    ) -> Result<Option<usize>, RuntimeErrorKind> {
+        step(
+            instructions,
+            instruction_index,
+            eof,
+            self,
+            meter,
+            input,
+            output,
+        )
+    }
+
+    fn get(&self, index: usize) -> Option<u32> {
+        self.memory.get(index).map(|cell| cell.widen())
+    }
+
+    fn pointer(&self) -> usize {
+        self.pointer
+    }
+
+    fn origin(&self) -> usize {
+        self.origin
+    }
+}
+
+/// Why the debugger stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    /// Ran one instruction.
+    Stepped,
+
+    /// Reached a breakpoint, before running the instruction at it.
+    Breakpoint,
+
+    /// A watched cell changed.
+    Watch {
+        /// Address of the cell.
+        address: isize,
+
+        /// Value before, or `None` if the cell did not exist yet.
+        old: Option<u32>,
+
+        /// Value after.
+        new: Option<u32>,
+    },
+
+    /// The program has stopped.
+    Finished,
+}
+
+/// Runs a program one instruction at a time, stopping at breakpoints.
+///
+/// Cells are addressed relative to the cell the pointer started on,
+/// so cells left of it have negative addresses on a bidirectional tape.
+pub struct Debugger<'a> {
+    instructions: Vec<Instruction>,
+    spans: Vec<Span>,
+    tape: Box<dyn Machine>,
+    meter: Metered,
+    eof: EofBehavior,
+    input: Box<dyn Read + 'a>,
+    output: Box<dyn Write + 'a>,
+
+    /// Index of the next instruction, or `None` once stopped.
+    next: Option<usize>,
+
+    /// Indexes of instructions to stop before.
+    breakpoints: BTreeSet<usize>,
+
+    /// Watched addresses, with the value last seen.
+    watches: Vec<(isize, Option<u32>)>,
+}
+
+impl<'a> Debugger<'a> {
+    /// Compiles `source` for debugging, with a breakpoint at every `#`.
+    /// Pointer moves are never deferred, whatever the optimisations.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the program is malformed.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the tape policy or tape limit has a size of 0.
+    pub fn new(
+        source: &str,
+        settings: &Settings,
+        input: impl Read + 'a,
+        output: impl Write + 'a,
+    ) -> Result<Self, CompileError> {
+        let (instructions, spans) = Compiler::new(lex(source))
+            .with_cell_width(settings.cell_width)
+            .with_tape(settings.tape)
+            .with_tape_limit(settings.tape_limit)
+            // Moves are not deferred, and the start is not run early, so the pointer is where the
+            // source says while stepping.
+            .with_optimisations(Optimisations {
+                fold_moves: false,
+                evaluate_prefix: false,
+                ..settings.optimisations
+            })
+            .compile_with_spans()?;
+        let tape: Box<dyn Machine> = match settings.cell_width {
+            CellWidth::U8 => Box::new(Tape::<u8>::new(settings)),
+            CellWidth::U16 => Box::new(Tape::<u16>::new(settings)),
+            CellWidth::U32 => Box::new(Tape::<u32>::new(settings)),
+        };
+
+        let mut debugger = Self {
+            instructions,
+            spans,
+            tape,
+            meter: Metered::new(settings.budget),
+            eof: settings.eof,
+            input: Box::new(input),
+            output: Box::new(output),
+            next: Some(0),
+            breakpoints: BTreeSet::new(),
+            watches: Vec::new(),
+        };
+        for ((position, _), character) in lex(source).zip(source.chars()) {
+            if character == '#' {
+                debugger.add_breakpoint(position.line, position.column);
+            }
+        }
+        Ok(debugger)
+    }
+
+    /// Stops before the first instruction that ends at or after `line` and `column`.
+    ///
+    /// Returns the span of that instruction, or `None` if there is none.
+    pub fn add_breakpoint(&mut self, line: usize, column: usize) -> Option<Span> {
+        let index = self
+            .spans
+            .partition_point(|span| (span.end.line, span.end.column) < (line, column));
+        let span = *self.spans.get(index)?;
+        self.breakpoints.insert(index);
+        Some(span)
+    }
+
+    /// Stops whenever the cell at `address` changes.
+    pub fn watch(&mut self, address: isize) {
+        let value = self.cell(address);
+        self.watches.push((address, value));
+    }
+
+    /// Runs the next instruction.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the program stops early, after which it is finished.
+    pub fn step(&mut self) -> Result<Event, RuntimeErrorKind> {
+        let result = self.step_quietly();
+        self.output.flush()?;
+        result
+    }
+
+    /// Runs until a breakpoint, a watched cell changing or the end of the program.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the program stops early, after which it is finished.
+    pub fn resume(&mut self) -> Result<Event, RuntimeErrorKind> {
+        let result = loop {
+            match self.step_quietly() {
+                Ok(Event::Stepped) => {
+                    if self
+                        .next
+                        .is_some_and(|next| self.breakpoints.contains(&next))
+                    {
+                        break Ok(Event::Breakpoint);
+                    }
+                }
+                other => break other,
+            }
+        };
+        self.output.flush()?;
+        result
+    }
+
+    fn step_quietly(&mut self) -> Result<Event, RuntimeErrorKind> {
+        let Some(index) = self.next else {
+            return Ok(Event::Finished);
+        };
+        self.next = None;
+        self.next = self.tape.step(
+            &self.instructions,
+            index,
+            self.eof,
+            &mut self.meter,
+            &mut self.input,
+            &mut self.output,
+        )?;
+        if self.next.is_none() {
+            return Ok(Event::Finished);
+        }
+
+        for index in 0..self.watches.len() {
+            let (address, old) = self.watches[index];
+            let new = self.cell(address);
+            if new != old {
+                self.watches[index].1 = new;
+                return Ok(Event::Watch { address, old, new });
+            }
+        }
+        Ok(Event::Stepped)
+    }
+
+    /// Whether the program has stopped.
+    #[must_use]
+    pub const fn is_finished(&self) -> bool {
+        self.next.is_none()
+    }
+
+    /// The next instruction to run.
+    #[must_use]
+    pub fn instruction(&self) -> Option<&Instruction> {
+        self.instructions.get(self.next?)
+    }
+
+    /// Span of the source the next instruction came from.
+    #[must_use]
+    pub fn span(&self) -> Option<Span> {
+        self.spans.get(self.next?).copied()
+    }
+
+    /// Address of the cell the pointer is on.
+    #[must_use]
+    pub fn pointer(&self) -> isize {
+        self.tape.pointer() as isize - self.tape.origin() as isize
+    }
+
+    /// Value of the cell at `address`, or `None` if the tape does not have it yet.
+    #[must_use]
+    pub fn cell(&self, address: isize) -> Option<u32> {
+        let index = usize::try_from(address + self.tape.origin() as isize).ok()?;
+        self.tape.get(index)
+    }
+
+    /// Addresses and values of the cells up to `radius` cells either side of the pointer.
+    #[must_use]
+    pub fn window(&self, radius: usize) -> Vec<(isize, u32)> {
+        let pointer = self.pointer();
+        let radius = radius as isize;
+        (pointer - radius..=pointer + radius)
+            .filter_map(|address| Some((address, self.cell(address)?)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        debugger::{Debugger, Event},
+        interpreter::RuntimeErrorKind,
+        settings::{Settings, TapePolicy},
+    };
+
+    #[test]
+    fn hash_breakpoints_work() {
+        let mut output = Vec::new();
+        let mut debugger = Debugger::new(
+            "++.#\n>+++[<+>-]#<.",
+            &Settings::default(),
+            &b""[..],
+            &mut output,
+        )
+        .unwrap();
+
+        assert_eq!(debugger.resume().unwrap(), Event::Breakpoint);
+        assert_eq!(debugger.span().unwrap().start.line, 2);
+        assert_eq!(debugger.pointer(), 0);
+
+        assert_eq!(debugger.resume().unwrap(), Event::Breakpoint);
+        assert_eq!(debugger.window(1), [(0, 5), (1, 0), (2, 0)]);
+        assert_eq!(debugger.resume().unwrap(), Event::Finished);
+        assert!(debugger.is_finished());
+        drop(debugger);
+        assert_eq!(output, [2, 5]);
+    }
+
+    #[test]
+    fn step_works() {
+        let mut debugger =
+            Debugger::new("+++>++", &Settings::default(), &b""[..], Vec::new()).unwrap();
+        let span = debugger.span().unwrap();
+        assert_eq!((span.start.column, span.end.column), (1, 3));
+
+        assert_eq!(debugger.step().unwrap(), Event::Stepped);
+        assert_eq!(debugger.step().unwrap(), Event::Stepped);
+        assert_eq!(debugger.pointer(), 1);
+        assert_eq!(debugger.cell(0), Some(3));
+        assert_eq!(debugger.step().unwrap(), Event::Stepped);
+        assert_eq!(debugger.step().unwrap(), Event::Finished);
+    }
+
+    #[test]
+    fn line_breakpoints_work() {
+        let mut debugger =
+            Debugger::new("+\n.\n+\n", &Settings::default(), &b""[..], Vec::new()).unwrap();
+        assert!(debugger.add_breakpoint(2, 1).is_some());
+        assert!(debugger.add_breakpoint(9, 1).is_none());
+        assert_eq!(debugger.resume().unwrap(), Event::Breakpoint);
+        assert_eq!(debugger.cell(0), Some(1));
+    }
+
+    #[test]
+    fn watch_works() {
+        let settings = Settings {
+            tape: TapePolicy::Bidirectional,
+            ..Settings::default()
+        };
+        let mut debugger =
+            Debugger::new(",[<+>-.]<<", &settings, &b"\x02"[..], Vec::new()).unwrap();
+        debugger.watch(-1);
+
+        let watch = |old, new| Event::Watch {
+            address: -1,
+            old,
+            new,
+        };
+        assert_eq!(debugger.resume().unwrap(), watch(None, Some(0)));
+        assert_eq!(debugger.pointer(), -1);
+        assert_eq!(debugger.resume().unwrap(), watch(Some(0), Some(1)));
+        assert_eq!(debugger.resume().unwrap(), watch(Some(1), Some(2)));
+        assert_eq!(debugger.resume().unwrap(), Event::Finished);
+        assert_eq!(debugger.pointer(), -2);
+    }
+
+    #[test]
+    fn errors_finish() {
+        let mut debugger = Debugger::new("+<", &Settings::default(), &b""[..], Vec::new()).unwrap();
+        assert!(matches!(
+            debugger.resume(),
+            Err(RuntimeErrorKind::PointerUnderflow)
+        ));
+        assert!(debugger.is_finished());
+        assert_eq!(debugger.cell(0), Some(1));
+    }
+}
@@ -1,112 +1,1174 @@
-use crate::{compile::Instruction, INITIAL_MEMORY_CAPACITY, MEMORY_RESIZE_AMOUNT};
-use core::num::Wrapping;
-use std::io::{stdin, stdout, Read, Write};
+use crate::{
+    compile::Instruction,
+    settings::{Budget, CellWidth, EofBehavior, Settings, TapePolicy},
+    MEMORY_RESIZE_AMOUNT,
+};
+use core::{fmt, iter};
+use std::io::{self, stdin, stdout, Read, Write};
+use std::time::Instant;
 
-/// Interprets instructions.
-pub fn execute(instructions: &[Instruction]) -> Vec<Wrapping<u8>> {
-    let mut stdout = stdout().lock();
-    let mut stdin = stdin().lock();
+/// Integer type of a cell, with wrapping arithmetic.
+pub(crate) trait Cell: Copy + Eq {
+    const ZERO: Self;
 
-    let mut memory: Vec<Wrapping<u8>> = vec![Wrapping(0); INITIAL_MEMORY_CAPACITY];
-    let mut pointer: usize = 0;
+    /// Keeps the low bits of `value`.
+    fn truncate(value: u32) -> Self;
 
-    let mut instruction_index = 0;
-    loop {
-        match unsafe { instructions.get_unchecked(instruction_index) } {
-            Instruction::Forward(offset) => {
-                pointer = pointer.wrapping_add(*offset as usize);
-                if pointer >= memory.len() {
-                    memory.resize(pointer + MEMORY_RESIZE_AMOUNT, Wrapping(0));
+    fn widen(self) -> u32;
+
+    #[must_use]
+    fn add(self, other: Self) -> Self;
+
+    #[must_use]
+    fn mul(self, other: Self) -> Self;
+}
+
+macro_rules! impl_cell {
+    ($($type:ty),*) => {
+        $(
+            impl Cell for $type {
+                const ZERO: Self = 0;
+
+                fn truncate(value: u32) -> Self {
+                    value as Self
                 }
-            }
-            Instruction::Backward(offset) => {
-                pointer = pointer.wrapping_sub(*offset as usize);
-            }
 
-            Instruction::Increment(increment) => {
-                let cell = unsafe { memory.get_unchecked_mut(pointer) };
-                *cell += increment;
+                fn widen(self) -> u32 {
+                    u32::from(self)
+                }
+
+                fn add(self, other: Self) -> Self {
+                    self.wrapping_add(other)
+                }
+
+                fn mul(self, other: Self) -> Self {
+                    self.wrapping_mul(other)
+                }
             }
+        )*
+    };
+}
+impl_cell!(u8, u16, u32);
+
+/// Reads one byte, or what to store instead once input has run out.
+pub(crate) fn read_byte(input: &mut impl Read, eof: EofBehavior) -> io::Result<Option<u32>> {
+    let mut byte: [u8; 1] = [0; 1];
+    match input.read_exact(&mut byte) {
+        Ok(()) => Ok(Some(u32::from(byte[0]))),
+        Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => Ok(match eof {
+            EofBehavior::Unchanged => None,
+            EofBehavior::Zero => Some(0),
+            EofBehavior::Max => Some(u32::MAX),
+        }),
+        Err(error) => Err(error),
+    }
+}
+
+/// Why a program stopped early.
+#[derive(Debug)]
+pub enum RuntimeErrorKind {
+    /// Reading input or writing output failed.
+    Io(io::Error),
+
+    /// The pointer moved left of the first cell.
+    PointerUnderflow,
+
+    /// The pointer moved right of the last cell of a fixed size tape.
+    PointerOverflow,
+
+    /// A growing tape needed more cells than the tape limit.
+    TapeLimit,
+
+    /// Ran the most steps the budget allows.
+    StepLimit {
+        /// Steps run.
+        steps: u64,
+    },
+
+    /// Ran for longer than the budget allows.
+    Timeout {
+        /// Steps run.
+        steps: u64,
+    },
+}
+
+impl From<io::Error> for RuntimeErrorKind {
+    fn from(error: io::Error) -> Self {
+        Self::Io(error)
+    }
+}
+
+impl fmt::Display for RuntimeErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(error) => error.fmt(f),
+            Self::PointerUnderflow => f.write_str("pointer moved left of the first cell"),
+            Self::PointerOverflow => f.write_str("pointer moved right of the last cell"),
+            Self::TapeLimit => f.write_str("tape limit exceeded"),
+            Self::StepLimit { steps } => write!(f, "step limit reached after {steps} steps"),
+            Self::Timeout { steps } => write!(f, "timed out after {steps} steps"),
+        }
+    }
+}
+
+/// Error returned when a program stops early.
+#[derive(Debug)]
+pub struct RuntimeError {
+    /// Why the program stopped.
+    pub kind: RuntimeErrorKind,
+
+    /// Memory when the program stopped, with each cell widened to `u32`.
+    pub memory: Vec<u32>,
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.kind.fmt(f)
+    }
+}
+
+impl std::error::Error for RuntimeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match &self.kind {
+            RuntimeErrorKind::Io(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+/// How many steps to run between checking the clock.
+const CLOCK_INTERVAL: u64 = 1 << 16;
+
+/// Counts steps towards a budget.
+pub(crate) trait Meter {
+    /// Counts a step, or errors if the budget has run out.
+    fn tick(&mut self) -> Result<(), RuntimeErrorKind>;
+}
+
+/// Meter for an unlimited budget, which costs nothing.
+pub(crate) struct Unmetered;
 
-            Instruction::LoopStart(loop_exit) => {
-                if unsafe { memory.get_unchecked_mut(pointer).0 } == 0 {
-                    instruction_index = *loop_exit as usize;
-                    continue;
+impl Meter for Unmetered {
+    #[inline]
+    fn tick(&mut self) -> Result<(), RuntimeErrorKind> {
+        Ok(())
+    }
+}
+
+/// Counts steps against a budget.
+pub(crate) struct Metered {
+    /// Steps left before the next check.
+    pub(crate) remaining: u64,
+
+    /// Steps between the previous check and the next one.
+    interval: u64,
+
+    /// Steps run before the previous check.
+    used: u64,
+
+    max_steps: Option<u64>,
+    deadline: Option<Instant>,
+}
+
+impl Metered {
+    pub(crate) fn new(budget: Budget) -> Self {
+        Self {
+            remaining: 0,
+            interval: 0,
+            used: 0,
+            max_steps: budget.max_steps,
+            deadline: budget.timeout.map(|timeout| Instant::now() + timeout),
+        }
+    }
+
+    /// Steps run so far.
+    fn steps(&self) -> u64 {
+        self.used + self.interval - self.remaining
+    }
+
+    #[cold]
+    fn check(&mut self) -> Result<(), RuntimeErrorKind> {
+        self.used += self.interval;
+        let steps = self.used;
+        if self.max_steps.is_some_and(|max_steps| steps >= max_steps) {
+            return Err(RuntimeErrorKind::StepLimit { steps });
+        }
+        if self
+            .deadline
+            .is_some_and(|deadline| Instant::now() >= deadline)
+        {
+            return Err(RuntimeErrorKind::Timeout { steps });
+        }
+
+        let until_limit = self
+            .max_steps
+            .map_or(u64::MAX, |max_steps| max_steps - steps);
+        let until_clock = if self.deadline.is_some() {
+            CLOCK_INTERVAL
+        } else {
+            u64::MAX
+        };
+        self.interval = until_limit.min(until_clock);
+        self.remaining = self.interval;
+        Ok(())
+    }
+}
+
+impl Meter for Metered {
+    #[inline]
+    fn tick(&mut self) -> Result<(), RuntimeErrorKind> {
+        if self.remaining == 0 {
+            self.check()?;
+        }
+        self.remaining -= 1;
+        Ok(())
+    }
+}
+
+/// Memory and pointer, following a tape policy.
+pub(crate) struct Tape<C> {
+    pub(crate) memory: Vec<C>,
+    pub(crate) pointer: usize,
+
+    /// Index of the cell the pointer started on, which moves when the tape grows at the front.
+    pub(crate) origin: usize,
+    policy: TapePolicy,
+
+    /// Most cells a growing tape may have.
+    limit: usize,
+}
+
+impl<C: Cell> Tape<C> {
+    pub(crate) fn new(settings: &Settings) -> Self {
+        assert!(settings.initial_tape_size() != 0, "tape size must not be 0");
+        Self {
+            memory: vec![C::ZERO; settings.initial_tape_size()],
+            pointer: 0,
+            origin: 0,
+            policy: settings.tape,
+            limit: settings.tape_limit.unwrap_or(usize::MAX),
+        }
+    }
+
+    fn cell(&self) -> C {
+        unsafe { *self.memory.get_unchecked(self.pointer) }
+    }
+
+    fn cell_mut(&mut self) -> &mut C {
+        unsafe { self.memory.get_unchecked_mut(self.pointer) }
+    }
+
+    fn at(&self, index: usize) -> C {
+        unsafe { *self.memory.get_unchecked(index) }
+    }
+
+    fn at_mut(&mut self, index: usize) -> &mut C {
+        unsafe { self.memory.get_unchecked_mut(index) }
+    }
+
+    /// Index of the cell `offset` cells to the right, making room for it if needed.
+    #[inline]
+    pub(crate) fn right(&mut self, offset: usize) -> Result<usize, RuntimeErrorKind> {
+        let index = self.pointer + offset;
+        if index < self.memory.len() {
+            Ok(index)
+        } else {
+            self.right_off_tape(index)
+        }
+    }
+
+    #[cold]
+    fn right_off_tape(&mut self, index: usize) -> Result<usize, RuntimeErrorKind> {
+        match self.policy {
+            TapePolicy::Grow | TapePolicy::Bidirectional => {
+                if index >= self.limit {
+                    return Err(RuntimeErrorKind::TapeLimit);
                 }
+                let size = index.saturating_add(MEMORY_RESIZE_AMOUNT).min(self.limit);
+                self.memory.resize(size, C::ZERO);
+                Ok(index)
             }
-            Instruction::LoopEnd(loop_body) => {
-                if unsafe { memory.get_unchecked_mut(pointer).0 } != 0 {
-                    instruction_index = *loop_body as usize;
-                    continue;
+            TapePolicy::Wrap(_) => Ok(index % self.memory.len()),
+            TapePolicy::Fixed(_) => Err(RuntimeErrorKind::PointerOverflow),
+        }
+    }
+
+    /// Index of the cell `offset` cells to the left, making room for it if needed.
+    #[inline]
+    pub(crate) fn left(&mut self, offset: usize) -> Result<usize, RuntimeErrorKind> {
+        if offset <= self.pointer {
+            Ok(self.pointer - offset)
+        } else {
+            self.left_off_tape(offset)
+        }
+    }
+
+    #[cold]
+    fn left_off_tape(&mut self, offset: usize) -> Result<usize, RuntimeErrorKind> {
+        match self.policy {
+            TapePolicy::Grow | TapePolicy::Fixed(_) => Err(RuntimeErrorKind::PointerUnderflow),
+            TapePolicy::Wrap(_) => {
+                let size = self.memory.len();
+                Ok((self.pointer + size - offset % size) % size)
+            }
+            TapePolicy::Bidirectional => {
+                let room = self.limit - self.memory.len();
+                let needed = offset - self.pointer;
+                if needed > room {
+                    return Err(RuntimeErrorKind::TapeLimit);
                 }
+                let extra = (needed + MEMORY_RESIZE_AMOUNT).min(room);
+                self.memory.splice(0..0, iter::repeat_n(C::ZERO, extra));
+                self.pointer += extra;
+                self.origin += extra;
+                Ok(self.pointer - offset)
             }
+        }
+    }
+
+    /// Index of the cell `offset` cells away, to the left if negative.
+    #[inline]
+    pub(crate) fn index(&mut self, offset: i32) -> Result<usize, RuntimeErrorKind> {
+        if offset == 0 {
+            Ok(self.pointer)
+        } else if offset.is_positive() {
+            self.right(offset as usize)
+        } else {
+            self.left(offset.unsigned_abs() as usize)
+        }
+    }
 
-            Instruction::SetCell(value) => {
-                let cell = unsafe { memory.get_unchecked_mut(pointer) };
-                *cell = Wrapping(*value);
+    fn forward(&mut self, offset: usize) -> Result<(), RuntimeErrorKind> {
+        self.pointer = self.right(offset)?;
+        Ok(())
+    }
+
+    fn backward(&mut self, offset: usize) -> Result<(), RuntimeErrorKind> {
+        self.pointer = self.left(offset)?;
+        Ok(())
+    }
+
+    /// Moves the pointer right `stride` cells at a time until it is on a zero cell,
+    /// adding `amount` to each cell it leaves.
+    fn scan_right(
+        &mut self,
+        stride: usize,
+        amount: C,
+        meter: &mut impl Meter,
+    ) -> Result<(), RuntimeErrorKind> {
+        while self.cell() != C::ZERO {
+            // Search the cells already on the tape, and only step off the end to grow or wrap it.
+            // A stride of 0, only possible in hand-written IR, stays on one cell.
+            let cells = &self.memory[self.pointer..];
+            let passed = match stride {
+                0 => 1,
+                _ => zero_right(cells, stride).unwrap_or((cells.len() - 1) / stride + 1),
+            };
+            for _ in 1..passed {
+                meter.tick()?;
+                let cell = self.cell_mut();
+                *cell = cell.add(amount);
+                self.pointer += stride;
             }
+            meter.tick()?;
+            let cell = self.cell_mut();
+            *cell = cell.add(amount);
+            self.forward(stride)?;
+        }
+        Ok(())
+    }
 
-            Instruction::MultiplyForward(offset, multiplier) => {
-                let cell = unsafe { *memory.get_unchecked(pointer) };
-                if cell != Wrapping(0) {
-                    if pointer + *offset as usize >= memory.len() {
-                        memory.resize(
-                            pointer + *offset as usize + MEMORY_RESIZE_AMOUNT,
-                            Wrapping(0),
-                        );
-                    }
+    /// Moves the pointer left `stride` cells at a time until it is on a zero cell,
+    /// adding `amount` to each cell it leaves.
+    fn scan_left(
+        &mut self,
+        stride: usize,
+        amount: C,
+        meter: &mut impl Meter,
+    ) -> Result<(), RuntimeErrorKind> {
+        while self.cell() != C::ZERO {
+            let cells = &self.memory[..=self.pointer];
+            let passed = match stride {
+                0 => 1,
+                _ => zero_left(cells, stride).unwrap_or(self.pointer / stride + 1),
+            };
+            for _ in 1..passed {
+                meter.tick()?;
+                let cell = self.cell_mut();
+                *cell = cell.add(amount);
+                self.pointer -= stride;
+            }
+            meter.tick()?;
+            let cell = self.cell_mut();
+            *cell = cell.add(amount);
+            self.backward(stride)?;
+        }
+        Ok(())
+    }
+}
 
-                    *unsafe { memory.get_unchecked_mut(pointer + *offset as usize) } +=
-                        cell * Wrapping(*multiplier);
-                }
+/// Cells searched at once for a zero.
+const SEARCH_CHUNK: usize = 32;
+
+// Checks every cell without stopping early, so that the check vectorises.
+#[allow(clippy::needless_bitwise_bool)]
+fn has_zero<C: Cell>(cells: &[C]) -> bool {
+    cells
+        .iter()
+        .fold(false, |found, &cell| found | (cell == C::ZERO))
+}
+
+/// Cells passed stepping `stride` right from the first cell before one is zero.
+fn zero_right<C: Cell>(cells: &[C], stride: usize) -> Option<usize> {
+    if stride != 1 {
+        return cells
+            .iter()
+            .step_by(stride)
+            .position(|&cell| cell == C::ZERO);
+    }
+    // Skip whole chunks without a zero, like `memchr`.
+    let mut start = 0;
+    for chunk in cells.chunks_exact(SEARCH_CHUNK) {
+        if has_zero(chunk) {
+            break;
+        }
+        start += SEARCH_CHUNK;
+    }
+    cells[start..]
+        .iter()
+        .position(|&cell| cell == C::ZERO)
+        .map(|position| start + position)
+}
+
+/// Cells passed stepping `stride` left from the last cell before one is zero.
+fn zero_left<C: Cell>(cells: &[C], stride: usize) -> Option<usize> {
+    if stride != 1 {
+        return cells
+            .iter()
+            .rev()
+            .step_by(stride)
+            .position(|&cell| cell == C::ZERO);
+    }
+    let mut end = cells.len();
+    for chunk in cells.rchunks_exact(SEARCH_CHUNK) {
+        if has_zero(chunk) {
+            break;
+        }
+        end -= SEARCH_CHUNK;
+    }
+    cells[..end]
+        .iter()
+        .rev()
+        .position(|&cell| cell == C::ZERO)
+        .map(|position| cells.len() - end + position)
+}
+
+/// Interprets instructions, reading from stdin and writing to stdout.
+///
+/// # Panics
+///
+/// Panics if the program stops early, see [`execute_with`].
+#[must_use]
+pub fn execute(instructions: &[Instruction], settings: &Settings) -> Vec<u32> {
+    execute_with(instructions, settings, stdin().lock(), stdout().lock()).unwrap()
+}
+
+/// Interprets instructions, reading from `input` and writing to `output`.
+///
+/// Returns the memory, with each cell widened to `u32`.
+///
+/// # Errors
+///
+/// Returns an error if reading from `input` or writing to `output` fails,
+/// or if the pointer moves off the tape when the tape policy does not allow it.
+///
+/// # Panics
+///
+/// Panics if the tape policy or tape limit has a size of 0.
+pub fn execute_with(
+    instructions: &[Instruction],
+    settings: &Settings,
+    input: impl Read,
+    output: impl Write,
+) -> Result<Vec<u32>, RuntimeError> {
+    match settings.cell_width {
+        CellWidth::U8 => execute_as::<u8>(instructions, settings, input, output),
+        CellWidth::U16 => execute_as::<u16>(instructions, settings, input, output),
+        CellWidth::U32 => execute_as::<u32>(instructions, settings, input, output),
+    }
+}
+
+fn execute_as<C: Cell>(
+    instructions: &[Instruction],
+    settings: &Settings,
+    input: impl Read,
+    mut output: impl Write,
+) -> Result<Vec<u32>, RuntimeError> {
+    let mut tape = Tape::<C>::new(settings);
+    let result = if settings.budget == Budget::default() {
+        run(
+            instructions,
+            settings.eof,
+            &mut tape,
+            &mut Unmetered,
+            input,
+            &mut output,
+        )
+    } else {
+        run(
+            instructions,
+            settings.eof,
+            &mut tape,
+            &mut Metered::new(settings.budget),
+            input,
+            &mut output,
+        )
+    };
+    // Flush even when stopping early, so output up to the error is not lost.
+    let flushed = output.flush();
+    let result = result.and_then(|()| Ok(flushed?));
+    let memory = tape.memory.into_iter().map(Cell::widen).collect();
+    match result {
+        Ok(()) => Ok(memory),
+        Err(kind) => Err(RuntimeError { kind, memory }),
+    }
+}
+
+fn run<C: Cell>(
+    instructions: &[Instruction],
+    eof: EofBehavior,
+    tape: &mut Tape<C>,
+    meter: &mut impl Meter,
+    mut input: impl Read,
+    mut output: impl Write,
+) -> Result<(), RuntimeErrorKind> {
+    let mut instruction_index = 0;
+    while let Some(next) = step(
+        instructions,
+        instruction_index,
+        eof,
+        tape,
+        meter,
+        &mut input,
+        &mut output,
+    )? {
+        instruction_index = next;
+    }
+    Ok(())
+}
+
+/// What running part of a program while compiling did.
+pub(crate) struct Evaluation {
+    /// Bytes written.
+    pub(crate) output: Vec<u8>,
+
+    /// Cells that are not 0, by where they are from the cell the pointer started on.
+    pub(crate) cells: Vec<(i64, u32)>,
+
+    /// Where the pointer is, from the cell it started on.
+    pub(crate) pointer: i64,
+
+    /// Steps run.
+    pub(crate) steps: u64,
+}
+
+impl Evaluation {
+    fn new<C: Cell>(tape: &Tape<C>, output: Vec<u8>, steps: u64) -> Self {
+        let origin = tape.origin as i64;
+        Self {
+            output,
+            cells: tape
+                .memory
+                .iter()
+                .enumerate()
+                .filter(|(_, &cell)| cell != C::ZERO)
+                .map(|(index, cell)| (index as i64 - origin, cell.widen()))
+                .collect(),
+            pointer: tape.pointer as i64 - origin,
+            steps,
+        }
+    }
+}
+
+/// Runs `instructions` from `start` to the end, from a tape with `cells` set and the pointer at
+/// `pointer`, where both are from the cell the pointer started on.
+///
+/// Returns `None` if they read input or fail, like by running out of `settings.budget`.
+pub(crate) fn evaluate(
+    instructions: &[Instruction],
+    start: usize,
+    cells: &[(i64, u32)],
+    pointer: i64,
+    settings: &Settings,
+) -> Option<Evaluation> {
+    match settings.cell_width {
+        CellWidth::U8 => evaluate_as::<u8>(instructions, start, cells, pointer, settings),
+        CellWidth::U16 => evaluate_as::<u16>(instructions, start, cells, pointer, settings),
+        CellWidth::U32 => evaluate_as::<u32>(instructions, start, cells, pointer, settings),
+    }
+}
+
+fn evaluate_as<C: Cell>(
+    instructions: &[Instruction],
+    start: usize,
+    cells: &[(i64, u32)],
+    pointer: i64,
+    settings: &Settings,
+) -> Option<Evaluation> {
+    if instructions[start..]
+        .iter()
+        .any(|instruction| matches!(instruction, Instruction::Input))
+    {
+        return None;
+    }
+    let mut tape = Tape::<C>::new(settings);
+    for &(position, value) in cells {
+        let index = tape.index(i32::try_from(position).ok()?).ok()?;
+        *tape.at_mut(index) = C::truncate(value);
+    }
+    tape.pointer = tape.index(i32::try_from(pointer).ok()?).ok()?;
+
+    let mut meter = Metered::new(settings.budget);
+    let mut output = Vec::new();
+    let mut index = start;
+    while index < instructions.len() {
+        index = step(
+            instructions,
+            index,
+            settings.eof,
+            &mut tape,
+            &mut meter,
+            io::empty(),
+            &mut output,
+        )
+        .ok()??;
+    }
+    Some(Evaluation::new(&tape, output, meter.steps()))
+}
+
+/// Runs the instructions at the start of a program up to the first loop, input or instruction
+/// that fails, returning how many ran and what they did.
+pub(crate) fn evaluate_prefix(
+    instructions: &[Instruction],
+    settings: &Settings,
+) -> (usize, Evaluation) {
+    match settings.cell_width {
+        CellWidth::U8 => evaluate_prefix_as::<u8>(instructions, settings),
+        CellWidth::U16 => evaluate_prefix_as::<u16>(instructions, settings),
+        CellWidth::U32 => evaluate_prefix_as::<u32>(instructions, settings),
+    }
+}
+
+fn evaluate_prefix_as<C: Cell>(
+    instructions: &[Instruction],
+    settings: &Settings,
+) -> (usize, Evaluation) {
+    let mut tape = Tape::<C>::new(settings);
+    let mut output = Vec::new();
+    let mut length = 0;
+    // These fail before changing any cells.
+    while matches!(
+        instructions[length],
+        Instruction::Forward(_)
+            | Instruction::Backward(_)
+            | Instruction::Increment { .. }
+            | Instruction::SetCell { .. }
+            | Instruction::Output { .. }
+            | Instruction::OutputBytes(_)
+            // Only made for cells known to be on the tape, so cannot fail.
+            | Instruction::Print { .. }
+    ) {
+        let stepped = step(
+            instructions,
+            length,
+            settings.eof,
+            &mut tape,
+            &mut Unmetered,
+            io::empty(),
+            &mut output,
+        );
+        if stepped.is_err() {
+            break;
+        }
+        length += 1;
+    }
+    (length, Evaluation::new(&tape, output, length as u64))
+}
+
+/// Runs the instruction at `instruction_index`, returning the index of the next one,
+/// or `None` once the program has stopped.
+#[inline]
+pub(crate) fn step<C: Cell>(
+    instructions: &[Instruction],
+    instruction_index: usize,
+    eof: EofBehavior,
+    tape: &mut Tape<C>,
+    meter: &mut impl Meter,
+    mut input: impl Read,
+    mut output: impl Write,
+) -> Result<Option<usize>, RuntimeErrorKind> {
+    let instruction = unsafe { instructions.get_unchecked(instruction_index) };
+    if matches!(instruction, Instruction::Stop) {
+        return Ok(None);
+    }
+    meter.tick()?;
+
+    match instruction {
+        Instruction::Forward(offset) => tape.forward(*offset as usize)?,
+        Instruction::Backward(offset) => tape.backward(*offset as usize)?,
+
+        Instruction::Increment { offset, amount } => {
+            let index = tape.index(*offset)?;
+            let cell = tape.at_mut(index);
+            *cell = cell.add(C::truncate(*amount));
+        }
+
+        Instruction::LoopStart(loop_exit) => {
+            if tape.cell() == C::ZERO {
+                return Ok(Some(*loop_exit as usize));
+            }
+        }
+        Instruction::LoopEnd(loop_body) => {
+            if tape.cell() != C::ZERO {
+                return Ok(Some(*loop_body as usize));
             }
+        }
 
-            Instruction::MultiplyBackward(offset, multiplier) => {
-                let cell = unsafe { *memory.get_unchecked(pointer) };
+        Instruction::SetCell { offset, value } => {
+            let index = tape.index(*offset)?;
+            *tape.at_mut(index) = C::truncate(*value);
+        }
 
-                if cell != Wrapping(0) {
-                    *unsafe { memory.get_unchecked_mut(pointer - *offset as usize) } +=
-                        cell * Wrapping(*multiplier);
-                }
+        Instruction::MultiplyForward(offset, multiplier) => {
+            let cell = tape.cell();
+            if cell != C::ZERO {
+                let index = tape.right(*offset as usize)?;
+                let target = tape.at_mut(index);
+                *target = target.add(cell.mul(C::truncate(*multiplier)));
             }
+        }
 
-            Instruction::ForwardLoop(offset) => {
-                while unsafe { memory.get_unchecked(pointer).0 } != 0 {
-                    pointer += *offset as usize;
-                    if pointer >= memory.len() {
-                        memory.resize(pointer + MEMORY_RESIZE_AMOUNT, Wrapping(0));
-                        break;
-                    }
-                }
+        Instruction::MultiplyBackward(offset, multiplier) => {
+            let cell = tape.cell();
+            if cell != C::ZERO {
+                let index = tape.left(*offset as usize)?;
+                let target = tape.at_mut(index);
+                *target = target.add(cell.mul(C::truncate(*multiplier)));
+            }
+        }
+
+        Instruction::MultiplyProduct {
+            offset,
+            factor,
+            multiplier,
+        } => {
+            let cell = tape.cell();
+            if cell != C::ZERO {
+                // Finding a cell can grow the tape, so each is read before the next is found.
+                let index = tape.index(*factor)?;
+                let product = cell.mul(tape.at(index)).mul(C::truncate(*multiplier));
+                let index = tape.index(*offset)?;
+                let target = tape.at_mut(index);
+                *target = target.add(product);
             }
-            Instruction::BackwardLoop(offset) => {
-                while unsafe { memory.get_unchecked(pointer).0 } != 0 {
-                    pointer -= *offset as usize;
+        }
+
+        Instruction::ForwardLoop(offset) => tape.scan_right(*offset as usize, C::ZERO, meter)?,
+        Instruction::BackwardLoop(offset) => tape.scan_left(*offset as usize, C::ZERO, meter)?,
+        Instruction::IncrementForwardLoop(offset, amount) => {
+            tape.scan_right(*offset as usize, C::truncate(*amount), meter)?;
+        }
+        Instruction::IncrementBackwardLoop(offset, amount) => {
+            tape.scan_left(*offset as usize, C::truncate(*amount), meter)?;
+        }
+        Instruction::Output { offset } => {
+            let index = tape.index(*offset)?;
+            output.write_all(&[tape.at(index).widen() as u8])?;
+        }
+        Instruction::Print { value, .. } => output.write_all(&[*value])?,
+        Instruction::OutputBytes(bytes) => output.write_all(bytes)?,
+
+        Instruction::IncrementLoop(increment) => {
+            let increment = C::truncate(*increment);
+            let mut cell = tape.cell();
+            let mut result = Ok(());
+            while cell != C::ZERO {
+                result = meter.tick();
+                if result.is_err() {
+                    break;
                 }
+                cell = cell.add(increment);
             }
-            Instruction::Output => {
-                let cell = unsafe { memory.get_unchecked(pointer) };
-                write!(stdout, "{}", cell.0 as char).unwrap();
+            // Store the cell even when out of budget, so the tape shows how far the loop got.
+            *tape.cell_mut() = cell;
+            result?;
+        }
+
+        Instruction::Input => {
+            if let Some(value) = read_byte(&mut input, eof)? {
+                *tape.cell_mut() = C::truncate(value);
             }
+        }
+        Instruction::Stop => unreachable!(),
+    }
 
-            Instruction::IncrementLoop(increment) => {
-                let mut cell = unsafe { *memory.get_unchecked(pointer) };
-                while cell != Wrapping(0) {
-                    cell += *increment;
-                }
-                *unsafe { memory.get_unchecked_mut(pointer) } = cell;
+    Ok(Some(instruction_index + 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Write};
+
+    use crate::{
+        compile::Compiler,
+        interpreter::{execute_with, RuntimeErrorKind},
+        lexer::lex,
+        settings::{Budget, CellWidth, EofBehavior, Optimisations, Settings, TapePolicy},
+    };
+    use core::time::Duration;
+
+    /// Stops the program once `limit` bytes have been written.
+    struct Limited {
+        output: Vec<u8>,
+        limit: usize,
+    }
+
+    impl Write for Limited {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.output.len() >= self.limit {
+                return Err(io::ErrorKind::WriteZero.into());
             }
+            self.output.extend_from_slice(buf);
+            Ok(buf.len())
+        }
 
-            Instruction::Input => {
-                let mut input: [u8; 1] = [0; 1];
-                stdin.read_exact(&mut input).unwrap();
-                let cell = unsafe { memory.get_unchecked_mut(pointer) };
-                *cell = Wrapping(input[0]);
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn run_with(code: &str, settings: Settings, input: &[u8]) -> Vec<u8> {
+        let instructions = Compiler::new(lex(code))
+            .with_cell_width(settings.cell_width)
+            .with_optimisations(settings.optimisations)
+            .compile()
+            .unwrap();
+        let mut output = Vec::new();
+        execute_with(&instructions, &settings, input, &mut output).unwrap();
+        output
+    }
+
+    fn run(code: &str, input: &[u8]) -> Vec<u8> {
+        run_with(code, Settings::default(), input)
+    }
+
+    #[test]
+    fn zyx_works() {
+        assert_eq!(
+            run(include_str!("../samples/Zyx.bf"), b""),
+            b"ZYXWVUTSRQPONMLKJIHGFEDCBA\n"
+        );
+    }
+
+    #[test]
+    fn squares_works() {
+        let expected = (0..=100).fold(String::new(), |squares, n| {
+            squares + &format!("{}\n", n * n)
+        });
+
+        let instructions = Compiler::new(lex(include_str!("../samples/Squares.bf")))
+            .compile()
+            .unwrap();
+        let mut output = Limited {
+            output: Vec::new(),
+            limit: expected.len(),
+        };
+        assert!(execute_with(&instructions, &Settings::default(), &b""[..], &mut output).is_err());
+        assert_eq!(String::from_utf8(output.output).unwrap(), expected);
+    }
+
+    #[test]
+    fn optimisations_agree() {
+        let without = |optimisations: fn(&mut Optimisations)| {
+            let mut all = Optimisations::ALL;
+            optimisations(&mut all);
+            all
+        };
+        for (code, input) in [
+            (include_str!("../samples/Bitwidth.bf"), &b""[..]),
+            (include_str!("../samples/NumWarp.bf"), b"3.14\n"),
+            ("[.],[.[-],]++[>+++<-]>.", b"ab"),
+        ] {
+            let expected = run(code, input);
+            for optimisations in [
+                Optimisations::level(0),
+                Optimisations::level(1),
+                without(|all| all.merge_runs = false),
+                without(|all| all.clear_loops = false),
+                without(|all| all.scan_loops = false),
+                without(|all| all.known_cells = false),
+                without(|all| all.multiply_loops = false),
+                without(|all| all.fold_moves = false),
+            ] {
+                let settings = Settings {
+                    optimisations,
+                    ..Settings::default()
+                };
+                assert_eq!(
+                    run_with(code, settings, input),
+                    expected,
+                    "{optimisations:?}"
+                );
             }
-            Instruction::Stop => break,
         }
-        instruction_index += 1;
     }
 
-    memory
+    #[test]
+    fn scans_agree() {
+        // Runs of cells longer than a search chunk, scanned both ways, with and without strides.
+        let ones = "+>".repeat(70);
+        let twos = "++>>".repeat(40);
+        let programs = [
+            format!(">{ones}<[<]>[>]<[-<]"),
+            format!(">>{twos}<<[<<]>>[>>]<<[-<<]>>[+>>]"),
+            format!(">>>{twos}<<[-<<<]+<[<]"),
+            format!("{ones}<[-<]"),
+            format!("{ones}+[->]"),
+        ];
+        for tape in [
+            TapePolicy::Grow,
+            TapePolicy::Wrap(100),
+            TapePolicy::Fixed(200),
+            TapePolicy::Bidirectional,
+        ] {
+            for code in &programs {
+                let execute = |optimisations| {
+                    let instructions = Compiler::new(lex(code))
+                        .with_tape(tape)
+                        .with_optimisations(optimisations)
+                        .compile()
+                        .unwrap();
+                    let settings = Settings {
+                        tape,
+                        ..Settings::default()
+                    };
+                    // Tapes grow by different amounts when moves are merged, so only compare what is in use.
+                    let trim = |memory: Vec<u32>| {
+                        let start = memory.iter().position(|&cell| cell != 0);
+                        let end = memory.iter().rposition(|&cell| cell != 0);
+                        match (start, end) {
+                            (Some(start), Some(end)) => memory[start..=end].to_vec(),
+                            _ => Vec::new(),
+                        }
+                    };
+                    match execute_with(&instructions, &settings, &b""[..], Vec::new()) {
+                        Ok(memory) => Ok(trim(memory)),
+                        Err(error) => Err((error.kind.to_string(), trim(error.memory))),
+                    }
+                };
+                assert_eq!(
+                    execute(Optimisations::ALL),
+                    execute(Optimisations::NONE),
+                    "{code} on {tape:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn prints_agree() {
+        for (tape, code) in [
+            (TapePolicy::Grow, ">+<[>]>.<<."),
+            (TapePolicy::Grow, "+.<."),
+            (TapePolicy::Fixed(2), "+>+.>."),
+            (TapePolicy::Fixed(2), ">+<>-.>."),
+            (TapePolicy::Wrap(2), "+<.>>."),
+        ] {
+            let execute = |optimisations| {
+                let instructions = Compiler::new(lex(code))
+                    .with_tape(tape)
+                    .with_optimisations(optimisations)
+                    .compile()
+                    .unwrap();
+                let settings = Settings {
+                    tape,
+                    ..Settings::default()
+                };
+                let mut output = Vec::new();
+                let error = execute_with(&instructions, &settings, &b""[..], &mut output)
+                    .err()
+                    .map(|error| error.kind.to_string());
+                (output, error)
+            };
+            assert_eq!(
+                execute(Optimisations::ALL),
+                execute(Optimisations::NONE),
+                "{code} on {tape:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn dead_loops_agree() {
+        // A loop on a cell known to be 0 is only left out if the cell is on the tape.
+        let code = "..<[+>]>,+>>+.+";
+        let execute = |level| {
+            let instructions = Compiler::new(lex(code))
+                .with_optimisations(Optimisations::level(level))
+                .compile()
+                .unwrap();
+            let mut output = Vec::new();
+            let error = execute_with(&instructions, &Settings::default(), &b""[..], &mut output)
+                .err()
+                .map(|error| error.kind.to_string());
+            (output, error)
+        };
+        assert_eq!(
+            execute(2),
+            (
+                vec![0, 0],
+                Some("pointer moved left of the first cell".to_string())
+            )
+        );
+        assert_eq!(execute(2), execute(1));
+    }
+
+    #[test]
+    fn input_works() {
+        assert_eq!(run(",+.,+.", b"ab"), b"bc");
+    }
+
+    #[test]
+    fn eof_works() {
+        let code = "+++++,.";
+        let settings = |eof| Settings {
+            eof,
+            ..Settings::default()
+        };
+        assert_eq!(run_with(code, settings(EofBehavior::Unchanged), b""), [5]);
+        assert_eq!(run_with(code, settings(EofBehavior::Zero), b""), [0]);
+        assert_eq!(run_with(code, settings(EofBehavior::Max), b""), [255]);
+    }
+
+    #[test]
+    fn cell_width_works() {
+        let settings = |cell_width| Settings {
+            cell_width,
+            ..Settings::default()
+        };
+        let code = include_str!("../samples/Bitwidth.bf");
+        assert_eq!(
+            run_with(code, settings(CellWidth::U8), b""),
+            b"Hello World! 255\n"
+        );
+        assert_eq!(
+            run_with(code, settings(CellWidth::U16), b""),
+            b"Hello world! 65535\n"
+        );
+        assert_eq!(
+            run_with(code, settings(CellWidth::U32), b""),
+            b"Hello, world!\n"
+        );
+    }
+
+    #[test]
+    fn tape_policy_works() {
+        let execute = |tape, code| {
+            let instructions = Compiler::new(lex(code)).with_tape(tape).compile().unwrap();
+            let settings = Settings {
+                tape,
+                ..Settings::default()
+            };
+            execute_with(&instructions, &settings, &b""[..], Vec::new())
+        };
+
+        let error = execute(TapePolicy::Grow, "+>+<<+").unwrap_err();
+        assert!(matches!(error.kind, RuntimeErrorKind::PointerUnderflow));
+        assert_eq!(error.memory[..2], [1, 1]);
+
+        let memory = execute(TapePolicy::Wrap(3), "+<++>>>+++").unwrap();
+        assert_eq!(memory, [1, 0, 5]);
+
+        let memory = execute(TapePolicy::Bidirectional, "+++[<<++>>-]+").unwrap();
+        let start = memory.iter().position(|&cell| cell != 0).unwrap();
+        assert_eq!(memory[start..start + 3], [6, 0, 1]);
+
+        let error = execute(TapePolicy::Fixed(2), ",+[>>+<<-]").unwrap_err();
+        assert!(matches!(error.kind, RuntimeErrorKind::PointerOverflow));
+        assert!(execute(TapePolicy::Fixed(3), ",+[>>+<<-]").is_ok());
+    }
+
+    #[test]
+    fn budget_works() {
+        let execute = |budget, tape, code, input: &[u8]| {
+            let instructions = Compiler::new(lex(code)).with_tape(tape).compile().unwrap();
+            let settings = Settings {
+                tape,
+                budget,
+                ..Settings::default()
+            };
+            execute_with(&instructions, &settings, input, Vec::new())
+        };
+        let steps = |max_steps| Budget {
+            max_steps: Some(max_steps),
+            ..Budget::default()
+        };
+
+        let error = execute(steps(100), TapePolicy::Grow, "+[]", b"").unwrap_err();
+        assert!(matches!(
+            error.kind,
+            RuntimeErrorKind::StepLimit { steps: 100 }
+        ));
+        assert_eq!(error.memory[0], 1);
+
+        // Increment and scan loops that never end count every pass.
+        let error = execute(steps(1000), TapePolicy::Grow, ",[++]", b"a").unwrap_err();
+        assert!(matches!(
+            error.kind,
+            RuntimeErrorKind::StepLimit { steps: 1000 }
+        ));
+        assert_eq!(error.memory[0], (u32::from(b'a') + 2 * 998) % 256);
+        let error = execute(steps(1000), TapePolicy::Wrap(2), "+>+[>]", b"").unwrap_err();
+        assert!(matches!(
+            error.kind,
+            RuntimeErrorKind::StepLimit { steps: 1000 }
+        ));
+
+        let budget = Budget {
+            timeout: Some(Duration::from_millis(10)),
+            ..Budget::default()
+        };
+        let error = execute(budget, TapePolicy::Grow, "+[]", b"").unwrap_err();
+        assert!(matches!(error.kind, RuntimeErrorKind::Timeout { .. }));
+
+        assert!(execute(steps(3), TapePolicy::Grow, "+>+", b"").is_ok());
+    }
+
+    #[test]
+    fn tape_limit_works() {
+        let execute = |tape, code| {
+            let instructions = Compiler::new(lex(code)).with_tape(tape).compile().unwrap();
+            let settings = Settings {
+                tape,
+                tape_limit: Some(40),
+                ..Settings::default()
+            };
+            execute_with(&instructions, &settings, &b""[..], Vec::new())
+        };
+
+        let error = execute(TapePolicy::Grow, "+[>+]").unwrap_err();
+        assert!(matches!(error.kind, RuntimeErrorKind::TapeLimit));
+        assert_eq!(error.memory, [1; 40]);
+
+        let error = execute(TapePolicy::Bidirectional, "+[<+]").unwrap_err();
+        assert!(matches!(error.kind, RuntimeErrorKind::TapeLimit));
+        assert_eq!(error.memory.len(), 40);
+
+        assert_eq!(
+            execute(TapePolicy::Grow, ">>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>+")
+                .unwrap()
+                .len(),
+            40
+        );
+    }
 }
@@ -0,0 +1,493 @@
+use crate::compile::Instruction;
+use crate::settings::{CellWidth, EofBehavior, Settings, TapePolicy};
+use crate::MEMORY_RESIZE_AMOUNT;
+
+/// Cells in the tape of a growing tape policy when no tape limit is set.
+pub const DEFAULT_TAPE_LIMIT: usize = 1 << 24;
+
+/// Bytes in the input and output buffers.
+const BUFFER_SIZE: usize = 4096;
+
+/// Labels of the errors a program can stop with, and their messages.
+const ERRORS: [(&str, &str); 3] = [
+    ("pointer_underflow", "pointer moved left of the first cell"),
+    ("pointer_overflow", "pointer moved right of the last cell"),
+    ("tape_limit_exceeded", "tape limit exceeded"),
+];
+
+macro_rules! emit {
+    ($code:expr, $($argument:tt)*) => {
+        $code.push('\t');
+        $code.push_str(&format!($($argument)*));
+        $code.push('\n');
+    };
+}
+
+fn label(code: &mut String, name: &str) {
+    code.push_str(name);
+    code.push_str(":\n");
+}
+
+/// Emits code that moves the cell index in `register` by `offset` cells,
+/// keeping it on the tape or jumping to the error it causes.
+fn emit_move(
+    code: &mut String,
+    settings: &Settings,
+    register: &str,
+    offset: u32,
+    forward: bool,
+    labels: &mut usize,
+) {
+    let limit = settings.tape_limit.unwrap_or(DEFAULT_TAPE_LIMIT);
+    match (settings.tape, forward) {
+        (TapePolicy::Grow, true) => {
+            emit!(code, "add {register}, {offset}");
+            emit!(code, "cmp {register}, {limit}");
+            emit!(code, "jae tape_limit_exceeded");
+        }
+        (TapePolicy::Fixed(size), true) => {
+            emit!(code, "add {register}, {offset}");
+            emit!(code, "cmp {register}, {size}");
+            emit!(code, "jae pointer_overflow");
+        }
+        (TapePolicy::Grow | TapePolicy::Fixed(_), false) => {
+            emit!(code, "sub {register}, {offset}");
+            emit!(code, "jb pointer_underflow");
+        }
+        (TapePolicy::Wrap(size), forward) => {
+            let offset = offset as usize % size;
+            if offset == 0 {
+                return;
+            }
+            // Add or subtract, then take the wrapped index if it went off the end.
+            if forward {
+                emit!(code, "lea rdx, [{register} - {}]", size - offset);
+                emit!(code, "add {register}, {offset}");
+                emit!(code, "cmp {register}, {size}");
+                emit!(code, "cmovae {register}, rdx");
+            } else {
+                emit!(code, "lea rdx, [{register} + {}]", size - offset);
+                emit!(code, "sub {register}, {offset}");
+                emit!(code, "cmovb {register}, rdx");
+            }
+        }
+        (TapePolicy::Bidirectional, forward) => {
+            let done = format!(".L{labels}");
+            *labels += 1;
+            if forward {
+                emit!(code, "add {register}, {offset}");
+                emit!(code, "cmp {register}, r14");
+                emit!(code, "jb {done}");
+            } else {
+                emit!(code, "sub {register}, {offset}");
+                emit!(code, "cmp {register}, r13");
+                emit!(code, "jge {done}");
+            }
+            if register != "rcx" {
+                emit!(code, "mov rcx, {register}");
+            }
+            emit!(
+                code,
+                "call {}",
+                if forward { "grow_right" } else { "grow_left" }
+            );
+            label(code, &done);
+        }
+    }
+}
+
+/// Emits the routines the program calls, and the data they use.
+#[allow(clippy::too_many_lines)]
+fn emit_runtime(code: &mut String, settings: &Settings, tape_bytes: usize, output: &[&[u8]]) {
+    let limit = settings.tape_limit.unwrap_or(DEFAULT_TAPE_LIMIT);
+
+    // Writes out the output buffer.
+    label(code, "flush");
+    emit!(code, "lea rsi, [rip + output_buffer]");
+    label(code, "flush_loop");
+    emit!(code, "test r15, r15");
+    emit!(code, "jz flush_done");
+    emit!(code, "mov eax, 1");
+    emit!(code, "mov edi, 1");
+    emit!(code, "mov rdx, r15");
+    emit!(code, "syscall");
+    emit!(code, "test rax, rax");
+    emit!(code, "jle io_error");
+    emit!(code, "add rsi, rax");
+    emit!(code, "sub r15, rax");
+    emit!(code, "jmp flush_loop");
+    label(code, "flush_done");
+    emit!(code, "ret");
+
+    // Buffers the byte in `al`, flushing at the end of a line or when full.
+    label(code, "put");
+    emit!(code, "lea rdi, [rip + output_buffer]");
+    emit!(code, "mov byte ptr [rdi + r15], al");
+    emit!(code, "inc r15");
+    emit!(code, "cmp al, 10");
+    emit!(code, "je flush");
+    emit!(code, "cmp r15, {BUFFER_SIZE}");
+    emit!(code, "je flush");
+    emit!(code, "ret");
+
+    // Buffers the rdx bytes at rsi, one at a time.
+    label(code, "put_bytes");
+    emit!(code, "test rdx, rdx");
+    emit!(code, "jz put_bytes_done");
+    emit!(code, "mov al, byte ptr [rsi]");
+    emit!(code, "push rsi");
+    emit!(code, "push rdx");
+    emit!(code, "call put");
+    emit!(code, "pop rdx");
+    emit!(code, "pop rsi");
+    emit!(code, "inc rsi");
+    emit!(code, "dec rdx");
+    emit!(code, "jmp put_bytes");
+    label(code, "put_bytes_done");
+    emit!(code, "ret");
+
+    // Returns the next byte in `eax`, or -1 at the end of input.
+    label(code, "get");
+    emit!(code, "mov rax, qword ptr [rip + input_position]");
+    emit!(code, "cmp rax, qword ptr [rip + input_length]");
+    emit!(code, "jb get_buffered");
+    emit!(code, "call flush");
+    emit!(code, "xor eax, eax");
+    emit!(code, "xor edi, edi");
+    emit!(code, "lea rsi, [rip + input_buffer]");
+    emit!(code, "mov edx, {BUFFER_SIZE}");
+    emit!(code, "syscall");
+    emit!(code, "test rax, rax");
+    emit!(code, "js io_error");
+    emit!(code, "jz get_end");
+    emit!(code, "mov qword ptr [rip + input_length], rax");
+    emit!(code, "xor eax, eax");
+    label(code, "get_buffered");
+    emit!(code, "lea rsi, [rip + input_buffer]");
+    emit!(code, "movzx ecx, byte ptr [rsi + rax]");
+    emit!(code, "inc rax");
+    emit!(code, "mov qword ptr [rip + input_position], rax");
+    emit!(code, "mov eax, ecx");
+    emit!(code, "ret");
+    label(code, "get_end");
+    emit!(code, "mov eax, -1");
+    emit!(code, "ret");
+
+    if settings.tape == TapePolicy::Bidirectional {
+        // The tape is the cells from r13 up to r14, inside a buffer with room to grow both ways.
+        // Both routines take the index in rcx and keep rax.
+        label(code, "grow_right");
+        emit!(code, "mov rdx, rcx");
+        emit!(code, "sub rdx, r13");
+        emit!(code, "cmp rdx, {limit}");
+        emit!(code, "jae tape_limit_exceeded");
+        emit!(code, "add rdx, {MEMORY_RESIZE_AMOUNT}");
+        emit!(code, "mov rsi, {limit}");
+        emit!(code, "cmp rdx, rsi");
+        emit!(code, "cmova rdx, rsi");
+        emit!(code, "lea r14, [r13 + rdx]");
+        emit!(code, "ret");
+
+        label(code, "grow_left");
+        emit!(code, "mov rdx, r13");
+        emit!(code, "sub rdx, rcx");
+        emit!(code, "mov rsi, {limit}");
+        emit!(code, "sub rsi, r14");
+        emit!(code, "add rsi, r13");
+        emit!(code, "cmp rdx, rsi");
+        emit!(code, "ja tape_limit_exceeded");
+        emit!(code, "add rdx, {MEMORY_RESIZE_AMOUNT}");
+        emit!(code, "cmp rdx, rsi");
+        emit!(code, "cmova rdx, rsi");
+        emit!(code, "sub r13, rdx");
+        emit!(code, "ret");
+    }
+
+    for (name, message) in ERRORS {
+        label(code, name);
+        emit!(code, "lea rsi, [rip + {name}_message]");
+        emit!(code, "mov edx, {}", message.len() + 1);
+        emit!(code, "jmp fail");
+    }
+
+    // Flushes output, writes the message in rsi and rdx to stderr and exits.
+    label(code, "fail");
+    emit!(code, "push rsi");
+    emit!(code, "push rdx");
+    emit!(code, "call flush");
+    emit!(code, "pop rdx");
+    emit!(code, "pop rsi");
+    emit!(code, "mov eax, 1");
+    emit!(code, "mov edi, 2");
+    emit!(code, "syscall");
+    label(code, "io_error");
+    emit!(code, "mov eax, 60");
+    emit!(code, "mov edi, 1");
+    emit!(code, "syscall");
+
+    emit!(code, ".section .rodata");
+    for (name, message) in ERRORS {
+        label(code, &format!("{name}_message"));
+        emit!(code, ".ascii \"{message}\\n\"");
+    }
+    for (index, bytes) in output.iter().enumerate() {
+        label(code, &format!("output_bytes{index}"));
+        if !bytes.is_empty() {
+            let bytes: Vec<_> = bytes.iter().map(u8::to_string).collect();
+            emit!(code, ".byte {}", bytes.join(", "));
+        }
+    }
+
+    emit!(code, ".bss");
+    emit!(code, ".balign 64");
+    label(code, "tape");
+    emit!(code, ".skip {tape_bytes}");
+    label(code, "output_buffer");
+    emit!(code, ".skip {BUFFER_SIZE}");
+    label(code, "input_buffer");
+    emit!(code, ".skip {BUFFER_SIZE}");
+    label(code, "input_position");
+    emit!(code, ".skip 8");
+    label(code, "input_length");
+    emit!(code, ".skip 8");
+}
+
+/// Compiles instructions into x86-64 assembly for Linux, in GNU `as` syntax.
+///
+/// The program uses `read`, `write` and `exit` system calls and a static tape,
+/// so it needs only `as` and `ld` to build. A growing tape policy gets a tape of
+/// the tape limit, or [`DEFAULT_TAPE_LIMIT`] cells without one.
+///
+/// # Panics
+///
+/// Panics if the tape policy or tape limit has a size of 0.
+#[allow(clippy::too_many_lines)]
+#[must_use]
+pub fn to_asm(instructions: &[Instruction], settings: &Settings) -> String {
+    let mut code = String::with_capacity(4096);
+
+    let limit = settings.tape_limit.unwrap_or(DEFAULT_TAPE_LIMIT);
+    assert!(limit > 0 && settings.initial_tape_size() > 0);
+
+    let (size, register, bytes) = match settings.cell_width {
+        CellWidth::U8 => ("byte", "al", 1),
+        CellWidth::U16 => ("word", "ax", 2),
+        CellWidth::U32 => ("dword", "eax", 4),
+    };
+    let scale = if bytes == 1 {
+        String::new()
+    } else {
+        format!("*{bytes}")
+    };
+    let cell = &format!("{size} ptr [rbx + r12{scale}]");
+    let target = &format!("{size} ptr [rbx + rcx{scale}]");
+    let load = match settings.cell_width {
+        CellWidth::U8 | CellWidth::U16 => "movzx",
+        CellWidth::U32 => "mov",
+    };
+    // Immediates as the signed value of the same bits, which `as` accepts for every width.
+    let immediate = |value: u32| match settings.cell_width {
+        CellWidth::U8 => i32::from(value as u8 as i8),
+        CellWidth::U16 => i32::from(value as u16 as i16),
+        CellWidth::U32 => value as i32,
+    };
+
+    let tape_cells = match settings.tape {
+        TapePolicy::Grow => limit,
+        TapePolicy::Wrap(size) | TapePolicy::Fixed(size) => size,
+        TapePolicy::Bidirectional => 2 * limit,
+    };
+
+    emit!(code, ".intel_syntax noprefix");
+    emit!(code, ".text");
+    emit!(code, ".globl _start");
+    label(&mut code, "_start");
+    emit!(code, "lea rbx, [rip + tape]");
+    if settings.tape == TapePolicy::Bidirectional {
+        emit!(code, "mov r12, {limit}");
+        emit!(code, "mov r13, r12");
+        emit!(code, "lea r14, [r12 + {}]", settings.initial_tape_size());
+    } else {
+        emit!(code, "xor r12d, r12d");
+    }
+    emit!(code, "xor r15d, r15d");
+
+    let mut labels = 0;
+    // Bytes of each `OutputBytes`, which go in the data section.
+    let mut output: Vec<&[u8]> = Vec::new();
+    // Puts the index of the cell `offset` cells from the pointer in rcx, unless it is the
+    // current cell, and gives the operand for that cell.
+    macro_rules! address {
+        ($offset:expr) => {{
+            let offset: i32 = $offset;
+            if offset == 0 {
+                cell
+            } else {
+                emit!(code, "mov rcx, r12");
+                let forward = offset.is_positive();
+                emit_move(
+                    &mut code,
+                    settings,
+                    "rcx",
+                    offset.unsigned_abs(),
+                    forward,
+                    &mut labels,
+                );
+                target
+            }
+        }};
+    }
+    let mut instruction_index = 0;
+    while let Some(instruction) = instructions.get(instruction_index) {
+        match instruction {
+            Instruction::Forward(offset) => {
+                emit_move(&mut code, settings, "r12", *offset, true, &mut labels);
+            }
+            Instruction::Backward(offset) => {
+                emit_move(&mut code, settings, "r12", *offset, false, &mut labels);
+            }
+            Instruction::Increment { offset, amount } => {
+                let address = address!(*offset);
+                emit!(code, "add {address}, {}", immediate(*amount));
+            }
+            Instruction::SetCell { offset, value } => {
+                let address = address!(*offset);
+                emit!(code, "mov {address}, {}", immediate(*value));
+            }
+            Instruction::LoopStart(_loop_end) => {
+                emit!(code, "cmp {cell}, 0");
+                emit!(code, "je .Lend{instruction_index}");
+                label(&mut code, &format!(".Lstart{instruction_index}"));
+            }
+            Instruction::LoopEnd(loop_start) => {
+                let loop_start = loop_start - 1;
+                emit!(code, "cmp {cell}, 0");
+                emit!(code, "jne .Lstart{loop_start}");
+                label(&mut code, &format!(".Lend{loop_start}"));
+            }
+            Instruction::IncrementLoop(increment) => {
+                let (done, repeat) = (labels, labels + 1);
+                labels += 2;
+                emit!(code, "mov {register}, {cell}");
+                emit!(code, "test {register}, {register}");
+                emit!(code, "je .L{done}");
+                label(&mut code, &format!(".L{repeat}"));
+                emit!(code, "add {register}, {}", immediate(*increment));
+                emit!(code, "jne .L{repeat}");
+                emit!(code, "mov {cell}, 0");
+                label(&mut code, &format!(".L{done}"));
+            }
+            Instruction::MultiplyForward(offset, multiplier)
+            | Instruction::MultiplyBackward(offset, multiplier) => {
+                let done = labels;
+                labels += 1;
+                emit!(code, "{load} eax, {cell}");
+                emit!(code, "test eax, eax");
+                emit!(code, "je .L{done}");
+                emit!(code, "mov rcx, r12");
+                let forward = matches!(
+                    instructions[instruction_index],
+                    Instruction::MultiplyForward(..)
+                );
+                emit_move(&mut code, settings, "rcx", *offset, forward, &mut labels);
+                if *multiplier != 1 {
+                    emit!(code, "imul eax, eax, {}", *multiplier as i32);
+                }
+                emit!(code, "add {target}, {register}");
+                label(&mut code, &format!(".L{done}"));
+            }
+            Instruction::MultiplyProduct {
+                offset,
+                factor,
+                multiplier,
+            } => {
+                let done = labels;
+                labels += 1;
+                emit!(code, "{load} eax, {cell}");
+                emit!(code, "test eax, eax");
+                emit!(code, "je .L{done}");
+                let factor = address!(*factor);
+                emit!(code, "{load} edi, {factor}");
+                emit!(code, "imul eax, edi");
+                if *multiplier != 1 {
+                    emit!(code, "imul eax, eax, {}", *multiplier as i32);
+                }
+                let address = address!(*offset);
+                emit!(code, "add {address}, {register}");
+                label(&mut code, &format!(".L{done}"));
+            }
+            Instruction::ForwardLoop(offset)
+            | Instruction::BackwardLoop(offset)
+            | Instruction::IncrementForwardLoop(offset, _)
+            | Instruction::IncrementBackwardLoop(offset, _) => {
+                let (done, repeat) = (labels, labels + 1);
+                labels += 2;
+                let forward = matches!(
+                    instructions[instruction_index],
+                    Instruction::ForwardLoop(_) | Instruction::IncrementForwardLoop(..)
+                );
+                emit!(code, "cmp {cell}, 0");
+                emit!(code, "je .L{done}");
+                label(&mut code, &format!(".L{repeat}"));
+                if let Instruction::IncrementForwardLoop(_, amount)
+                | Instruction::IncrementBackwardLoop(_, amount) = instructions[instruction_index]
+                {
+                    emit!(code, "add {cell}, {}", immediate(amount));
+                }
+                emit_move(&mut code, settings, "r12", *offset, forward, &mut labels);
+                emit!(code, "cmp {cell}, 0");
+                emit!(code, "jne .L{repeat}");
+                label(&mut code, &format!(".L{done}"));
+            }
+            Instruction::Output { offset } => {
+                let index = if *offset == 0 { "r12" } else { "rcx" };
+                address!(*offset);
+                emit!(code, "movzx eax, byte ptr [rbx + {index}{scale}]");
+                emit!(code, "call put");
+            }
+            Instruction::Print { value, .. } => {
+                emit!(code, "mov eax, {value}");
+                emit!(code, "call put");
+            }
+            Instruction::OutputBytes(text) => {
+                emit!(code, "lea rsi, [rip + output_bytes{}]", output.len());
+                emit!(code, "mov edx, {}", text.len());
+                emit!(code, "call put_bytes");
+                output.push(text);
+            }
+            Instruction::Input => {
+                emit!(code, "call get");
+                match settings.eof {
+                    EofBehavior::Unchanged => {
+                        let done = labels;
+                        labels += 1;
+                        emit!(code, "test eax, eax");
+                        emit!(code, "js .L{done}");
+                        emit!(code, "mov {cell}, {register}");
+                        label(&mut code, &format!(".L{done}"));
+                    }
+                    EofBehavior::Zero => {
+                        emit!(code, "xor ecx, ecx");
+                        emit!(code, "test eax, eax");
+                        emit!(code, "cmovs eax, ecx");
+                        emit!(code, "mov {cell}, {register}");
+                    }
+                    // -1 is already the largest value.
+                    EofBehavior::Max => {
+                        emit!(code, "mov {cell}, {register}");
+                    }
+                }
+            }
+            Instruction::Stop => break,
+        }
+        instruction_index += 1;
+    }
+    emit!(code, "call flush");
+    emit!(code, "mov eax, 60");
+    emit!(code, "xor edi, edi");
+    emit!(code, "syscall");
+
+    emit_runtime(&mut code, settings, tape_cells * bytes, &output);
+
+    code
+}
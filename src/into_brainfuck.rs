@@ -0,0 +1,757 @@
+use core::iter;
+
+use crate::compile::{inverse, Instruction, KnownCells};
+use crate::settings::Settings;
+
+/// Writes Brainfuck while tracking what the compiler will know about it.
+struct Emitter {
+    code: String,
+    max: u32,
+
+    /// Values the compiler will know cells have, which turn increments into `SetCell`.
+    known: KnownCells,
+
+    /// Whether the code ends in `+` or `-`, which further increments would merge with.
+    after_increment: bool,
+
+    /// Cells the Brainfuck pointer is right of the instructions' pointer, while a move is deferred.
+    position: i64,
+
+    /// Whether the compiler works out what loops run a known number of times do.
+    solve_loops: bool,
+
+    /// Whether only output and cells being set have been written, which the compiler runs while
+    /// compiling, so loops there only need to leave the same cells.
+    prefix: bool,
+}
+
+impl Emitter {
+    fn repeat(&mut self, character: char, count: usize) {
+        self.code.extend(iter::repeat_n(character, count));
+    }
+    fn increment(&mut self, value: u32) {
+        if value > self.max / 2 {
+            self.repeat('-', (self.max - value) as usize + 1);
+        } else {
+            self.repeat('+', value as usize);
+        }
+        self.after_increment |= value != 0;
+    }
+    /// How many characters `increment` writes for the value.
+    const fn increment_length(&self, value: u32) -> u32 {
+        if value > self.max / 2 {
+            self.max - value + 1
+        } else {
+            value
+        }
+    }
+    fn step(&mut self, offset: i64) {
+        if offset.is_positive() {
+            self.repeat('>', offset as usize);
+        } else {
+            self.repeat('<', offset.unsigned_abs() as usize);
+        }
+    }
+    fn push(&mut self, code: &str) {
+        self.code.push_str(code);
+        self.after_increment = false;
+    }
+    /// Adds `value` to the cell at `offset`, where the pointer is, as a product made by a loop
+    /// in a borrowed cell, if that is shorter than adding it directly.
+    ///
+    /// Returns whether it did.
+    fn multiply_in(&mut self, offset: i64, value: u32) -> bool {
+        let Some((spare, known)) = self.known.spare(&[offset]) else {
+            return false;
+        };
+        let negative = value > self.max / 2;
+        let size = if negative {
+            self.max - value + 1
+        } else {
+            value
+        };
+        let passes = size.isqrt();
+        let signed = |amount: u32| {
+            if negative {
+                amount.wrapping_neg() & self.max
+            } else {
+                amount
+            }
+        };
+        let (amount, remainder) = (signed(size / passes), signed(size % passes));
+
+        let distance = u32::try_from(offset.abs_diff(spare)).unwrap_or(u32::MAX);
+        let clear = if known == 0 {
+            0
+        } else {
+            3 + self.increment_length(known)
+        };
+        let length = [
+            passes,
+            self.increment_length(amount),
+            self.increment_length(remainder),
+            distance.saturating_mul(4),
+            clear,
+            3,
+        ]
+        .into_iter()
+        .fold(0, u32::saturating_add);
+        if length >= self.increment_length(value) {
+            return false;
+        }
+
+        self.push("");
+        self.step(spare - offset);
+        if known != 0 {
+            self.push("[-]");
+        }
+        self.increment(passes);
+        self.push("[-");
+        self.step(offset - spare);
+        self.increment(amount);
+        self.step(spare - offset);
+        self.push("]");
+        self.increment(known);
+        self.push("");
+        self.step(offset - spare);
+        self.increment(remainder);
+        true
+    }
+    /// Writes `changes` to other cells, made by a loop run the known number of times the cell at
+    /// `offset` says, as that loop, if it is shorter than adding them directly.
+    ///
+    /// Returns whether it did.
+    fn known_loop(&mut self, offset: i64, changes: &[(i64, u32)]) -> bool {
+        let Some(count) = self.known.get(offset).filter(|&count| count != 0) else {
+            return false;
+        };
+        // The loop can only make multiples of the count's factors of 2.
+        let shift = count.trailing_zeros();
+        if changes
+            .iter()
+            .any(|&(_, change)| change.trailing_zeros() < shift)
+        {
+            return false;
+        }
+
+        let max = self.max;
+        let amounts = |step: u32| {
+            let passes = (count.wrapping_mul(inverse(step).wrapping_neg()) & max) >> shift;
+            changes.iter().map(move |&(_, change)| {
+                (change >> shift).wrapping_mul(inverse(passes)) & (max >> shift)
+            })
+        };
+        let length = |step: u32| {
+            amounts(step)
+                .map(|amount| self.increment_length(amount))
+                .fold(self.increment_length(step), u32::saturating_add)
+        };
+        let step = (1..=max.min(255))
+            .step_by(2)
+            .flat_map(|step| [step.wrapping_neg() & max, step])
+            .min_by_key(|&step| length(step))
+            .unwrap_or(max);
+        let direct = changes
+            .iter()
+            .map(|&(_, change)| self.increment_length(change))
+            .fold(0, u32::saturating_add);
+        if length(step).saturating_add(2) >= direct {
+            return false;
+        }
+
+        let amounts: Vec<u32> = amounts(step).collect();
+        self.go_to(offset);
+        self.push("[");
+        self.increment(step);
+        let mut position = offset;
+        for (&(target, _), amount) in changes.iter().zip(amounts) {
+            self.step(target - position);
+            self.increment(amount);
+            position = target;
+        }
+        self.step(offset - position);
+        self.push("]");
+        true
+    }
+    /// Whether `instructions`, which only change cells and move, cannot fail, as every cell they
+    /// change or move to is known to be on the tape.
+    fn cannot_fail(&self, instructions: &[Instruction]) -> bool {
+        let mut known = self.known.clone();
+        instructions.iter().all(|instruction| {
+            let offset = match *instruction {
+                Instruction::Forward(offset) => i64::from(offset),
+                Instruction::Backward(offset) => -i64::from(offset),
+                Instruction::Increment { offset, .. } | Instruction::SetCell { offset, .. } => {
+                    i64::from(offset)
+                }
+                _ => 0,
+            };
+            let on_tape = known.is_on_tape(offset);
+            known.update(instruction);
+            on_tape
+        })
+    }
+    /// Moves to the cell `offset` cells from the instructions' pointer.
+    fn go_to(&mut self, offset: i64) {
+        if offset != self.position {
+            self.push("");
+            self.step(offset - self.position);
+            self.position = offset;
+        }
+    }
+}
+
+/// Whether the instruction only changes cells or moves, so nothing reads what it does if only
+/// others like it follow.
+const fn is_tail(instruction: &Instruction) -> bool {
+    matches!(
+        instruction,
+        Instruction::Forward(_)
+            | Instruction::Backward(_)
+            | Instruction::Increment { .. }
+            | Instruction::SetCell { .. }
+            | Instruction::Stop
+    )
+}
+
+/// Compiles instructions back into Brainfuck, without comments.
+///
+/// Compiling the result gives the same instructions, as long as they came from the compiler.
+#[must_use]
+pub fn to_brainfuck(instructions: &[Instruction], settings: &Settings) -> String {
+    emit(instructions, settings, false)
+}
+
+/// Like [`to_brainfuck`], but leaves out cells changed and moves made at the end, which nothing
+/// reads, where that cannot fail.
+///
+/// Compiling the result gives the same instructions up to that tail.
+#[must_use]
+pub fn to_trimmed_brainfuck(instructions: &[Instruction], settings: &Settings) -> String {
+    emit(instructions, settings, true)
+}
+
+#[allow(clippy::too_many_lines)]
+fn emit(instructions: &[Instruction], settings: &Settings, trim_end: bool) -> String {
+    let mut emitter = Emitter {
+        code: String::new(),
+        max: settings.cell_width.max(),
+        known: KnownCells::new(settings.tape, settings.tape_limit, settings.cell_width),
+        after_increment: false,
+        position: 0,
+        solve_loops: settings.optimisations.known_cells && settings.optimisations.multiply_loops,
+        prefix: settings.optimisations.evaluate_prefix
+            && settings.optimisations.known_cells
+            && settings.optimisations.multiply_loops,
+    };
+
+    // Instructions at the end that only change cells and move, which are left out if they cannot fail.
+    let tail = if trim_end {
+        instructions
+            .iter()
+            .rposition(|instruction| !is_tail(instruction))
+            .map_or(0, |index| index + 1)
+    } else {
+        instructions.len()
+    };
+
+    let mut instruction_index = 0;
+    while let Some(instruction) = instructions.get(instruction_index) {
+        if instruction_index >= tail && emitter.cannot_fail(&instructions[instruction_index..]) {
+            break;
+        }
+        let start = instruction_index;
+        instruction_index += 1;
+        emitter.prefix &= matches!(
+            instruction,
+            Instruction::OutputBytes(_) | Instruction::SetCell { .. }
+        );
+
+        // A loop run a known number of times changes other cells, then clears its own.
+        if emitter.solve_loops {
+            let mut changes: Vec<(i64, u32)> = Vec::new();
+            for (end, change) in instructions.iter().enumerate().skip(start) {
+                let (offset, change) = match *change {
+                    Instruction::Increment { offset, amount }
+                        if emitter.known.get(i64::from(offset)).is_none() =>
+                    {
+                        (i64::from(offset), amount)
+                    }
+                    Instruction::SetCell { offset, value } => {
+                        let offset = i64::from(offset);
+                        // The compiler changes them from right to left, then clears the loop's cell.
+                        if value == 0
+                            && changes.iter().all(|change| change.0 != offset)
+                            && !changes.is_empty()
+                            && emitter.known_loop(offset, &changes)
+                        {
+                            for instruction in &instructions[start..=end] {
+                                emitter.known.update(instruction);
+                            }
+                            instruction_index = end + 1;
+                            break;
+                        }
+                        match emitter.known.get(offset) {
+                            Some(known) if known != value => {
+                                (offset, value.wrapping_sub(known) & emitter.max)
+                            }
+                            _ => break,
+                        }
+                    }
+                    _ => break,
+                };
+                if changes.last().is_some_and(|last| last.0 <= offset) {
+                    break;
+                }
+                changes.push((offset, change));
+            }
+            if instruction_index != start + 1 {
+                continue;
+            }
+        }
+
+        match instruction {
+            Instruction::Forward(offset) => {
+                emitter.go_to(i64::from(*offset));
+                emitter.position = 0;
+                emitter.known.update(instruction);
+                continue;
+            }
+            Instruction::Backward(offset) => {
+                emitter.go_to(-i64::from(*offset));
+                emitter.position = 0;
+                emitter.known.update(instruction);
+                continue;
+            }
+            Instruction::Increment { offset, .. }
+            | Instruction::SetCell { offset, .. }
+            | Instruction::Output { offset }
+            | Instruction::Print { offset, .. } => emitter.go_to(i64::from(*offset)),
+            // The deferred move is made before anything else.
+            _ => emitter.go_to(0),
+        }
+        match instruction {
+            Instruction::Forward(_) | Instruction::Backward(_) => unreachable!(),
+            Instruction::Increment { amount, .. } => emitter.increment(*amount),
+            Instruction::SetCell { offset, value } => {
+                let known = emitter.known.get(i64::from(*offset));
+                match known {
+                    Some(known) if known == *value => {
+                        if emitter.known.is_on_tape(i64::from(*offset)) {
+                            continue;
+                        }
+                        // The compiler only leaves out loops on cells known to be on the tape.
+                        emitter.push("[-]");
+                        emitter.increment(*value);
+                    }
+                    Some(known)
+                        if emitter.prefix
+                            && emitter.multiply_in(
+                                i64::from(*offset),
+                                value.wrapping_sub(known) & emitter.max,
+                            ) => {}
+                    // Add the difference, unless it would merge with the increments before.
+                    Some(known) if *value != 0 && !emitter.after_increment => {
+                        emitter.increment(value.wrapping_sub(known) & emitter.max);
+                    }
+                    known => {
+                        if known != Some(0) {
+                            emitter.push("[-]");
+                        }
+                        emitter.increment(*value);
+                    }
+                }
+            }
+            Instruction::IncrementLoop(increment) => {
+                emitter.push("[");
+                emitter.increment(*increment);
+                emitter.push("]");
+            }
+            Instruction::MultiplyForward(..)
+            | Instruction::MultiplyBackward(..)
+            | Instruction::MultiplyProduct { .. } => {
+                // Consecutive multiplies come from one loop, ended by the `SetCell(0)` that clears it.
+                let mut targets = Vec::new();
+                let mut products = Vec::new();
+                for multiply in &instructions[instruction_index - 1..] {
+                    match multiply {
+                        Instruction::MultiplyForward(offset, multiplier) => {
+                            targets.push((i64::from(*offset), *multiplier));
+                        }
+                        Instruction::MultiplyBackward(offset, multiplier) => {
+                            targets.push((-i64::from(*offset), *multiplier));
+                        }
+                        Instruction::MultiplyProduct {
+                            offset,
+                            factor,
+                            multiplier,
+                        } => products.push((i64::from(*factor), i64::from(*offset), *multiplier)),
+                        _ => break,
+                    }
+                }
+                instruction_index += targets.len() + products.len() - 1;
+                if instructions.get(instruction_index)
+                    == Some(&Instruction::SetCell {
+                        offset: 0,
+                        value: 0,
+                    })
+                {
+                    instruction_index += 1;
+                }
+
+                // The compiler only multiplies by another cell when it knows a cell it can borrow
+                // to copy it back with. IR written by hand may not, so the cell past the others
+                // is borrowed and taken to be 0.
+                let mut offsets: Vec<i64> = targets.iter().map(|target| target.0).collect();
+                offsets.extend(
+                    products
+                        .iter()
+                        .flat_map(|&(factor, offset, _)| [factor, offset]),
+                );
+                offsets.push(0);
+                let spare = emitter
+                    .known
+                    .spare(&offsets)
+                    .unwrap_or_else(|| (offsets.iter().copied().fold(0, i64::max) + 1, 0));
+
+                // Counting down by another odd step can make the multipliers shorter to write,
+                // like `[--->+<]` rather than 171 `+`s with 8-bit cells.
+                let max = emitter.max;
+                let step = (1..=max.min(255))
+                    .step_by(2)
+                    .flat_map(|step| [step, step.wrapping_neg() & max])
+                    .min_by_key(|&step| {
+                        let multipliers = targets.iter().map(|target| target.1);
+                        multipliers
+                            .chain(products.iter().map(|product| product.2))
+                            .map(|multiplier| {
+                                emitter.increment_length(multiplier.wrapping_mul(step) & max)
+                            })
+                            .fold(emitter.increment_length(step), u32::saturating_add)
+                    })
+                    .unwrap_or(1);
+
+                targets.sort_unstable_by_key(|target| target.0);
+                products.sort_unstable();
+                emitter.push("[");
+                emitter.increment(step.wrapping_neg() & max);
+                let mut position = 0;
+                for (offset, multiplier) in targets {
+                    if multiplier != 0 {
+                        emitter.step(offset - position);
+                        emitter.increment(multiplier.wrapping_mul(step) & max);
+                        position = offset;
+                    }
+                }
+                if !products.is_empty() && spare.1 != 0 {
+                    emitter.step(spare.0 - position);
+                    emitter.push("[-]");
+                    position = spare.0;
+                }
+                for (index, &(factor, offset, multiplier)) in products.iter().enumerate() {
+                    if index == 0 || products[index - 1].0 != factor {
+                        // Move the factor to the spare cell, as well as adding it to each target.
+                        emitter.step(factor - position);
+                        emitter.push("[-");
+                        emitter.step(spare.0 - factor);
+                        emitter.increment(1);
+                        position = spare.0;
+                    }
+                    emitter.step(offset - position);
+                    emitter.increment(multiplier.wrapping_mul(step) & max);
+                    position = offset;
+                    if products.get(index + 1).is_none_or(|next| next.0 != factor) {
+                        // Then move it back.
+                        emitter.step(factor - position);
+                        emitter.push("]");
+                        emitter.step(spare.0 - factor);
+                        emitter.push("[-");
+                        emitter.step(factor - spare.0);
+                        emitter.increment(1);
+                        emitter.step(spare.0 - factor);
+                        emitter.push("]");
+                        position = spare.0;
+                    }
+                }
+                if !products.is_empty() && spare.1 != 0 {
+                    emitter.increment(spare.1);
+                }
+                emitter.step(-position);
+                emitter.push("]");
+            }
+            Instruction::ForwardLoop(offset) => {
+                emitter.push("[");
+                emitter.step(i64::from(*offset));
+                emitter.push("]");
+            }
+            Instruction::BackwardLoop(offset) => {
+                emitter.push("[");
+                emitter.step(-i64::from(*offset));
+                emitter.push("]");
+            }
+            Instruction::IncrementForwardLoop(offset, amount) => {
+                emitter.push("[");
+                emitter.increment(*amount);
+                emitter.step(i64::from(*offset));
+                emitter.push("]");
+            }
+            Instruction::IncrementBackwardLoop(offset, amount) => {
+                emitter.push("[");
+                emitter.increment(*amount);
+                emitter.step(-i64::from(*offset));
+                emitter.push("]");
+            }
+            Instruction::LoopStart(_loop_end) => emitter.push("["),
+            Instruction::LoopEnd(_loop_start) => emitter.push("]"),
+            // The cell already has the character, as the compiler knew.
+            Instruction::Output { .. } | Instruction::Print { .. } => emitter.push("."),
+            // The compiler only makes these where it knows every cell, so the current cell can be
+            // used to print them and then put back, unless the program ends there.
+            Instruction::OutputBytes(bytes) => {
+                let Some(known) = emitter.known.get(0) else {
+                    unreachable!("output bytes where the current cell is not known")
+                };
+                let mut value = known;
+                for &byte in bytes {
+                    let change = u32::from(byte).wrapping_sub(value) & emitter.max;
+                    if change != 0 && !(emitter.prefix && emitter.multiply_in(0, change)) {
+                        emitter.increment(change);
+                    }
+                    emitter.push(".");
+                    value = u32::from(byte);
+                }
+                if instruction_index >= tail
+                    && emitter.cannot_fail(&instructions[instruction_index..])
+                {
+                    break;
+                }
+                emitter.increment(known.wrapping_sub(value) & emitter.max);
+            }
+            Instruction::Input => emitter.push(","),
+            Instruction::Stop => break,
+        }
+        for instruction in &instructions[start..instruction_index] {
+            emitter.known.update(instruction);
+        }
+    }
+    emitter.code.push('\n');
+
+    emitter.code
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        compile::Compiler,
+        interpreter::execute_with,
+        into_brainfuck::{is_tail, to_brainfuck, to_trimmed_brainfuck},
+        lexer::lex,
+        settings::{CellWidth, Settings, TapePolicy},
+    };
+
+    fn minify(code: &str, cell_width: CellWidth) -> String {
+        let instructions = Compiler::new(lex(code))
+            .with_cell_width(cell_width)
+            .compile()
+            .unwrap();
+        to_brainfuck(
+            &instructions,
+            &Settings {
+                cell_width,
+                ..Settings::default()
+            },
+        )
+    }
+
+    fn assert_round_trips(code: &str, cell_width: CellWidth) {
+        let compile = |code| {
+            Compiler::new(lex(code))
+                .with_cell_width(cell_width)
+                .compile()
+                .unwrap()
+        };
+        let minified = minify(code, cell_width);
+        assert_eq!(
+            compile(code),
+            compile(&minified),
+            "{code:?} minified to {minified:?}"
+        );
+    }
+
+    /// Makes a random program with balanced loops.
+    fn random_program(seed: &mut u64, depth: u32) -> String {
+        let mut code = String::new();
+        loop {
+            // xorshift64
+            *seed ^= *seed << 13;
+            *seed ^= *seed >> 7;
+            *seed ^= *seed << 17;
+            match *seed % 13 {
+                0..=2 => code.push('+'),
+                3..=4 => code.push('-'),
+                5..=6 => code.push('>'),
+                7 => code.push('<'),
+                8 => code.push('.'),
+                9 => code.push(','),
+                10..=11 if depth < 3 => {
+                    code.push('[');
+                    code.push_str(&random_program(seed, depth + 1));
+                    code.push(']');
+                }
+                _ => break code,
+            }
+        }
+    }
+
+    #[test]
+    fn minifies() {
+        assert_eq!(minify(",>-.", CellWidth::U8), ",>-.\n");
+        assert_eq!(minify(",>-.", CellWidth::U16), ",>-.\n");
+        assert_eq!(minify(",[+].", CellWidth::U8), ",[-].\n");
+        assert_eq!(minify(",+++++[-].", CellWidth::U8), ",+++++[-].\n");
+        assert_eq!(minify(",+++++.[-].", CellWidth::U8), ",+++++.[-].\n");
+        assert_eq!(minify(",+++++[-]>+", CellWidth::U8), ",+++++[-]>+\n");
+        assert_eq!(
+            minify("a,[>+++++++>+++b<<-]comment", CellWidth::U8),
+            ",[->+++++++>+++<<]\n"
+        );
+        assert_eq!(minify(",[->>-<<<+>]", CellWidth::U8), ",[-<+>>>-<<]\n");
+        assert_eq!(minify(",>[.]+[[-].]", CellWidth::U8), ",>+[[-].]\n");
+        assert_eq!(
+            minify("++[>++<-]>.+.", CellWidth::U8),
+            "++++.+.----->+++++\n"
+        );
+        assert_eq!(
+            minify("++[>++<-]>.+.,", CellWidth::U8),
+            "++++.+.----->+++++,\n"
+        );
+        assert_eq!(minify(",[--->+<]", CellWidth::U32), ",[--->+<]\n");
+        // Loops run a known number of times can give values that take billions of `+` to write.
+        assert_eq!(minify(",>-[<++->+++]<.", CellWidth::U32), ",>-[---<->]<.\n");
+        assert!(minify("+[+++>+<]>.", CellWidth::U32).len() < 100_000);
+        assert_round_trips("+[+++>+<]>.", CellWidth::U32);
+        assert_eq!(
+            minify(",>,>>,[-<<[->+<<+>]>[-<+>]>]", CellWidth::U8),
+            ",>,>>,[-<<[->+<<+>]>[-<+>]>]\n"
+        );
+    }
+
+    #[test]
+    fn trims_end() {
+        let trim = |code: &str, settings: &Settings| {
+            let instructions = Compiler::new(lex(code))
+                .with_tape(settings.tape)
+                .compile()
+                .unwrap();
+            to_trimmed_brainfuck(&instructions, settings)
+        };
+        let settings = Settings::default();
+        assert_eq!(trim(",+++++[-]>+", &settings), ",\n");
+        assert_eq!(trim("++[>++<-]>.+.", &settings), "++++.+.\n");
+        // Only what is printed is written, not every cell left at the end.
+        assert_eq!(
+            trim("++++++++[>++++++++<-]>+.+.+.", &settings),
+            ">++++++++[-<++++++++>]<+.+.+.\n"
+        );
+        // Unless leaving them out could stop the program failing.
+        let settings = Settings {
+            tape: TapePolicy::Fixed(1),
+            ..Settings::default()
+        };
+        assert_eq!(trim(",>+", &settings), ",>+\n");
+
+        // Compiling gives the same instructions, up to the cells changed at the end.
+        let compile = |code: &str| {
+            let mut instructions = Compiler::new(lex(code)).compile().unwrap();
+            let stop = instructions.pop();
+            while instructions.last().is_some_and(is_tail) {
+                instructions.pop();
+            }
+            instructions.extend(stop);
+            instructions
+        };
+        for code in [
+            include_str!("../samples/Bitwidth.bf"),
+            include_str!("../samples/Squares.bf"),
+            include_str!("../samples/TicTacToe.bf"),
+            include_str!("../samples/Zyx.bf"),
+            "++[>++<-]>.+.,>+<-",
+        ] {
+            let trimmed = trim(code, &Settings::default());
+            assert_eq!(
+                compile(code),
+                compile(&trimmed),
+                "{code:?} trimmed to {trimmed:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn borrowed_cells_stay_on_the_tape() {
+        let compile = |code: &str, settings: &Settings| {
+            Compiler::new(lex(code))
+                .with_tape(settings.tape)
+                .with_tape_limit(settings.tape_limit)
+                .compile()
+                .unwrap()
+        };
+        let run = |code: &str, settings: &Settings| {
+            let mut output = Vec::new();
+            execute_with(&compile(code, settings), settings, &b""[..], &mut output)
+                .map(|_| output)
+                .map_err(|error| error.to_string())
+        };
+        for (tape, tape_limit) in [
+            (TapePolicy::Grow, Some(1)),
+            (TapePolicy::Bidirectional, Some(2)),
+            (TapePolicy::Fixed(1), None),
+        ] {
+            let settings = Settings {
+                tape,
+                tape_limit,
+                ..Settings::default()
+            };
+            for code in [&"+".repeat(100), "++[>+++<-]>."] {
+                let code = format!("{code}.");
+                let minified = to_brainfuck(&compile(&code, &settings), &settings);
+                assert_eq!(
+                    run(&minified, &settings),
+                    run(&code, &settings),
+                    "{code:?} minified to {minified:?} with {settings:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn samples_round_trip() {
+        for code in [
+            include_str!("../samples/Bitwidth.bf"),
+            include_str!("../samples/BrainfuckInBrainfuck.bf"),
+            include_str!("../samples/Calculator.bf"),
+            include_str!("../samples/E.bf"),
+            include_str!("../samples/GoldenRatio.bf"),
+            include_str!("../samples/Mandelbrot.bf"),
+            include_str!("../samples/NumWarp.bf"),
+            include_str!("../samples/Squares.bf"),
+            include_str!("../samples/TicTacToe.bf"),
+            include_str!("../samples/TowersOfHanoi.bf"),
+            include_str!("../samples/Zyx.bf"),
+        ] {
+            for cell_width in [CellWidth::U8, CellWidth::U16] {
+                assert_round_trips(code, cell_width);
+            }
+        }
+    }
+
+    #[test]
+    fn random_programs_round_trip() {
+        let mut seed = 0x2545_f491_4f6c_dd1d;
+        for _ in 0..5000 {
+            let code = random_program(&mut seed, 0);
+            for cell_width in [CellWidth::U8, CellWidth::U16, CellWidth::U32] {
+                assert_round_trips(&code, cell_width);
+            }
+        }
+    }
+}
@@ -1,12 +1,30 @@
+use core::fmt::Write;
 use core::iter;
 
-use crate::MEMORY_RESIZE_AMOUNT;
-use crate::{compile::Instruction, INITIAL_MEMORY_CAPACITY};
+use crate::settings::{CellWidth, EofBehavior, Settings, TapePolicy};
+use crate::{compile::Instruction, MEMORY_RESIZE_AMOUNT};
+
+/// Writes bytes as the inside of a C string literal, in octal where they are not printable
+/// or could start a trigraph.
+fn escape(bytes: &[u8]) -> String {
+    let mut escaped = String::with_capacity(bytes.len());
+    for &byte in bytes {
+        match byte {
+            b'"' | b'\\' | b'?' => {
+                escaped.push('\\');
+                escaped.push(char::from(byte));
+            }
+            b' '..=b'~' => escaped.push(char::from(byte)),
+            _ => write!(escaped, "\\{byte:03o}").unwrap(),
+        }
+    }
+    escaped
+}
 
 /// Compiles instructions into C
 #[allow(clippy::too_many_lines)]
 #[must_use]
-pub fn to_c(instructions: &[Instruction]) -> String {
+pub fn to_c(instructions: &[Instruction], settings: &Settings) -> String {
     let mut code = String::with_capacity(2048);
 
     let mut indent_level = 0;
@@ -26,73 +44,261 @@ pub fn to_c(instructions: &[Instruction]) -> String {
             push_str!($text);
         };
     }
-    macro_rules! forward {
-        ($offset:expr) => {
-            push_str!("point_right(");
+    macro_rules! call {
+        ($function:expr, $offset:expr) => {
+            push_str!($function);
+            code.push('(');
             push_str!(&$offset.to_string());
             push_str!(");\n");
         };
     }
 
+    // Cell `offset` cells from the pointer, found before `memory` is read since finding it may grow the tape.
+    let cell = |offset: i32| {
+        if offset == 0 {
+            "memory[pointer]".to_string()
+        } else {
+            format!("*at({offset})")
+        }
+    };
+
+    push_str!("#include <stddef.h>\n");
     push_str!("#include <stdio.h>\n");
     push_str!("#include <stdint.h>\n");
     push_str!("#include <stdlib.h>\n");
     push_str!("#include <string.h>\n");
-    push_str!("uint8_t* memory = NULL;\n");
-    push_str!("int pointer = 0;\n");
-    push_str!("int cell = 0;\n");
-    push_str!("int memory_size = ");
-    push_str!(&INITIAL_MEMORY_CAPACITY.to_string());
+    push_str!("typedef ");
+    push_str!(match settings.cell_width {
+        CellWidth::U8 => "uint8_t",
+        CellWidth::U16 => "uint16_t",
+        CellWidth::U32 => "uint32_t",
+    });
+    push_str!(" cell_t;\n");
+    push_str!("cell_t* memory = NULL;\n");
+    push_str!("size_t pointer = 0;\n");
+    push_str!("cell_t cell = 0;\n");
+    push_str!("size_t memory_size = ");
+    push_str!(&settings.initial_tape_size().to_string());
     push_str!(";\n");
     push_str!(
-        "void grow_memory(size_t new_size) {
-\tmemory = (uint8_t*)realloc(memory, new_size * sizeof(uint8_t));
+        "void fail(const char* message) {
+\tfflush(stdout);
+\tfprintf(stderr, \"%s\\n\", message);
+\texit(1);
+}\n"
+    );
+
+    let resize_amount = MEMORY_RESIZE_AMOUNT.to_string();
+    let limit = settings.tape_limit.map(|limit| limit.to_string());
+    match settings.tape {
+        TapePolicy::Grow | TapePolicy::Bidirectional => {
+            push_str!(
+                "void grow_memory(size_t new_size) {
+\tmemory = (cell_t*)realloc(memory, new_size * sizeof(cell_t));
 \tif (memory == NULL) {
-\t\texit(1);
+\t\tfail(\"out of memory\");
 \t}
-\tmemset(memory + memory_size, 0, (new_size - memory_size) * sizeof(uint8_t));
+\tmemset(memory + memory_size, 0, (new_size - memory_size) * sizeof(cell_t));
 \tmemory_size = new_size;
+}\n"
+            );
+            push_str!(
+                "static inline size_t right(size_t offset) {
+\tsize_t index = pointer + offset;
+\tif (index >= memory_size) {\n"
+            );
+            if let Some(limit) = &limit {
+                push_str!("\t\tif (index >= ");
+                push_str!(limit);
+                push_str!(") {\n\t\t\tfail(\"tape limit exceeded\");\n\t\t}\n");
+                push_str!("\t\tgrow_memory(index + ");
+                push_str!(&resize_amount);
+                push_str!(" < ");
+                push_str!(limit);
+                push_str!(" ? index + ");
+                push_str!(&resize_amount);
+                push_str!(" : ");
+                push_str!(limit);
+            } else {
+                push_str!("\t\tgrow_memory(index + ");
+                push_str!(&resize_amount);
+            }
+            push_str!(");\n\t}\n\treturn index;\n}\n");
+        }
+        TapePolicy::Wrap(_) => {
+            push_str!(
+                "static inline size_t right(size_t offset) {
+\treturn (pointer + offset % memory_size) % memory_size;
+}\n"
+            );
+        }
+        TapePolicy::Fixed(_) => {
+            push_str!(
+                "static inline size_t right(size_t offset) {
+\tif (offset >= memory_size - pointer) {
+\t\tfail(\"pointer moved right of the last cell\");
+\t}
+\treturn pointer + offset;
+}\n"
+            );
+        }
+    }
+    match settings.tape {
+        TapePolicy::Grow | TapePolicy::Fixed(_) => {
+            push_str!(
+                "static inline size_t left(size_t offset) {
+\tif (offset > pointer) {
+\t\tfail(\"pointer moved left of the first cell\");
+\t}
+\treturn pointer - offset;
+}\n"
+            );
+        }
+        TapePolicy::Wrap(_) => {
+            push_str!(
+                "static inline size_t left(size_t offset) {
+\treturn (pointer + memory_size - offset % memory_size) % memory_size;
+}\n"
+            );
+        }
+        TapePolicy::Bidirectional => {
+            push_str!(
+                "void grow_front(size_t amount) {
+\tsize_t old_size = memory_size;
+\tgrow_memory(memory_size + amount);
+\tmemmove(memory + amount, memory, old_size * sizeof(cell_t));
+\tmemset(memory, 0, amount * sizeof(cell_t));
+\tpointer += amount;
+}\n"
+            );
+            push_str!(
+                "static inline size_t left(size_t offset) {
+\tif (offset > pointer) {\n"
+            );
+            if let Some(limit) = &limit {
+                push_str!("\t\tsize_t room = ");
+                push_str!(limit);
+                push_str!(
+                    " - memory_size;
+\t\tsize_t needed = offset - pointer;
+\t\tif (needed > room) {
+\t\t\tfail(\"tape limit exceeded\");
+\t\t}
+\t\tgrow_front(needed + "
+                );
+                push_str!(&resize_amount);
+                push_str!(" < room ? needed + ");
+                push_str!(&resize_amount);
+                push_str!(" : room");
+            } else {
+                push_str!("\t\tgrow_front(offset - pointer + ");
+                push_str!(&resize_amount);
+            }
+            push_str!(");\n\t}\n\treturn pointer - offset;\n}\n");
+        }
+    }
+    push_str!(
+        "static inline void point_right(size_t offset) {
+\tpointer = right(offset);
+}\n"
+    );
+    push_str!(
+        "static inline void point_left(size_t offset) {
+\tpointer = left(offset);
+}\n"
+    );
+
+    // Scans search the cells already on the tape, then step off its end to grow or wrap it.
+    push_str!(
+        "static inline void scan_right(size_t stride, cell_t amount) {
+\twhile (memory[pointer] != 0) {
+\t\tsize_t last = pointer + (memory_size - 1 - pointer) / stride * stride;\n"
+    );
+    if settings.cell_width == CellWidth::U8 {
+        push_str!(
+            "\t\tif (stride == 1 && amount == 0) {
+\t\t\tcell_t* zero = memchr(memory + pointer, 0, memory_size - pointer);
+\t\t\tpointer = zero != NULL ? (size_t)(zero - memory) : last;
+\t\t}\n"
+        );
+    }
+    push_str!(
+        "\t\twhile (pointer != last && memory[pointer] != 0) {
+\t\t\tmemory[pointer] += amount;
+\t\t\tpointer += stride;
+\t\t}
+\t\tif (memory[pointer] != 0) {
+\t\t\tmemory[pointer] += amount;
+\t\t\tpoint_right(stride);
+\t\t}
+\t}
+}\n"
+    );
+    push_str!(
+        "static inline void scan_left(size_t stride, cell_t amount) {
+\twhile (memory[pointer] != 0) {
+\t\tsize_t last = pointer % stride;
+\t\twhile (pointer != last && memory[pointer] != 0) {
+\t\t\tmemory[pointer] += amount;
+\t\t\tpointer -= stride;
+\t\t}
+\t\tif (memory[pointer] != 0) {
+\t\t\tmemory[pointer] += amount;
+\t\t\tpoint_left(stride);
+\t\t}
+\t}
 }\n"
     );
 
     push_str!(
-        "inline void point_right(size_t offset) {
-\tpointer += offset;
-\tif (pointer >= memory_size) {
-\t\tgrow_memory(pointer + "
+        "static inline cell_t* at(ptrdiff_t offset) {
+\tsize_t index = offset < 0 ? left(-offset) : right(offset);
+\treturn memory + index;
+}\n"
     );
-    push_str!(&MEMORY_RESIZE_AMOUNT.to_string());
 
-    push_str!(");\n\t}\n}\n");
+    push_str!(
+        "void input(void) {
+\tint character = getchar();
+"
+    );
+    push_str!(match settings.eof {
+        EofBehavior::Unchanged =>
+            "\tif (character != EOF) {\n\t\tmemory[pointer] = character;\n\t}\n",
+        EofBehavior::Zero => "\tmemory[pointer] = character == EOF ? 0 : character;\n",
+        EofBehavior::Max => "\tmemory[pointer] = character == EOF ? (cell_t)-1 : character;\n",
+    });
+    push_str!("}\n");
 
     push_str!("int main() {\n");
 
     push_str!(
-        "\tmemory = (uint8_t*)calloc(memory_size, sizeof(uint8_t));
+        "\tmemory = (cell_t*)calloc(memory_size, sizeof(cell_t));
 \tif (memory == NULL) {
 \t\texit(1);
 \t}\n"
     );
     indent_level += 1;
     let mut instruction_index = 0;
-    loop {
-        match unsafe { instructions.get_unchecked(instruction_index) } {
+    while let Some(instruction) = instructions.get(instruction_index) {
+        match instruction {
             Instruction::Forward(offset) => {
                 indent!();
-                forward!(offset);
+                call!("point_right", offset);
             }
             Instruction::Backward(offset) => {
-                indented_push!("pointer -= ");
-                push_str!(&offset.to_string());
-                push_str!(";\n");
+                indent!();
+                call!("point_left", offset);
             }
-            Instruction::Increment(increment) => {
-                indented_push!("memory[pointer] += ");
-                push_str!(&increment.to_string());
+            Instruction::Increment { offset, amount } => {
+                indented_push!(&cell(*offset));
+                push_str!(" += ");
+                push_str!(&amount.to_string());
                 push_str!(";\n");
             }
-            Instruction::SetCell(value) => {
-                indented_push!("memory[pointer] = ");
+            Instruction::SetCell { offset, value } => {
+                indented_push!(&cell(*offset));
+                push_str!(" = ");
                 push_str!(&value.to_string());
                 push_str!(";\n");
             }
@@ -125,24 +331,14 @@ pub fn to_c(instructions: &[Instruction]) -> String {
                 indented_push!("if (cell != 0) {\n");
                 indent_level += 1;
 
-                indented_push!("if (pointer + ");
-                push_str!(&offset.to_string());
-                push_str!(" >= memory_size) {\n");
-
-                indent_level += 1;
-                indented_push!("grow_memory(pointer + ");
-                push_str!(&(*offset as usize + MEMORY_RESIZE_AMOUNT).to_string());
-                push_str!(");\n");
-                indent_level -= 1;
+                indented_push!("size_t target = ");
+                call!("right", offset);
 
-                indented_push!("}\n");
-
-                indented_push!("memory[pointer + ");
-                push_str!(&offset.to_string());
-                push_str!("] += cell");
+                indented_push!("memory[target] += cell");
                 if *multiplier != 1 {
                     push_str!(" * ");
                     push_str!(&multiplier.to_string());
+                    code.push('u');
                 }
                 push_str!(";\n");
 
@@ -155,44 +351,86 @@ pub fn to_c(instructions: &[Instruction]) -> String {
                 indented_push!("if (cell != 0) {\n");
                 indent_level += 1;
 
-                indented_push!("memory[pointer - ");
-                push_str!(&offset.to_string());
-                push_str!("] += cell");
+                indented_push!("size_t target = ");
+                call!("left", offset);
+
+                indented_push!("memory[target] += cell");
                 if *multiplier != 1 {
                     push_str!(" * ");
                     push_str!(&multiplier.to_string());
+                    code.push('u');
                 }
                 push_str!(";\n");
 
                 indent_level -= 1;
                 indented_push!("}\n");
             }
-            Instruction::ForwardLoop(offset) => {
-                indented_push!("while (memory[pointer] != 0) {\n");
+            Instruction::MultiplyProduct {
+                offset,
+                factor,
+                multiplier,
+            } => {
+                indented_push!("cell = memory[pointer];\n");
+
+                indented_push!("if (cell != 0) {\n");
                 indent_level += 1;
 
-                indent!();
-                forward!(offset);
+                indented_push!("cell_t factor = ");
+                push_str!(&cell(*factor));
+                push_str!(";\n");
+
+                // Unsigned, so the product wraps rather than overflowing an `int`.
+                indented_push!(&cell(*offset));
+                push_str!(" += (uint32_t)cell * factor * ");
+                push_str!(&multiplier.to_string());
+                push_str!("u;\n");
 
                 indent_level -= 1;
                 indented_push!("}\n");
             }
+            Instruction::ForwardLoop(offset) => {
+                indented_push!("scan_right(");
+                push_str!(&offset.to_string());
+                push_str!(", 0);\n");
+            }
             Instruction::BackwardLoop(offset) => {
-                indented_push!("while (memory[pointer] != 0) {\n");
-                indent_level += 1;
-
-                indented_push!("pointer -= ");
+                indented_push!("scan_left(");
                 push_str!(&offset.to_string());
-                push_str!(";\n");
-
-                indent_level -= 1;
-                indented_push!("}\n");
+                push_str!(", 0);\n");
+            }
+            Instruction::IncrementForwardLoop(offset, amount) => {
+                indented_push!("scan_right(");
+                push_str!(&offset.to_string());
+                push_str!(", ");
+                push_str!(&amount.to_string());
+                push_str!(");\n");
+            }
+            Instruction::IncrementBackwardLoop(offset, amount) => {
+                indented_push!("scan_left(");
+                push_str!(&offset.to_string());
+                push_str!(", ");
+                push_str!(&amount.to_string());
+                push_str!(");\n");
+            }
+            Instruction::Output { offset } => {
+                indented_push!("putchar(");
+                push_str!(&cell(*offset));
+                push_str!(");\n");
+            }
+            Instruction::Print { value, .. } => {
+                indented_push!("putchar(");
+                push_str!(&value.to_string());
+                push_str!(");\n");
             }
-            Instruction::Output => {
-                indented_push!("putchar(memory[pointer]);\n");
+            Instruction::OutputBytes(bytes) => {
+                indented_push!("fwrite(\"");
+                push_str!(&escape(bytes));
+                push_str!("\", 1, ");
+                push_str!(&bytes.len().to_string());
+                push_str!(", stdout);\n");
             }
             Instruction::Input => {
-                indented_push!("memory[pointer] = getchar();\n");
+                indented_push!("input();\n");
             }
             Instruction::Stop => break,
         }
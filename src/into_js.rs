@@ -0,0 +1,285 @@
+use core::fmt::Write;
+use core::{iter, mem};
+
+use crate::settings::{CellWidth, EofBehavior, Settings, TapePolicy};
+use crate::{compile::Instruction, MEMORY_RESIZE_AMOUNT};
+
+/// Compiles instructions into an ES module exporting `run(inputBytes)`,
+/// which returns the output as a `Uint8Array`.
+///
+/// When the program stops early, `run` throws an `Error` with the output so far in its `output` property.
+#[allow(clippy::too_many_lines)]
+#[must_use]
+pub fn to_js(instructions: &[Instruction], settings: &Settings) -> String {
+    let mut code = String::with_capacity(2048);
+
+    let mut indent_level = 0;
+    macro_rules! emit {
+        ($($argument:tt)*) => {
+            code.extend(iter::repeat('\t').take(indent_level));
+            writeln!(code, $($argument)*).unwrap();
+        };
+    }
+
+    let array = match settings.cell_width {
+        CellWidth::U8 => "Uint8Array",
+        CellWidth::U16 => "Uint16Array",
+        CellWidth::U32 => "Uint32Array",
+    };
+    // Keeps a sum in range of the cell width.
+    let wrap = |sum: &str| match settings.cell_width {
+        CellWidth::U32 => format!("({sum}) >>> 0"),
+        cell_width => format!("({sum}) & {}", cell_width.max()),
+    };
+
+    // Cells away from the pointer are found first, as growing the tape replaces `memory`.
+    let at = |offset: i32| {
+        if offset.is_positive() {
+            format!("right({offset})")
+        } else {
+            format!("left({})", offset.unsigned_abs())
+        }
+    };
+    let index = |offset: i32| if offset == 0 { "pointer" } else { "target" };
+
+    emit!("export function run(inputBytes = new Uint8Array()) {{");
+    indent_level += 1;
+    emit!(
+        "let memory = new {array}({});",
+        settings.initial_tape_size()
+    );
+    emit!("let pointer = 0;");
+    emit!("let cell = 0;");
+    emit!("let target = 0;");
+    emit!("let inputIndex = 0;");
+    emit!("const output = [];");
+
+    emit!("function fail(message) {{");
+    emit!("\tconst error = new Error(message);");
+    emit!("\terror.output = Uint8Array.from(output);");
+    emit!("\tthrow error;");
+    emit!("}}");
+
+    let limit = settings.tape_limit;
+    match settings.tape {
+        TapePolicy::Grow | TapePolicy::Bidirectional => {
+            emit!("function growMemory(newSize, at) {{");
+            emit!("\tconst grown = new {array}(newSize);");
+            emit!("\tgrown.set(memory, at);");
+            emit!("\tmemory = grown;");
+            emit!("}}");
+            emit!("function right(offset) {{");
+            emit!("\tconst index = pointer + offset;");
+            emit!("\tif (index >= memory.length) {{");
+            if let Some(limit) = limit {
+                emit!("\t\tif (index >= {limit}) {{");
+                emit!("\t\t\tfail(\"tape limit exceeded\");");
+                emit!("\t\t}}");
+                emit!("\t\tgrowMemory(Math.min(index + {MEMORY_RESIZE_AMOUNT}, {limit}), 0);");
+            } else {
+                emit!("\t\tgrowMemory(index + {MEMORY_RESIZE_AMOUNT}, 0);");
+            }
+            emit!("\t}}");
+            emit!("\treturn index;");
+            emit!("}}");
+        }
+        TapePolicy::Wrap(_) => {
+            emit!("function right(offset) {{");
+            emit!("\treturn (pointer + offset % memory.length) % memory.length;");
+            emit!("}}");
+        }
+        TapePolicy::Fixed(_) => {
+            emit!("function right(offset) {{");
+            emit!("\tif (offset >= memory.length - pointer) {{");
+            emit!("\t\tfail(\"pointer moved right of the last cell\");");
+            emit!("\t}}");
+            emit!("\treturn pointer + offset;");
+            emit!("}}");
+        }
+    }
+    match settings.tape {
+        TapePolicy::Grow | TapePolicy::Fixed(_) => {
+            emit!("function left(offset) {{");
+            emit!("\tif (offset > pointer) {{");
+            emit!("\t\tfail(\"pointer moved left of the first cell\");");
+            emit!("\t}}");
+            emit!("\treturn pointer - offset;");
+            emit!("}}");
+        }
+        TapePolicy::Wrap(_) => {
+            emit!("function left(offset) {{");
+            emit!("\treturn (pointer + memory.length - offset % memory.length) % memory.length;");
+            emit!("}}");
+        }
+        TapePolicy::Bidirectional => {
+            emit!("function left(offset) {{");
+            emit!("\tif (offset > pointer) {{");
+            emit!("\t\tconst needed = offset - pointer;");
+            if let Some(limit) = limit {
+                emit!("\t\tconst room = {limit} - memory.length;");
+                emit!("\t\tif (needed > room) {{");
+                emit!("\t\t\tfail(\"tape limit exceeded\");");
+                emit!("\t\t}}");
+                emit!("\t\tconst amount = Math.min(needed + {MEMORY_RESIZE_AMOUNT}, room);");
+            } else {
+                emit!("\t\tconst amount = needed + {MEMORY_RESIZE_AMOUNT};");
+            }
+            emit!("\t\tgrowMemory(memory.length + amount, amount);");
+            emit!("\t\tpointer += amount;");
+            emit!("\t}}");
+            emit!("\treturn pointer - offset;");
+            emit!("}}");
+        }
+    }
+
+    emit!("function input() {{");
+    match settings.eof {
+        EofBehavior::Unchanged => {
+            emit!("\tif (inputIndex < inputBytes.length) {{");
+            emit!("\t\tmemory[pointer] = inputBytes[inputIndex++];");
+            emit!("\t}}");
+        }
+        EofBehavior::Zero => {
+            emit!("\tmemory[pointer] = inputIndex < inputBytes.length ? inputBytes[inputIndex++] : 0;");
+        }
+        EofBehavior::Max => {
+            emit!(
+                "\tmemory[pointer] = inputIndex < inputBytes.length ? inputBytes[inputIndex++] : {};",
+                settings.cell_width.max()
+            );
+        }
+    }
+    emit!("}}");
+
+    // Each loop becomes a function, as engines do not optimise very large functions.
+    let functions_at = code.len();
+    let mut functions = String::new();
+    let mut outer = Vec::new();
+    let mut instruction_index = 0;
+    while let Some(instruction) = instructions.get(instruction_index) {
+        match instruction {
+            Instruction::Forward(offset) => {
+                emit!("pointer = right({offset});");
+            }
+            Instruction::Backward(offset) => {
+                emit!("pointer = left({offset});");
+            }
+            Instruction::Increment { offset, amount } => {
+                if *offset != 0 {
+                    emit!("target = {};", at(*offset));
+                }
+                emit!("memory[{}] += {amount};", index(*offset));
+            }
+            Instruction::SetCell { offset, value } => {
+                if *offset != 0 {
+                    emit!("target = {};", at(*offset));
+                }
+                emit!("memory[{}] = {value};", index(*offset));
+            }
+            Instruction::LoopStart(_loop_end) => {
+                emit!("loop{instruction_index}();");
+                outer.push((mem::take(&mut code), indent_level));
+                indent_level = 1;
+                emit!("function loop{instruction_index}() {{");
+                emit!("\twhile (memory[pointer] !== 0) {{");
+                indent_level += 2;
+            }
+            Instruction::LoopEnd(_loop_start) => {
+                indent_level = 1;
+                emit!("\t}}");
+                emit!("}}");
+                if let Some((outer_code, outer_indent_level)) = outer.pop() {
+                    functions.push_str(&mem::replace(&mut code, outer_code));
+                    indent_level = outer_indent_level;
+                }
+            }
+            Instruction::IncrementLoop(increment) => {
+                emit!("cell = memory[pointer];");
+                emit!("while (cell !== 0) {{");
+                emit!("\tcell = {};", wrap(&format!("cell + {increment}")));
+                emit!("}}");
+                emit!("memory[pointer] = cell;");
+            }
+            Instruction::MultiplyForward(offset, multiplier)
+            | Instruction::MultiplyBackward(offset, multiplier) => {
+                let direction = if matches!(
+                    instructions[instruction_index],
+                    Instruction::MultiplyForward(..)
+                ) {
+                    "right"
+                } else {
+                    "left"
+                };
+                emit!("cell = memory[pointer];");
+                emit!("if (cell !== 0) {{");
+                // Find the target first, since growing the tape replaces `memory`.
+                emit!("\tconst target = {direction}({offset});");
+                if *multiplier == 1 {
+                    emit!("\tmemory[target] += cell;");
+                } else {
+                    // `Math.imul` keeps the low bits of products too wide for a double.
+                    emit!("\tmemory[target] += Math.imul(cell, {multiplier});");
+                }
+                emit!("}}");
+            }
+            Instruction::MultiplyProduct {
+                offset,
+                factor,
+                multiplier,
+            } => {
+                emit!("cell = memory[pointer];");
+                emit!("if (cell !== 0) {{");
+                emit!("\tconst factor = memory[{}];", at(*factor));
+                emit!("\tconst target = {};", at(*offset));
+                emit!("\tmemory[target] += Math.imul(Math.imul(cell, factor), {multiplier});");
+                emit!("}}");
+            }
+            Instruction::ForwardLoop(offset) => {
+                emit!("while (memory[pointer] !== 0) {{");
+                emit!("\tpointer = right({offset});");
+                emit!("}}");
+            }
+            Instruction::BackwardLoop(offset) => {
+                emit!("while (memory[pointer] !== 0) {{");
+                emit!("\tpointer = left({offset});");
+                emit!("}}");
+            }
+            Instruction::IncrementForwardLoop(offset, amount) => {
+                emit!("while (memory[pointer] !== 0) {{");
+                emit!("\tmemory[pointer] += {amount};");
+                emit!("\tpointer = right({offset});");
+                emit!("}}");
+            }
+            Instruction::IncrementBackwardLoop(offset, amount) => {
+                emit!("while (memory[pointer] !== 0) {{");
+                emit!("\tmemory[pointer] += {amount};");
+                emit!("\tpointer = left({offset});");
+                emit!("}}");
+            }
+            Instruction::Output { offset } => {
+                if *offset != 0 {
+                    emit!("target = {};", at(*offset));
+                }
+                emit!("output.push(memory[{}] & 255);", index(*offset));
+            }
+            Instruction::Print { value, .. } => {
+                emit!("output.push({value});");
+            }
+            Instruction::OutputBytes(bytes) => {
+                // Pushed one at a time, as spreading too many arguments overflows the stack.
+                emit!("for (const byte of {bytes:?}) output.push(byte);");
+            }
+            Instruction::Input => {
+                emit!("input();");
+            }
+            Instruction::Stop => break,
+        }
+        instruction_index += 1;
+    }
+    emit!("return Uint8Array.from(output);");
+    indent_level -= 1;
+    emit!("}}");
+    code.insert_str(functions_at, &functions);
+
+    code
+}
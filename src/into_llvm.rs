@@ -0,0 +1,597 @@
+use core::fmt::Write;
+
+use crate::compile::Instruction;
+use crate::settings::{CellWidth, EofBehavior, Settings, TapePolicy};
+use crate::MEMORY_RESIZE_AMOUNT;
+
+macro_rules! emit {
+    ($code:expr, $($argument:tt)*) => {
+        $code.push('\t');
+        writeln!($code, $($argument)*).unwrap();
+    };
+}
+
+fn label(code: &mut String, name: &str) {
+    code.push_str(name);
+    code.push_str(":\n");
+}
+
+/// Messages the program can stop with, by the name of their constant.
+const MESSAGES: [(&str, &str); 4] = [
+    ("underflow", "pointer moved left of the first cell"),
+    ("overflow", "pointer moved right of the last cell"),
+    ("tape_limit", "tape limit exceeded"),
+    ("out_of_memory", "out of memory"),
+];
+
+/// Emits a call to `fail` with the message named `name`.
+fn emit_fail(code: &mut String, name: &str) {
+    let (_, message) = MESSAGES.iter().find(|(other, _)| *other == name).unwrap();
+    emit!(
+        code,
+        "call void @fail(ptr @{name}_message, i64 {})",
+        message.len() + 1
+    );
+    emit!(code, "unreachable");
+}
+
+/// Emits `grow_memory` and the functions that move the pointer.
+#[allow(clippy::too_many_lines)]
+fn emit_tape(code: &mut String, settings: &Settings, cell: &str, bytes: usize) {
+    let resize_amount = MEMORY_RESIZE_AMOUNT;
+    if matches!(settings.tape, TapePolicy::Grow | TapePolicy::Bidirectional) {
+        code.push_str("define internal void @grow_memory(i64 %new_size) {\n");
+        label(code, "entry");
+        emit!(code, "%memory = load ptr, ptr @memory");
+        emit!(code, "%bytes = mul i64 %new_size, {bytes}");
+        emit!(code, "%grown = call ptr @realloc(ptr %memory, i64 %bytes)");
+        emit!(code, "%failed = icmp eq ptr %grown, null");
+        emit!(code, "br i1 %failed, label %out_of_memory, label %clear");
+        label(code, "out_of_memory");
+        emit_fail(code, "out_of_memory");
+        label(code, "clear");
+        emit!(code, "store ptr %grown, ptr @memory");
+        emit!(code, "%old_size = load i64, ptr @memory_size");
+        emit!(code, "%old_bytes = mul i64 %old_size, {bytes}");
+        emit!(
+            code,
+            "%start = getelementptr inbounds i8, ptr %grown, i64 %old_bytes"
+        );
+        emit!(code, "%new_bytes = sub i64 %bytes, %old_bytes");
+        emit!(code, "call ptr @memset(ptr %start, i32 0, i64 %new_bytes)");
+        emit!(code, "store i64 %new_size, ptr @memory_size");
+        emit!(code, "ret void");
+        code.push_str("}\n");
+    }
+
+    code.push_str("define internal i64 @right(i64 %offset) {\n");
+    label(code, "entry");
+    emit!(code, "%pointer = load i64, ptr @pointer");
+    emit!(code, "%size = load i64, ptr @memory_size");
+    match settings.tape {
+        TapePolicy::Grow | TapePolicy::Bidirectional => {
+            emit!(code, "%index = add i64 %pointer, %offset");
+            emit!(code, "%inside = icmp ult i64 %index, %size");
+            emit!(code, "br i1 %inside, label %done, label %grow");
+            label(code, "grow");
+            emit!(code, "%wanted = add i64 %index, {resize_amount}");
+            if let Some(limit) = settings.tape_limit {
+                emit!(code, "%over = icmp uge i64 %index, {limit}");
+                emit!(code, "br i1 %over, label %limit, label %resize");
+                label(code, "limit");
+                emit_fail(code, "tape_limit");
+                label(code, "resize");
+                emit!(code, "%small = icmp ult i64 %wanted, {limit}");
+                emit!(
+                    code,
+                    "%new_size = select i1 %small, i64 %wanted, i64 {limit}"
+                );
+                emit!(code, "call void @grow_memory(i64 %new_size)");
+            } else {
+                emit!(code, "call void @grow_memory(i64 %wanted)");
+            }
+            emit!(code, "br label %done");
+            label(code, "done");
+            emit!(code, "ret i64 %index");
+        }
+        TapePolicy::Wrap(_) => {
+            emit!(code, "%wrapped = urem i64 %offset, %size");
+            emit!(code, "%sum = add i64 %pointer, %wrapped");
+            emit!(code, "%index = urem i64 %sum, %size");
+            emit!(code, "ret i64 %index");
+        }
+        TapePolicy::Fixed(_) => {
+            emit!(code, "%room = sub i64 %size, %pointer");
+            emit!(code, "%outside = icmp uge i64 %offset, %room");
+            emit!(code, "br i1 %outside, label %overflow, label %done");
+            label(code, "overflow");
+            emit_fail(code, "overflow");
+            label(code, "done");
+            emit!(code, "%index = add i64 %pointer, %offset");
+            emit!(code, "ret i64 %index");
+        }
+    }
+    code.push_str("}\n");
+
+    if settings.tape == TapePolicy::Bidirectional {
+        code.push_str("define internal void @grow_front(i64 %amount) {\n");
+        label(code, "entry");
+        emit!(code, "%old_size = load i64, ptr @memory_size");
+        emit!(code, "%new_size = add i64 %old_size, %amount");
+        emit!(code, "call void @grow_memory(i64 %new_size)");
+        emit!(code, "%memory = load ptr, ptr @memory");
+        emit!(
+            code,
+            "%start = getelementptr inbounds {cell}, ptr %memory, i64 %amount"
+        );
+        emit!(code, "%old_bytes = mul i64 %old_size, {bytes}");
+        emit!(
+            code,
+            "call ptr @memmove(ptr %start, ptr %memory, i64 %old_bytes)"
+        );
+        emit!(code, "%amount_bytes = mul i64 %amount, {bytes}");
+        emit!(
+            code,
+            "call ptr @memset(ptr %memory, i32 0, i64 %amount_bytes)"
+        );
+        emit!(code, "%pointer = load i64, ptr @pointer");
+        emit!(code, "%moved = add i64 %pointer, %amount");
+        emit!(code, "store i64 %moved, ptr @pointer");
+        emit!(code, "ret void");
+        code.push_str("}\n");
+    }
+
+    code.push_str("define internal i64 @left(i64 %offset) {\n");
+    label(code, "entry");
+    emit!(code, "%pointer = load i64, ptr @pointer");
+    match settings.tape {
+        TapePolicy::Grow | TapePolicy::Fixed(_) => {
+            emit!(code, "%outside = icmp ugt i64 %offset, %pointer");
+            emit!(code, "br i1 %outside, label %underflow, label %done");
+            label(code, "underflow");
+            emit_fail(code, "underflow");
+            label(code, "done");
+            emit!(code, "%index = sub i64 %pointer, %offset");
+            emit!(code, "ret i64 %index");
+        }
+        TapePolicy::Wrap(_) => {
+            emit!(code, "%size = load i64, ptr @memory_size");
+            emit!(code, "%wrapped = urem i64 %offset, %size");
+            emit!(code, "%sum = add i64 %pointer, %size");
+            emit!(code, "%difference = sub i64 %sum, %wrapped");
+            emit!(code, "%index = urem i64 %difference, %size");
+            emit!(code, "ret i64 %index");
+        }
+        TapePolicy::Bidirectional => {
+            emit!(code, "%outside = icmp ugt i64 %offset, %pointer");
+            emit!(code, "br i1 %outside, label %grow, label %done");
+            label(code, "grow");
+            emit!(code, "%needed = sub i64 %offset, %pointer");
+            emit!(code, "%wanted = add i64 %needed, {resize_amount}");
+            if let Some(limit) = settings.tape_limit {
+                emit!(code, "%size = load i64, ptr @memory_size");
+                emit!(code, "%room = sub i64 {limit}, %size");
+                emit!(code, "%over = icmp ugt i64 %needed, %room");
+                emit!(code, "br i1 %over, label %limit, label %resize");
+                label(code, "limit");
+                emit_fail(code, "tape_limit");
+                label(code, "resize");
+                emit!(code, "%small = icmp ult i64 %wanted, %room");
+                emit!(code, "%amount = select i1 %small, i64 %wanted, i64 %room");
+                emit!(code, "call void @grow_front(i64 %amount)");
+            } else {
+                emit!(code, "call void @grow_front(i64 %wanted)");
+            }
+            emit!(code, "br label %done");
+            label(code, "done");
+            emit!(code, "%moved = load i64, ptr @pointer");
+            emit!(code, "%index = sub i64 %moved, %offset");
+            emit!(code, "ret i64 %index");
+        }
+    }
+    code.push_str("}\n");
+
+    for direction in ["right", "left"] {
+        writeln!(
+            code,
+            "define internal void @point_{direction}(i64 %offset) {{"
+        )
+        .unwrap();
+        label(code, "entry");
+        emit!(code, "%index = call i64 @{direction}(i64 %offset)");
+        emit!(code, "store i64 %index, ptr @pointer");
+        emit!(code, "ret void");
+        code.push_str("}\n");
+    }
+}
+
+/// Compiles instructions into LLVM IR, which runs with `lli` or builds with `llc`.
+///
+/// The IR uses opaque pointers, so LLVM 14 needs `-opaque-pointers`.
+#[allow(clippy::too_many_lines)]
+#[must_use]
+pub fn to_llvm(instructions: &[Instruction], settings: &Settings) -> String {
+    let mut code = String::with_capacity(4096);
+
+    let (cell, bytes) = match settings.cell_width {
+        CellWidth::U8 => ("i8", 1),
+        CellWidth::U16 => ("i16", 2),
+        CellWidth::U32 => ("i32", 4),
+    };
+    // Constants as the signed value of the same bits.
+    let immediate = |value: u32| match settings.cell_width {
+        CellWidth::U8 => i64::from(value as u8 as i8),
+        CellWidth::U16 => i64::from(value as u16 as i16),
+        CellWidth::U32 => i64::from(value as i32),
+    };
+
+    code.push_str("@memory = internal global ptr null\n");
+    code.push_str("@pointer = internal global i64 0\n");
+    writeln!(
+        code,
+        "@memory_size = internal global i64 {}",
+        settings.initial_tape_size()
+    )
+    .unwrap();
+    for (name, message) in MESSAGES {
+        writeln!(
+            code,
+            "@{name}_message = private unnamed_addr constant [{} x i8] c\"{message}\\0A\"",
+            message.len() + 1
+        )
+        .unwrap();
+    }
+    code.push_str(
+        "declare i32 @getchar()
+declare i32 @putchar(i32)
+declare ptr @calloc(i64, i64)
+declare ptr @realloc(ptr, i64)
+declare void @free(ptr)
+declare ptr @memset(ptr, i32, i64)
+declare ptr @memmove(ptr, ptr, i64)
+declare i32 @fflush(ptr)
+declare i64 @write(i32, ptr, i64)
+declare void @exit(i32) noreturn
+",
+    );
+
+    code.push_str("define internal void @fail(ptr %message, i64 %length) cold noreturn {\n");
+    label(&mut code, "entry");
+    emit!(code, "call i32 @fflush(ptr null)");
+    emit!(code, "call i64 @write(i32 2, ptr %message, i64 %length)");
+    emit!(code, "call void @exit(i32 1)");
+    emit!(code, "unreachable");
+    code.push_str("}\n");
+
+    emit_tape(&mut code, settings, cell, bytes);
+
+    code.push_str("define internal ptr @cell(i64 %index) {\n");
+    label(&mut code, "entry");
+    emit!(code, "%memory = load ptr, ptr @memory");
+    emit!(
+        code,
+        "%address = getelementptr inbounds {cell}, ptr %memory, i64 %index"
+    );
+    emit!(code, "ret ptr %address");
+    code.push_str("}\n");
+
+    code.push_str("define internal ptr @current() {\n");
+    label(&mut code, "entry");
+    emit!(code, "%pointer = load i64, ptr @pointer");
+    emit!(code, "%address = call ptr @cell(i64 %pointer)");
+    emit!(code, "ret ptr %address");
+    code.push_str("}\n");
+
+    code.push_str("define internal void @put_bytes(ptr %bytes, i64 %length) {\n");
+    label(&mut code, "entry");
+    emit!(code, "br label %check");
+    label(&mut code, "check");
+    emit!(code, "%index = phi i64 [0, %entry], [%next, %body]");
+    emit!(code, "%more = icmp ult i64 %index, %length");
+    emit!(code, "br i1 %more, label %body, label %done");
+    label(&mut code, "body");
+    emit!(
+        code,
+        "%address = getelementptr inbounds i8, ptr %bytes, i64 %index"
+    );
+    emit!(code, "%byte = load i8, ptr %address");
+    emit!(code, "%character = zext i8 %byte to i32");
+    emit!(code, "call i32 @putchar(i32 %character)");
+    emit!(code, "%next = add i64 %index, 1");
+    emit!(code, "br label %check");
+    label(&mut code, "done");
+    emit!(code, "ret void");
+    code.push_str("}\n");
+
+    code.push_str("define internal void @input() {\n");
+    label(&mut code, "entry");
+    emit!(code, "%character = call i32 @getchar()");
+    emit!(code, "%address = call ptr @current()");
+    emit!(code, "%end = icmp slt i32 %character, 0");
+    let (character, done) = match settings.eof {
+        EofBehavior::Unchanged => {
+            emit!(code, "br i1 %end, label %done, label %store");
+            label(&mut code, "store");
+            ("%character", true)
+        }
+        EofBehavior::Zero => {
+            emit!(code, "%value = select i1 %end, i32 0, i32 %character");
+            ("%value", false)
+        }
+        // EOF is -1, which is already the largest value.
+        EofBehavior::Max => ("%character", false),
+    };
+    if settings.cell_width == CellWidth::U32 {
+        emit!(code, "store i32 {character}, ptr %address");
+    } else {
+        emit!(code, "%truncated = trunc i32 {character} to {cell}");
+        emit!(code, "store {cell} %truncated, ptr %address");
+    }
+    if done {
+        emit!(code, "br label %done");
+        label(&mut code, "done");
+    }
+    emit!(code, "ret void");
+    code.push_str("}\n");
+
+    code.push_str("define i32 @main() {\n");
+    label(&mut code, "entry");
+    emit!(code, "%cell = alloca {cell}");
+    emit!(
+        code,
+        "%memory = call ptr @calloc(i64 {}, i64 {bytes})",
+        settings.initial_tape_size()
+    );
+    emit!(code, "%failed = icmp eq ptr %memory, null");
+    emit!(code, "br i1 %failed, label %out_of_memory, label %start");
+    label(&mut code, "out_of_memory");
+    emit_fail(&mut code, "out_of_memory");
+    label(&mut code, "start");
+    emit!(code, "store ptr %memory, ptr @memory");
+
+    // Bytes of each `OutputBytes`, which become constants after `main`.
+    let mut output: Vec<&[u8]> = Vec::new();
+
+    // Names of values and blocks in `main`.
+    let mut names = 0;
+    let mut name = || {
+        names += 1;
+        names
+    };
+    // Puts the address of the cell `offset` cells from the pointer in a new value, giving its name.
+    macro_rules! address {
+        ($offset:expr) => {{
+            let offset: i32 = $offset;
+            let address = name();
+            if offset == 0 {
+                emit!(code, "%t{address} = call ptr @current()");
+            } else {
+                let index = name();
+                let direction = if offset.is_positive() {
+                    "right"
+                } else {
+                    "left"
+                };
+                emit!(
+                    code,
+                    "%t{index} = call i64 @{direction}(i64 {})",
+                    offset.unsigned_abs()
+                );
+                emit!(code, "%t{address} = call ptr @cell(i64 %t{index})");
+            }
+            address
+        }};
+    }
+    let mut instruction_index = 0;
+    while let Some(instruction) = instructions.get(instruction_index) {
+        match instruction {
+            Instruction::Forward(offset) => {
+                emit!(code, "call void @point_right(i64 {offset})");
+            }
+            Instruction::Backward(offset) => {
+                emit!(code, "call void @point_left(i64 {offset})");
+            }
+            Instruction::Increment { offset, amount } => {
+                let address = address!(*offset);
+                let (value, sum) = (name(), name());
+                emit!(code, "%t{value} = load {cell}, ptr %t{address}");
+                emit!(
+                    code,
+                    "%t{sum} = add {cell} %t{value}, {}",
+                    immediate(*amount)
+                );
+                emit!(code, "store {cell} %t{sum}, ptr %t{address}");
+            }
+            Instruction::SetCell { offset, value } => {
+                let address = address!(*offset);
+                emit!(code, "store {cell} {}, ptr %t{address}", immediate(*value));
+            }
+            Instruction::LoopStart(_loop_end) => {
+                let (address, value, zero) = (name(), name(), name());
+                emit!(code, "br label %check{instruction_index}");
+                label(&mut code, &format!("check{instruction_index}"));
+                emit!(code, "%t{address} = call ptr @current()");
+                emit!(code, "%t{value} = load {cell}, ptr %t{address}");
+                emit!(code, "%t{zero} = icmp eq {cell} %t{value}, 0");
+                emit!(
+                    code,
+                    "br i1 %t{zero}, label %end{instruction_index}, label %body{instruction_index}"
+                );
+                label(&mut code, &format!("body{instruction_index}"));
+            }
+            Instruction::LoopEnd(loop_start) => {
+                let loop_start = loop_start - 1;
+                emit!(code, "br label %check{loop_start}");
+                label(&mut code, &format!("end{loop_start}"));
+            }
+            Instruction::IncrementLoop(increment) => {
+                let (address, value, check, body, done) = (name(), name(), name(), name(), name());
+                let (current, zero, sum) = (name(), name(), name());
+                emit!(code, "%t{address} = call ptr @current()");
+                emit!(code, "%t{value} = load {cell}, ptr %t{address}");
+                emit!(code, "store {cell} %t{value}, ptr %cell");
+                emit!(code, "br label %L{check}");
+                label(&mut code, &format!("L{check}"));
+                emit!(code, "%t{current} = load {cell}, ptr %cell");
+                emit!(code, "%t{zero} = icmp eq {cell} %t{current}, 0");
+                emit!(code, "br i1 %t{zero}, label %L{done}, label %L{body}");
+                label(&mut code, &format!("L{body}"));
+                emit!(
+                    code,
+                    "%t{sum} = add {cell} %t{current}, {}",
+                    immediate(*increment)
+                );
+                emit!(code, "store {cell} %t{sum}, ptr %cell");
+                emit!(code, "br label %L{check}");
+                label(&mut code, &format!("L{done}"));
+                emit!(code, "store {cell} %t{current}, ptr %t{address}");
+            }
+            Instruction::MultiplyForward(offset, multiplier)
+            | Instruction::MultiplyBackward(offset, multiplier) => {
+                let (address, value, zero, body, done) = (name(), name(), name(), name(), name());
+                let (index, target, old, product, sum) = (name(), name(), name(), name(), name());
+                let direction = if matches!(
+                    instructions[instruction_index],
+                    Instruction::MultiplyForward(..)
+                ) {
+                    "right"
+                } else {
+                    "left"
+                };
+                emit!(code, "%t{address} = call ptr @current()");
+                emit!(code, "%t{value} = load {cell}, ptr %t{address}");
+                emit!(code, "%t{zero} = icmp eq {cell} %t{value}, 0");
+                emit!(code, "br i1 %t{zero}, label %L{done}, label %L{body}");
+                label(&mut code, &format!("L{body}"));
+                emit!(code, "%t{index} = call i64 @{direction}(i64 {offset})");
+                emit!(code, "%t{target} = call ptr @cell(i64 %t{index})");
+                emit!(code, "%t{old} = load {cell}, ptr %t{target}");
+                emit!(
+                    code,
+                    "%t{product} = mul {cell} %t{value}, {}",
+                    immediate(*multiplier)
+                );
+                emit!(code, "%t{sum} = add {cell} %t{old}, %t{product}");
+                emit!(code, "store {cell} %t{sum}, ptr %t{target}");
+                emit!(code, "br label %L{done}");
+                label(&mut code, &format!("L{done}"));
+            }
+            Instruction::MultiplyProduct {
+                offset,
+                factor,
+                multiplier,
+            } => {
+                let (address, value, zero, body, done) = (name(), name(), name(), name(), name());
+                let (factor_value, product, scaled, old, sum) =
+                    (name(), name(), name(), name(), name());
+                emit!(code, "%t{address} = call ptr @current()");
+                emit!(code, "%t{value} = load {cell}, ptr %t{address}");
+                emit!(code, "%t{zero} = icmp eq {cell} %t{value}, 0");
+                emit!(code, "br i1 %t{zero}, label %L{done}, label %L{body}");
+                label(&mut code, &format!("L{body}"));
+                // The factor is read before finding the target, which can grow the tape.
+                let factor_address = address!(*factor);
+                emit!(
+                    code,
+                    "%t{factor_value} = load {cell}, ptr %t{factor_address}"
+                );
+                emit!(code, "%t{product} = mul {cell} %t{value}, %t{factor_value}");
+                emit!(
+                    code,
+                    "%t{scaled} = mul {cell} %t{product}, {}",
+                    immediate(*multiplier)
+                );
+                let target = address!(*offset);
+                emit!(code, "%t{old} = load {cell}, ptr %t{target}");
+                emit!(code, "%t{sum} = add {cell} %t{old}, %t{scaled}");
+                emit!(code, "store {cell} %t{sum}, ptr %t{target}");
+                emit!(code, "br label %L{done}");
+                label(&mut code, &format!("L{done}"));
+            }
+            Instruction::ForwardLoop(offset)
+            | Instruction::BackwardLoop(offset)
+            | Instruction::IncrementForwardLoop(offset, _)
+            | Instruction::IncrementBackwardLoop(offset, _) => {
+                let (check, body, done, address, value, zero) =
+                    (name(), name(), name(), name(), name(), name());
+                let direction = if matches!(
+                    instructions[instruction_index],
+                    Instruction::ForwardLoop(_) | Instruction::IncrementForwardLoop(..)
+                ) {
+                    "right"
+                } else {
+                    "left"
+                };
+                emit!(code, "br label %L{check}");
+                label(&mut code, &format!("L{check}"));
+                emit!(code, "%t{address} = call ptr @current()");
+                emit!(code, "%t{value} = load {cell}, ptr %t{address}");
+                emit!(code, "%t{zero} = icmp eq {cell} %t{value}, 0");
+                emit!(code, "br i1 %t{zero}, label %L{done}, label %L{body}");
+                label(&mut code, &format!("L{body}"));
+                if let Instruction::IncrementForwardLoop(_, amount)
+                | Instruction::IncrementBackwardLoop(_, amount) = instructions[instruction_index]
+                {
+                    let sum = name();
+                    emit!(
+                        code,
+                        "%t{sum} = add {cell} %t{value}, {}",
+                        immediate(amount)
+                    );
+                    emit!(code, "store {cell} %t{sum}, ptr %t{address}");
+                }
+                emit!(code, "call void @point_{direction}(i64 {offset})");
+                emit!(code, "br label %L{check}");
+                label(&mut code, &format!("L{done}"));
+            }
+            Instruction::Output { offset } => {
+                let address = address!(*offset);
+                let value = name();
+                emit!(code, "%t{value} = load {cell}, ptr %t{address}");
+                if settings.cell_width == CellWidth::U32 {
+                    emit!(code, "call i32 @putchar(i32 %t{value})");
+                } else {
+                    let widened = name();
+                    emit!(code, "%t{widened} = zext {cell} %t{value} to i32");
+                    emit!(code, "call i32 @putchar(i32 %t{widened})");
+                }
+            }
+            Instruction::Print { value, .. } => {
+                emit!(code, "call i32 @putchar(i32 {value})");
+            }
+            Instruction::OutputBytes(bytes) => {
+                emit!(
+                    code,
+                    "call void @put_bytes(ptr @output_bytes{}, i64 {})",
+                    output.len(),
+                    bytes.len()
+                );
+                output.push(bytes);
+            }
+            Instruction::Input => {
+                emit!(code, "call void @input()");
+            }
+            Instruction::Stop => break,
+        }
+        instruction_index += 1;
+    }
+    emit!(code, "%end_memory = load ptr, ptr @memory");
+    emit!(code, "call void @free(ptr %end_memory)");
+    emit!(code, "ret i32 0");
+    code.push_str("}\n");
+
+    for (index, bytes) in output.iter().enumerate() {
+        write!(
+            code,
+            "@output_bytes{index} = private unnamed_addr constant [{} x i8] c\"",
+            bytes.len()
+        )
+        .unwrap();
+        for byte in *bytes {
+            write!(code, "\\{byte:02X}").unwrap();
+        }
+        code.push_str("\"\n");
+    }
+
+    code
+}
@@ -0,0 +1,289 @@
+use core::fmt::Write;
+use core::iter;
+
+use crate::settings::{CellWidth, EofBehavior, Settings, TapePolicy};
+use crate::{compile::Instruction, MEMORY_RESIZE_AMOUNT};
+
+/// Most loops nested in one function. Python allows 20 nested blocks, and an instruction
+/// inside the loops can open one more.
+const MAX_NESTED_LOOPS: usize = 18;
+
+/// Compiles instructions into a Python 3 script.
+///
+/// Instructions that Brainfuck has no single command for are preceded by a comment naming them.
+/// Loops nested deeper than Python allows are moved into functions of their own.
+#[allow(clippy::too_many_lines)]
+#[must_use]
+pub fn to_python(instructions: &[Instruction], settings: &Settings) -> String {
+    let mut code = String::with_capacity(2048);
+
+    let mut indent_level = 0;
+    macro_rules! emit {
+        ($($argument:tt)*) => {
+            code.extend(iter::repeat("    ").take(indent_level));
+            writeln!(code, $($argument)*).unwrap();
+        };
+    }
+
+    let max = settings.cell_width.max();
+    let zeros = |size: &str| match settings.cell_width {
+        CellWidth::U8 => format!("bytearray({size})"),
+        CellWidth::U16 | CellWidth::U32 if size.contains(' ') => format!("[0] * ({size})"),
+        CellWidth::U16 | CellWidth::U32 => format!("[0] * {size}"),
+    };
+    // Adds `value` to `cell`, as a subtraction if that reads better.
+    let add = |cell: &str, value: u32| {
+        if value > max / 2 {
+            format!("({cell} - {}) & {max}", max - value + 1)
+        } else {
+            format!("({cell} + {value}) & {max}")
+        }
+    };
+
+    // Cells away from the pointer are found first, as finding them can grow the tape.
+    let at = |offset: i32| {
+        if offset.is_positive() {
+            format!("right({offset})")
+        } else {
+            format!("left({})", offset.unsigned_abs())
+        }
+    };
+    let index = |offset: i32| if offset == 0 { "pointer" } else { "target" };
+
+    emit!("import sys");
+    emit!();
+    emit!(
+        "memory = {}",
+        zeros(&settings.initial_tape_size().to_string())
+    );
+    emit!("pointer = 0");
+    emit!();
+    emit!();
+    emit!("def fail(message):");
+    emit!("    sys.stdout.buffer.flush()");
+    emit!("    sys.stderr.write(message + \"\\n\")");
+    emit!("    sys.exit(1)");
+    emit!();
+    emit!();
+
+    let limit = settings.tape_limit;
+    emit!("def right(offset):");
+    match settings.tape {
+        TapePolicy::Grow | TapePolicy::Bidirectional => {
+            emit!("    index = pointer + offset");
+            emit!("    if index >= len(memory):");
+            if let Some(limit) = limit {
+                emit!("        if index >= {limit}:");
+                emit!("            fail(\"tape limit exceeded\")");
+                emit!("        new_size = min(index + {MEMORY_RESIZE_AMOUNT}, {limit})");
+            } else {
+                emit!("        new_size = index + {MEMORY_RESIZE_AMOUNT}");
+            }
+            emit!("        memory.extend({})", zeros("new_size - len(memory)"));
+            emit!("    return index");
+        }
+        TapePolicy::Wrap(_) => {
+            emit!("    return (pointer + offset) % len(memory)");
+        }
+        TapePolicy::Fixed(_) => {
+            emit!("    if offset >= len(memory) - pointer:");
+            emit!("        fail(\"pointer moved right of the last cell\")");
+            emit!("    return pointer + offset");
+        }
+    }
+    emit!();
+    emit!();
+    emit!("def left(offset):");
+    match settings.tape {
+        TapePolicy::Grow | TapePolicy::Fixed(_) => {
+            emit!("    if offset > pointer:");
+            emit!("        fail(\"pointer moved left of the first cell\")");
+            emit!("    return pointer - offset");
+        }
+        TapePolicy::Wrap(_) => {
+            emit!("    return (pointer - offset) % len(memory)");
+        }
+        TapePolicy::Bidirectional => {
+            emit!("    global pointer");
+            emit!("    if offset > pointer:");
+            emit!("        needed = offset - pointer");
+            if let Some(limit) = limit {
+                emit!("        room = {limit} - len(memory)");
+                emit!("        if needed > room:");
+                emit!("            fail(\"tape limit exceeded\")");
+                emit!("        amount = min(needed + {MEMORY_RESIZE_AMOUNT}, room)");
+            } else {
+                emit!("        amount = needed + {MEMORY_RESIZE_AMOUNT}");
+            }
+            emit!("        memory[0:0] = {}", zeros("amount"));
+            emit!("        pointer += amount");
+            emit!("    return pointer - offset");
+        }
+    }
+    emit!();
+    emit!();
+    emit!("def read():");
+    emit!("    byte = sys.stdin.buffer.read(1)");
+    emit!("    if byte:");
+    emit!("        memory[pointer] = byte[0]");
+    match settings.eof {
+        EofBehavior::Unchanged => {}
+        EofBehavior::Zero => {
+            emit!("    else:");
+            emit!("        memory[pointer] = 0");
+        }
+        EofBehavior::Max => {
+            emit!("    else:");
+            emit!("        memory[pointer] = {max}");
+        }
+    }
+    emit!();
+    emit!();
+
+    // Loops open in the current function, and for each open loop, the loops open in the
+    // function it was moved out of, if it was.
+    let mut depth = 0;
+    let mut loop_stack = Vec::new();
+    let mut instruction_index = 0;
+    while let Some(instruction) = instructions.get(instruction_index) {
+        if matches!(
+            instruction,
+            Instruction::SetCell { .. }
+                | Instruction::IncrementLoop(_)
+                | Instruction::MultiplyForward(..)
+                | Instruction::MultiplyBackward(..)
+                | Instruction::MultiplyProduct { .. }
+                | Instruction::ForwardLoop(_)
+                | Instruction::BackwardLoop(_)
+                | Instruction::IncrementForwardLoop(..)
+                | Instruction::IncrementBackwardLoop(..)
+                | Instruction::Print { .. }
+        ) {
+            emit!("# {instruction:?}");
+        }
+        match instruction {
+            Instruction::Forward(offset) => {
+                emit!("pointer = right({offset})");
+            }
+            Instruction::Backward(offset) => {
+                emit!("pointer = left({offset})");
+            }
+            Instruction::Increment { offset, amount } => {
+                if *offset != 0 {
+                    emit!("target = {}", at(*offset));
+                }
+                let cell = format!("memory[{}]", index(*offset));
+                emit!("{cell} = {}", add(&cell, *amount));
+            }
+            Instruction::SetCell { offset, value } => {
+                if *offset != 0 {
+                    emit!("target = {}", at(*offset));
+                }
+                emit!("memory[{}] = {value}", index(*offset));
+            }
+            Instruction::LoopStart(_loop_end) => {
+                if depth == MAX_NESTED_LOOPS {
+                    emit!("def loop_{instruction_index}():");
+                    emit!("    global pointer");
+                    indent_level += 1;
+                    loop_stack.push(Some(depth));
+                    depth = 0;
+                } else {
+                    loop_stack.push(None);
+                }
+                emit!("while memory[pointer] != 0:");
+                indent_level += 1;
+                depth += 1;
+            }
+            Instruction::LoopEnd(loop_start) => {
+                if *loop_start as usize == instruction_index {
+                    emit!("pass");
+                }
+                indent_level -= 1;
+                depth -= 1;
+                if let Some(Some(outer_depth)) = loop_stack.pop() {
+                    indent_level -= 1;
+                    emit!("loop_{}()", loop_start - 1);
+                    depth = outer_depth;
+                }
+            }
+            Instruction::IncrementLoop(increment) => {
+                emit!("cell = memory[pointer]");
+                emit!("while cell != 0:");
+                emit!("    cell = {}", add("cell", *increment));
+                emit!("memory[pointer] = cell");
+            }
+            Instruction::MultiplyForward(offset, multiplier)
+            | Instruction::MultiplyBackward(offset, multiplier) => {
+                let direction = if matches!(instruction, Instruction::MultiplyForward(..)) {
+                    "right"
+                } else {
+                    "left"
+                };
+                emit!("if memory[pointer] != 0:");
+                emit!("    target = {direction}({offset})");
+                if *multiplier == 1 {
+                    emit!("    memory[target] = (memory[target] + memory[pointer]) & {max}");
+                } else {
+                    emit!(
+                        "    memory[target] = (memory[target] + memory[pointer] * {multiplier}) & {max}"
+                    );
+                }
+            }
+            Instruction::MultiplyProduct {
+                offset,
+                factor,
+                multiplier,
+            } => {
+                emit!("if memory[pointer] != 0:");
+                emit!("    factor = memory[{}]", at(*factor));
+                emit!("    target = {}", at(*offset));
+                emit!(
+                    "    memory[target] = (memory[target] + memory[pointer] * factor * {multiplier}) & {max}"
+                );
+            }
+            Instruction::ForwardLoop(offset) => {
+                emit!("while memory[pointer] != 0:");
+                emit!("    pointer = right({offset})");
+            }
+            Instruction::BackwardLoop(offset) => {
+                emit!("while memory[pointer] != 0:");
+                emit!("    pointer = left({offset})");
+            }
+            Instruction::IncrementForwardLoop(offset, amount) => {
+                emit!("while memory[pointer] != 0:");
+                emit!("    memory[pointer] = {}", add("memory[pointer]", *amount));
+                emit!("    pointer = right({offset})");
+            }
+            Instruction::IncrementBackwardLoop(offset, amount) => {
+                emit!("while memory[pointer] != 0:");
+                emit!("    memory[pointer] = {}", add("memory[pointer]", *amount));
+                emit!("    pointer = left({offset})");
+            }
+            Instruction::Output { offset } => {
+                if *offset != 0 {
+                    emit!("target = {}", at(*offset));
+                }
+                let index = index(*offset);
+                if settings.cell_width == CellWidth::U8 {
+                    emit!("sys.stdout.buffer.write(bytes([memory[{index}]]))");
+                } else {
+                    emit!("sys.stdout.buffer.write(bytes([memory[{index}] & 255]))");
+                }
+            }
+            Instruction::Print { value, .. } => {
+                emit!("sys.stdout.buffer.write(bytes([{value}]))");
+            }
+            Instruction::OutputBytes(bytes) => {
+                emit!("sys.stdout.buffer.write(b'{}')", bytes.escape_ascii());
+            }
+            Instruction::Input => {
+                emit!("read()");
+            }
+            Instruction::Stop => break,
+        }
+        instruction_index += 1;
+    }
+
+    code
+}
@@ -1,12 +1,12 @@
 use core::iter;
 
-use crate::MEMORY_RESIZE_AMOUNT;
-use crate::{compile::Instruction, INITIAL_MEMORY_CAPACITY};
+use crate::settings::{CellWidth, EofBehavior, Settings, TapePolicy};
+use crate::{compile::Instruction, MEMORY_RESIZE_AMOUNT};
 
 /// Compiles instructions into Rust
 #[allow(clippy::too_many_lines)]
 #[must_use]
-pub fn to_rust(instructions: &[Instruction]) -> String {
+pub fn to_rust(instructions: &[Instruction], settings: &Settings) -> String {
     let mut code = String::with_capacity(2048);
 
     let mut indent_level = 0;
@@ -26,30 +26,63 @@ pub fn to_rust(instructions: &[Instruction]) -> String {
             push_str!($text);
         };
     }
-    macro_rules! forward {
-        ($offset:expr) => {
-            push_str!("point_right!(");
+    macro_rules! call {
+        ($macro:expr, $offset:expr) => {
+            push_str!($macro);
+            push_str!("!(");
             push_str!(&$offset.to_string());
             push_str!(");\n");
         };
     }
 
-    push_str!("use std::io::{stdin, Read};\n");
+    // Index of the cell `offset` cells from the pointer, for the macros that take one.
+    let at = |offset: i32| {
+        if offset.is_positive() {
+            format!("right!({offset})")
+        } else {
+            format!("left!({})", offset.unsigned_abs())
+        }
+    };
+
+    push_str!("use std::io::{stdin, stdout, ErrorKind, Read, Write};\n");
     push_str!("use std::num::Wrapping;\n");
 
+    push_str!("type Cell = ");
+    push_str!(match settings.cell_width {
+        CellWidth::U8 => "u8",
+        CellWidth::U16 => "u16",
+        CellWidth::U32 => "u32",
+    });
+    push_str!(";\n");
+
+    push_str!(
+        "fn read_byte(stdin: &mut impl Read) -> Option<Cell> {
+\tlet mut input: [u8; 1] = [0; 1];
+\tmatch stdin.read_exact(&mut input) {
+\t\tOk(()) => Some(Cell::from(input[0])),
+\t\tErr(error) if error.kind() == ErrorKind::UnexpectedEof => None,
+\t\tErr(error) => panic!(\"{}\", error),
+\t}
+}\n"
+    );
+
     push_str!("fn main() {\n");
     indent_level += 1;
     indented_push!("let mut stdin = stdin().lock();\n");
+    indented_push!("let mut stdout = stdout().lock();\n");
     indented_push!("let mut pointer: usize = 0;\n");
 
-    indented_push!("let mut memory: Vec<Wrapping<u8>> = vec![Wrapping(0); ");
-    push_str!(&INITIAL_MEMORY_CAPACITY.to_string());
+    indented_push!("let mut memory: Vec<Wrapping<Cell>> = vec![Wrapping(0); ");
+    push_str!(&settings.initial_tape_size().to_string());
     push_str!("];\n");
 
     push_str!(
         "\tmacro_rules! cell {
+\t\t($position:expr) => {
+\t\t\tunsafe { memory.get_unchecked($position) }
+\t\t};
 \t\t() => {
-\t\t\tunsafe { memory.get_unchecked(pointer) }
+\t\t\tcell!(pointer)
 \t\t};
 \t}\n"
     );
@@ -67,6 +100,10 @@ pub fn to_rust(instructions: &[Instruction]) -> String {
 
     push_str!(
         "\tmacro_rules! increment {
+\t\t($number:expr, $index:expr) => {{
+\t\t\tlet index = $index;
+\t\t\t*mut_cell!(index) += $number;
+\t\t}};
 \t\t($number:expr) => {
 \t\t\t*mut_cell!() += $number;
 \t\t};
@@ -75,6 +112,10 @@ pub fn to_rust(instructions: &[Instruction]) -> String {
 
     push_str!(
         "\tmacro_rules! set_cell {
+\t\t($number:expr, $index:expr) => {{
+\t\t\tlet index = $index;
+\t\t\t*mut_cell!(index) = Wrapping($number);
+\t\t}};
 \t\t($number:expr) => {
 \t\t\t*mut_cell!() = Wrapping($number);
 \t\t};
@@ -82,12 +123,129 @@ pub fn to_rust(instructions: &[Instruction]) -> String {
     );
 
     push_str!(
-        "\tmacro_rules! point_right {
+        "\tmacro_rules! fail {
+\t\t($message:expr) => {{
+\t\t\tstdout.flush().unwrap();
+\t\t\teprintln!(\"{}\", $message);
+\t\t\tstd::process::exit(1);
+\t\t}};
+\t}\n"
+    );
+
+    let resize_amount = MEMORY_RESIZE_AMOUNT.to_string();
+    let limit = settings.tape_limit.map(|limit| limit.to_string());
+    match settings.tape {
+        TapePolicy::Grow | TapePolicy::Bidirectional => {
+            push_str!(
+                "\tmacro_rules! right {
+\t\t($offset:expr) => {{
+\t\t\tlet index = pointer + $offset;
+\t\t\tif index >= memory.len() {\n"
+            );
+            if let Some(limit) = &limit {
+                push_str!("\t\t\t\tif index >= ");
+                push_str!(limit);
+                push_str!(" {\n\t\t\t\t\tfail!(\"tape limit exceeded\");\n\t\t\t\t}\n");
+                push_str!("\t\t\t\tmemory.resize((index + ");
+                push_str!(&resize_amount);
+                push_str!(").min(");
+                push_str!(limit);
+                code.push(')');
+            } else {
+                push_str!("\t\t\t\tmemory.resize(index + ");
+                push_str!(&resize_amount);
+            }
+            push_str!(
+                ", Wrapping(0));
+\t\t\t}
+\t\t\tindex
+\t\t}};
+\t}\n"
+            );
+        }
+        TapePolicy::Wrap(_) => {
+            push_str!(
+                "\tmacro_rules! right {
 \t\t($offset:expr) => {
-\t\t\tpointer = pointer.wrapping_add($offset);
-\t\t\tif pointer >= memory.len() {
-\t\t\t\tmemory.resize(pointer + 16, Wrapping(0));
+\t\t\t(pointer + $offset % memory.len()) % memory.len()
+\t\t};
+\t}\n"
+            );
+        }
+        TapePolicy::Fixed(_) => {
+            push_str!(
+                "\tmacro_rules! right {
+\t\t($offset:expr) => {{
+\t\t\tif $offset >= memory.len() - pointer {
+\t\t\t\tfail!(\"pointer moved right of the last cell\");
+\t\t\t}
+\t\t\tpointer + $offset
+\t\t}};
+\t}\n"
+            );
+        }
+    }
+    match settings.tape {
+        TapePolicy::Grow | TapePolicy::Fixed(_) => {
+            push_str!(
+                "\tmacro_rules! left {
+\t\t($offset:expr) => {{
+\t\t\tif $offset > pointer {
+\t\t\t\tfail!(\"pointer moved left of the first cell\");
 \t\t\t}
+\t\t\tpointer - $offset
+\t\t}};
+\t}\n"
+            );
+        }
+        TapePolicy::Wrap(_) => {
+            push_str!(
+                "\tmacro_rules! left {
+\t\t($offset:expr) => {
+\t\t\t(pointer + memory.len() - $offset % memory.len()) % memory.len()
+\t\t};
+\t}\n"
+            );
+        }
+        TapePolicy::Bidirectional => {
+            push_str!(
+                "\tmacro_rules! left {
+\t\t($offset:expr) => {{
+\t\t\tif $offset > pointer {\n"
+            );
+            if let Some(limit) = &limit {
+                push_str!("\t\t\t\tlet room = ");
+                push_str!(limit);
+                push_str!(
+                    " - memory.len();
+\t\t\t\tlet needed = $offset - pointer;
+\t\t\t\tif needed > room {
+\t\t\t\t\tfail!(\"tape limit exceeded\");
+\t\t\t\t}
+\t\t\t\tlet extra = (needed + "
+                );
+                push_str!(&resize_amount);
+                push_str!(").min(room)");
+            } else {
+                push_str!("\t\t\t\tlet extra = $offset - pointer + ");
+                push_str!(&resize_amount);
+            }
+            push_str!(
+                ";
+\t\t\t\tmemory.splice(0..0, std::iter::repeat(Wrapping(0)).take(extra));
+\t\t\t\tpointer += extra;
+\t\t\t}
+\t\t\tpointer - $offset
+\t\t}};
+\t}\n"
+            );
+        }
+    }
+
+    push_str!(
+        "\tmacro_rules! point_right {
+\t\t($offset:expr) => {
+\t\t\tpointer = right!($offset)
 \t\t};
 \t}\n"
     );
@@ -95,15 +253,24 @@ pub fn to_rust(instructions: &[Instruction]) -> String {
     push_str!(
         "\tmacro_rules! point_left {
 \t\t($offset:expr) => {
-\t\t\tpointer = pointer.wrapping_sub($offset)
+\t\t\tpointer = left!($offset)
 \t\t};
 \t}\n"
     );
 
     push_str!(
         "\tmacro_rules! output {
+\t\t($index:expr) => {{
+\t\t\tlet index = $index;
+\t\t\tstdout.write_all(&[cell!(index).0 as u8]).unwrap();
+\t\t}};
 \t\t() => {
-\t\t\tprint!(\"{}\", cell!().0 as char);
+\t\t\tstdout.write_all(&[cell!().0 as u8]).unwrap();
+\t\t};
+\t}
+\tmacro_rules! print_byte {
+\t\t($value:expr) => {
+\t\t\tstdout.write_all(&[$value]).unwrap();
 \t\t};
 \t}\n"
     );
@@ -116,26 +283,118 @@ pub fn to_rust(instructions: &[Instruction]) -> String {
 \t}\n"
     );
 
+    // Scans search the cells already on the tape, then step off its end to grow or wrap it.
+    push_str!(
+        "\tmacro_rules! scan_right {
+\t\t($stride:expr) => {
+\t\t\twhile cell_is_not_zero!() {
+\t\t\t\tmatch memory[pointer..].iter().step_by($stride).position(|cell| cell.0 == 0) {
+\t\t\t\t\tSome(passed) => pointer += passed * $stride,
+\t\t\t\t\tNone => {
+\t\t\t\t\t\tpointer += (memory.len() - 1 - pointer) / $stride * $stride;
+\t\t\t\t\t\tpoint_right!($stride);
+\t\t\t\t\t}
+\t\t\t\t}
+\t\t\t}
+\t\t};
+\t\t($stride:expr, $amount:expr) => {
+\t\t\twhile cell_is_not_zero!() {
+\t\t\t\tlet last = pointer + (memory.len() - 1 - pointer) / $stride * $stride;
+\t\t\t\twhile pointer != last && cell_is_not_zero!() {
+\t\t\t\t\tincrement!($amount);
+\t\t\t\t\tpointer += $stride;
+\t\t\t\t}
+\t\t\t\tif cell_is_not_zero!() {
+\t\t\t\t\tincrement!($amount);
+\t\t\t\t\tpoint_right!($stride);
+\t\t\t\t}
+\t\t\t}
+\t\t};
+\t}\n"
+    );
+
+    push_str!(
+        "\tmacro_rules! scan_left {
+\t\t($stride:expr) => {
+\t\t\twhile cell_is_not_zero!() {
+\t\t\t\tmatch memory[..=pointer].iter().rev().step_by($stride).position(|cell| cell.0 == 0) {
+\t\t\t\t\tSome(passed) => pointer -= passed * $stride,
+\t\t\t\t\tNone => {
+\t\t\t\t\t\tpointer %= $stride;
+\t\t\t\t\t\tpoint_left!($stride);
+\t\t\t\t\t}
+\t\t\t\t}
+\t\t\t}
+\t\t};
+\t\t($stride:expr, $amount:expr) => {
+\t\t\twhile cell_is_not_zero!() {
+\t\t\t\tlet last = pointer % $stride;
+\t\t\t\twhile pointer != last && cell_is_not_zero!() {
+\t\t\t\t\tincrement!($amount);
+\t\t\t\t\tpointer -= $stride;
+\t\t\t\t}
+\t\t\t\tif cell_is_not_zero!() {
+\t\t\t\t\tincrement!($amount);
+\t\t\t\t\tpoint_left!($stride);
+\t\t\t\t}
+\t\t\t}
+\t\t};
+\t}\n"
+    );
+
+    push_str!(match settings.eof {
+        EofBehavior::Unchanged => {
+            "\tmacro_rules! input {
+\t\t() => {
+\t\t\tif let Some(input) = read_byte(&mut stdin) {
+\t\t\t\tset_cell!(input);
+\t\t\t}
+\t\t};
+\t}\n"
+        }
+        EofBehavior::Zero => {
+            "\tmacro_rules! input {
+\t\t() => {
+\t\t\tset_cell!(read_byte(&mut stdin).unwrap_or(0));
+\t\t};
+\t}\n"
+        }
+        EofBehavior::Max => {
+            "\tmacro_rules! input {
+\t\t() => {
+\t\t\tset_cell!(read_byte(&mut stdin).unwrap_or(Cell::MAX));
+\t\t};
+\t}\n"
+        }
+    });
+
     let mut instruction_index = 0;
-    loop {
-        match unsafe { instructions.get_unchecked(instruction_index) } {
+    while let Some(instruction) = instructions.get(instruction_index) {
+        match instruction {
             Instruction::Forward(offset) => {
                 indent!();
-                forward!(offset);
+                call!("point_right", offset);
             }
             Instruction::Backward(offset) => {
-                indented_push!("point_left!(");
-                push_str!(&offset.to_string());
-                push_str!(");\n");
+                indent!();
+                call!("point_left", offset);
             }
-            Instruction::Increment(increment) => {
+            Instruction::Increment { offset, amount } => {
                 indented_push!("increment!(");
-                push_str!(&increment.to_string());
+                push_str!(&amount.to_string());
+                if *offset != 0 {
+                    push_str!(", ");
+                    push_str!(&at(*offset));
+                }
                 push_str!(");\n");
             }
-            Instruction::SetCell(value) => {
+            Instruction::SetCell { offset, value } => {
                 indented_push!("set_cell!(");
                 push_str!(&value.to_string());
+                if *offset != 0 {
+                    push_str!(", ");
+                    push_str!(&at(*offset));
+                }
                 push_str!(");\n");
             }
             Instruction::LoopStart(_loop_end) => {
@@ -167,21 +426,10 @@ pub fn to_rust(instructions: &[Instruction]) -> String {
                 indented_push!("if cell != Wrapping(0) {\n");
                 indent_level += 1;
 
-                indented_push!("if pointer + ");
-                push_str!(&offset.to_string());
-                push_str!(" >= memory.len() {\n");
+                indented_push!("let target = ");
+                call!("right", offset);
 
-                indent_level += 1;
-                indented_push!("memory.resize(pointer + ");
-                push_str!(&(*offset as usize + MEMORY_RESIZE_AMOUNT).to_string());
-                push_str!(", Wrapping(0));\n");
-                indent_level -= 1;
-
-                indented_push!("}\n");
-
-                indented_push!("*mut_cell!(pointer + ");
-                push_str!(&offset.to_string());
-                push_str!(") += cell");
+                indented_push!("*mut_cell!(target) += cell");
                 if *multiplier != 1 {
                     push_str!(" * Wrapping(");
                     push_str!(&multiplier.to_string());
@@ -198,9 +446,10 @@ pub fn to_rust(instructions: &[Instruction]) -> String {
                 indented_push!("if cell != Wrapping(0) {\n");
                 indent_level += 1;
 
-                indented_push!("*mut_cell!(pointer - ");
-                push_str!(&offset.to_string());
-                push_str!(") += cell");
+                indented_push!("let target = ");
+                call!("left", offset);
+
+                indented_push!("*mut_cell!(target) += cell");
                 if *multiplier != 1 {
                     push_str!(" * Wrapping(");
                     push_str!(&multiplier.to_string());
@@ -211,36 +460,77 @@ pub fn to_rust(instructions: &[Instruction]) -> String {
                 indent_level -= 1;
                 indented_push!("}\n");
             }
-            Instruction::ForwardLoop(offset) => {
-                indented_push!("while cell_is_not_zero!() {\n");
+            Instruction::MultiplyProduct {
+                offset,
+                factor,
+                multiplier,
+            } => {
+                indented_push!("let cell = *cell!();\n");
+
+                indented_push!("if cell != Wrapping(0) {\n");
                 indent_level += 1;
 
-                indent!();
-                forward!(offset);
+                indented_push!("let factor = ");
+                push_str!(&at(*factor));
+                push_str!(";\n");
+                indented_push!("let factor = *cell!(factor);\n");
+
+                indented_push!("let target = ");
+                push_str!(&at(*offset));
+                push_str!(";\n");
+
+                indented_push!("*mut_cell!(target) += cell * factor");
+                if *multiplier != 1 {
+                    push_str!(" * Wrapping(");
+                    push_str!(&multiplier.to_string());
+                    code.push(')');
+                }
+                push_str!(";\n");
 
                 indent_level -= 1;
                 indented_push!("}\n");
             }
+            Instruction::ForwardLoop(offset) => {
+                indent!();
+                call!("scan_right", offset);
+            }
             Instruction::BackwardLoop(offset) => {
-                indented_push!("while cell_is_not_zero!() {\n");
-                indent_level += 1;
-
-                indented_push!("point_left!(");
+                indent!();
+                call!("scan_left", offset);
+            }
+            Instruction::IncrementForwardLoop(offset, amount) => {
+                indented_push!("scan_right!(");
                 push_str!(&offset.to_string());
-                push_str!(")\n");
-
-                indent_level -= 1;
-                indented_push!("}\n");
+                push_str!(", ");
+                push_str!(&amount.to_string());
+                push_str!(");\n");
             }
-            Instruction::Output => {
-                indented_push!("output!();\n");
+            Instruction::IncrementBackwardLoop(offset, amount) => {
+                indented_push!("scan_left!(");
+                push_str!(&offset.to_string());
+                push_str!(", ");
+                push_str!(&amount.to_string());
+                push_str!(");\n");
+            }
+            Instruction::Output { offset } => {
+                indented_push!("output!(");
+                if *offset != 0 {
+                    push_str!(&at(*offset));
+                }
+                push_str!(");\n");
+            }
+            Instruction::Print { value, .. } => {
+                indented_push!("print_byte!(");
+                push_str!(&value.to_string());
+                push_str!(");\n");
+            }
+            Instruction::OutputBytes(bytes) => {
+                indented_push!("stdout.write_all(b\"");
+                push_str!(&bytes.escape_ascii().to_string());
+                push_str!("\").unwrap();\n");
             }
             Instruction::Input => {
-                indented_push!("let mut input: [u8; 1] = [0; 1];\n");
-
-                indented_push!("stdin.read_exact(&mut input).unwrap();\n");
-
-                indented_push!("set_cell!(input[0]);\n");
+                indented_push!("input!();\n");
             }
             Instruction::Stop => break,
         }
@@ -0,0 +1,549 @@
+use core::iter;
+
+use crate::settings::{CellWidth, EofBehavior, Settings, TapePolicy};
+use crate::{compile::Instruction, MEMORY_RESIZE_AMOUNT};
+
+/// Bytes in a WebAssembly memory page.
+const PAGE_SIZE: usize = 65536;
+
+/// Compiles instructions into a WebAssembly text module.
+///
+/// The module imports `env.input`, which returns the next byte or -1 at the end of input,
+/// and `env.output`, which writes a byte. It exports `run`, which runs the program, and
+/// `memory`, which holds the tape.
+///
+/// When the program stops early the module sets the exported `error` global and traps:
+/// 1 when the pointer moves left of the first cell, 2 when it moves right of the last cell,
+/// 3 when the tape limit is exceeded and 4 when memory runs out.
+#[allow(clippy::too_many_lines)]
+#[must_use]
+pub fn to_wasm(instructions: &[Instruction], settings: &Settings) -> String {
+    let mut code = String::with_capacity(2048);
+
+    let mut indent_level = 0;
+    macro_rules! indent {
+        () => {
+            code.extend(iter::repeat('\t').take(indent_level));
+        };
+    }
+    macro_rules! push_str {
+        ($text:expr) => {
+            code.push_str($text);
+        };
+    }
+    macro_rules! emit {
+        ($($text:expr),+) => {
+            indent!();
+            $(push_str!($text);)+
+            code.push('\n');
+        };
+    }
+
+    // Cells are `1 << shift` bytes wide.
+    let shift = settings.cell_width.bits().trailing_zeros() - 3;
+    let shift_text = &shift.to_string();
+    // Turns the cell index on the stack into a byte address.
+    macro_rules! scale {
+        () => {
+            if shift != 0 {
+                emit!("i32.const ", shift_text);
+                emit!("i32.shl");
+            }
+        };
+    }
+    let (load, store) = match settings.cell_width {
+        CellWidth::U8 => ("i32.load8_u", "i32.store8"),
+        CellWidth::U16 => ("i32.load16_u", "i32.store16"),
+        CellWidth::U32 => ("i32.load", "i32.store"),
+    };
+    let initial_size = settings.initial_tape_size();
+    let pages = (initial_size << shift).div_ceil(PAGE_SIZE).max(1);
+    let resize_amount = &MEMORY_RESIZE_AMOUNT.to_string();
+    let limit = settings.tape_limit.map(|limit| limit.to_string());
+
+    push_str!("(module\n");
+    indent_level += 1;
+    emit!("(import \"env\" \"input\" (func $input (result i32)))");
+    emit!("(import \"env\" \"output\" (func $output (param i32)))");
+    emit!("(memory (export \"memory\") ", &pages.to_string(), ")");
+    emit!("(global $error (export \"error\") (mut i32) (i32.const 0))");
+    emit!("(global $pointer (mut i32) (i32.const 0))");
+    emit!(
+        "(global $size (mut i32) (i32.const ",
+        &initial_size.to_string(),
+        "))"
+    );
+
+    emit!("(func $fail (param $code i32)");
+    indent_level += 1;
+    emit!("local.get $code");
+    emit!("global.set $error");
+    emit!("unreachable)");
+    indent_level -= 1;
+
+    emit!("(func $load (param $index i32) (result i32)");
+    indent_level += 1;
+    emit!("local.get $index");
+    scale!();
+    emit!(load, ")");
+    indent_level -= 1;
+
+    emit!("(func $store (param $index i32) (param $value i32)");
+    indent_level += 1;
+    emit!("local.get $index");
+    scale!();
+    emit!("local.get $value");
+    emit!(store, ")");
+    indent_level -= 1;
+
+    match settings.tape {
+        TapePolicy::Grow | TapePolicy::Bidirectional => {
+            // Cells that fit in the 32-bit address space, leaving room to round up to a page.
+            let max_cells = ((u32::MAX - PAGE_SIZE as u32) >> shift).to_string();
+            emit!("(func $grow (param $new_size i32) (local $pages i32)");
+            indent_level += 1;
+            emit!("local.get $new_size");
+            emit!("i32.const ", &max_cells);
+            emit!("i32.gt_u");
+            emit!("if");
+            emit!("\ti32.const 4");
+            emit!("\tcall $fail");
+            emit!("end");
+            emit!("local.get $new_size");
+            scale!();
+            emit!("i32.const ", &(PAGE_SIZE - 1).to_string());
+            emit!("i32.add");
+            emit!("i32.const 16");
+            emit!("i32.shr_u");
+            emit!("memory.size");
+            emit!("i32.sub");
+            emit!("local.tee $pages");
+            emit!("i32.const 0");
+            emit!("i32.gt_s");
+            emit!("if");
+            emit!("\tlocal.get $pages");
+            emit!("\tmemory.grow");
+            emit!("\ti32.const -1");
+            emit!("\ti32.eq");
+            emit!("\tif");
+            emit!("\t\ti32.const 4");
+            emit!("\t\tcall $fail");
+            emit!("\tend");
+            emit!("end");
+            emit!("local.get $new_size");
+            emit!("global.set $size)");
+            indent_level -= 1;
+
+            emit!("(func $right (param $offset i32) (result i32) (local $index i32)");
+            indent_level += 1;
+            emit!("global.get $pointer");
+            emit!("local.get $offset");
+            emit!("i32.add");
+            emit!("local.tee $index");
+            emit!("global.get $size");
+            emit!("i32.ge_u");
+            emit!("if");
+            indent_level += 1;
+            if let Some(limit) = &limit {
+                emit!("local.get $index");
+                emit!("i32.const ", limit);
+                emit!("i32.ge_u");
+                emit!("if");
+                emit!("\ti32.const 3");
+                emit!("\tcall $fail");
+                emit!("end");
+            }
+            emit!("local.get $index");
+            emit!("i32.const ", resize_amount);
+            emit!("i32.add");
+            if let Some(limit) = &limit {
+                emit!("local.tee $index");
+                emit!("i32.const ", limit);
+                emit!("local.get $index");
+                emit!("i32.const ", limit);
+                emit!("i32.lt_u");
+                emit!("select");
+                emit!("call $grow");
+                emit!("global.get $pointer");
+                emit!("local.get $offset");
+                emit!("i32.add");
+                emit!("local.set $index");
+            } else {
+                emit!("call $grow");
+            }
+            indent_level -= 1;
+            emit!("end");
+            emit!("local.get $index)");
+            indent_level -= 1;
+        }
+        TapePolicy::Wrap(_) => {
+            emit!("(func $right (param $offset i32) (result i32)");
+            indent_level += 1;
+            emit!("global.get $pointer");
+            emit!("local.get $offset");
+            emit!("global.get $size");
+            emit!("i32.rem_u");
+            emit!("i32.add");
+            emit!("global.get $size");
+            emit!("i32.rem_u)");
+            indent_level -= 1;
+        }
+        TapePolicy::Fixed(_) => {
+            emit!("(func $right (param $offset i32) (result i32)");
+            indent_level += 1;
+            emit!("local.get $offset");
+            emit!("global.get $size");
+            emit!("global.get $pointer");
+            emit!("i32.sub");
+            emit!("i32.ge_u");
+            emit!("if");
+            emit!("\ti32.const 2");
+            emit!("\tcall $fail");
+            emit!("end");
+            emit!("global.get $pointer");
+            emit!("local.get $offset");
+            emit!("i32.add)");
+            indent_level -= 1;
+        }
+    }
+    match settings.tape {
+        TapePolicy::Grow | TapePolicy::Fixed(_) => {
+            emit!("(func $left (param $offset i32) (result i32)");
+            indent_level += 1;
+            emit!("local.get $offset");
+            emit!("global.get $pointer");
+            emit!("i32.gt_u");
+            emit!("if");
+            emit!("\ti32.const 1");
+            emit!("\tcall $fail");
+            emit!("end");
+            emit!("global.get $pointer");
+            emit!("local.get $offset");
+            emit!("i32.sub)");
+            indent_level -= 1;
+        }
+        TapePolicy::Wrap(_) => {
+            emit!("(func $left (param $offset i32) (result i32)");
+            indent_level += 1;
+            emit!("global.get $pointer");
+            emit!("global.get $size");
+            emit!("i32.add");
+            emit!("local.get $offset");
+            emit!("global.get $size");
+            emit!("i32.rem_u");
+            emit!("i32.sub");
+            emit!("global.get $size");
+            emit!("i32.rem_u)");
+            indent_level -= 1;
+        }
+        TapePolicy::Bidirectional => {
+            emit!("(func $left (param $offset i32) (result i32) (local $extra i32) (local $old_size i32)");
+            indent_level += 1;
+            emit!("local.get $offset");
+            emit!("global.get $pointer");
+            emit!("i32.gt_u");
+            emit!("if");
+            indent_level += 1;
+            emit!("local.get $offset");
+            emit!("global.get $pointer");
+            emit!("i32.sub");
+            if let Some(limit) = &limit {
+                // Room left is limit - size.
+                emit!("local.tee $extra");
+                emit!("i32.const ", limit);
+                emit!("global.get $size");
+                emit!("i32.sub");
+                emit!("i32.gt_u");
+                emit!("if");
+                emit!("\ti32.const 3");
+                emit!("\tcall $fail");
+                emit!("end");
+                emit!("local.get $extra");
+                emit!("i32.const ", resize_amount);
+                emit!("i32.add");
+                emit!("local.tee $extra");
+                emit!("i32.const ", limit);
+                emit!("global.get $size");
+                emit!("i32.sub");
+                emit!("local.get $extra");
+                emit!("i32.const ", limit);
+                emit!("global.get $size");
+                emit!("i32.sub");
+                emit!("i32.lt_u");
+                emit!("select");
+            } else {
+                emit!("i32.const ", resize_amount);
+                emit!("i32.add");
+            }
+            emit!("local.set $extra");
+            emit!("global.get $size");
+            emit!("local.tee $old_size");
+            emit!("local.get $extra");
+            emit!("i32.add");
+            emit!("call $grow");
+            // Move the cells up to make room at the front.
+            emit!("local.get $extra");
+            scale!();
+            emit!("i32.const 0");
+            emit!("local.get $old_size");
+            scale!();
+            emit!("memory.copy");
+            emit!("i32.const 0");
+            emit!("i32.const 0");
+            emit!("local.get $extra");
+            scale!();
+            emit!("memory.fill");
+            emit!("global.get $pointer");
+            emit!("local.get $extra");
+            emit!("i32.add");
+            emit!("global.set $pointer");
+            indent_level -= 1;
+            emit!("end");
+            emit!("global.get $pointer");
+            emit!("local.get $offset");
+            emit!("i32.sub)");
+            indent_level -= 1;
+        }
+    }
+
+    emit!("(func $read (local $byte i32)");
+    indent_level += 1;
+    emit!("call $input");
+    emit!("local.tee $byte");
+    emit!("i32.const 0");
+    emit!("i32.lt_s");
+    emit!("if");
+    match settings.eof {
+        EofBehavior::Unchanged => {
+            emit!("\treturn");
+        }
+        EofBehavior::Zero => {
+            emit!("\ti32.const 0");
+            emit!("\tlocal.set $byte");
+        }
+        EofBehavior::Max => {
+            emit!("\ti32.const -1");
+            emit!("\tlocal.set $byte");
+        }
+    }
+    emit!("end");
+    emit!("global.get $pointer");
+    emit!("local.get $byte");
+    emit!("call $store)");
+    indent_level -= 1;
+
+    emit!("(func (export \"run\") (local $cell i32) (local $target i32)");
+    indent_level += 1;
+    let mask = settings.cell_width.max().to_string();
+    // Pushes the index of the cell `offset` cells from the pointer.
+    macro_rules! cell_index {
+        ($offset:expr) => {
+            let offset: i32 = $offset;
+            if offset == 0 {
+                emit!("global.get $pointer");
+            } else {
+                emit!("i32.const ", &offset.unsigned_abs().to_string());
+                emit!(if offset.is_positive() {
+                    "call $right"
+                } else {
+                    "call $left"
+                });
+            }
+        };
+    }
+    let mut instruction_index = 0;
+    while let Some(instruction) = instructions.get(instruction_index) {
+        match instruction {
+            Instruction::Forward(offset) => {
+                emit!("i32.const ", &offset.to_string());
+                emit!("call $right");
+                emit!("global.set $pointer");
+            }
+            Instruction::Backward(offset) => {
+                emit!("i32.const ", &offset.to_string());
+                emit!("call $left");
+                emit!("global.set $pointer");
+            }
+            Instruction::Increment { offset, amount } => {
+                cell_index!(*offset);
+                emit!("local.tee $target");
+                emit!("local.get $target");
+                emit!("call $load");
+                emit!("i32.const ", &amount.to_string());
+                emit!("i32.add");
+                emit!("call $store");
+            }
+            Instruction::SetCell { offset, value } => {
+                cell_index!(*offset);
+                emit!("i32.const ", &value.to_string());
+                emit!("call $store");
+            }
+            Instruction::LoopStart(_loop_end) => {
+                emit!("block");
+                emit!("\tloop");
+                indent_level += 2;
+                emit!("global.get $pointer");
+                emit!("call $load");
+                emit!("i32.eqz");
+                emit!("br_if 1");
+            }
+            Instruction::LoopEnd(_loop_start) => {
+                emit!("br 0");
+                indent_level -= 2;
+                emit!("\tend");
+                emit!("end");
+            }
+            Instruction::IncrementLoop(value) => {
+                emit!("global.get $pointer");
+                emit!("call $load");
+                emit!("local.set $cell");
+                emit!("block");
+                emit!("\tloop");
+                indent_level += 2;
+                emit!("local.get $cell");
+                emit!("i32.eqz");
+                emit!("br_if 1");
+                emit!("local.get $cell");
+                emit!("i32.const ", &value.to_string());
+                emit!("i32.add");
+                if settings.cell_width != CellWidth::U32 {
+                    emit!("i32.const ", &mask);
+                    emit!("i32.and");
+                }
+                emit!("local.set $cell");
+                emit!("br 0");
+                indent_level -= 2;
+                emit!("\tend");
+                emit!("end");
+                emit!("global.get $pointer");
+                emit!("local.get $cell");
+                emit!("call $store");
+            }
+            Instruction::MultiplyForward(offset, multiplier)
+            | Instruction::MultiplyBackward(offset, multiplier) => {
+                emit!("global.get $pointer");
+                emit!("call $load");
+                emit!("local.tee $cell");
+                emit!("if");
+                indent_level += 1;
+                emit!("i32.const ", &offset.to_string());
+                if matches!(
+                    instructions[instruction_index],
+                    Instruction::MultiplyForward(..)
+                ) {
+                    emit!("call $right");
+                } else {
+                    emit!("call $left");
+                }
+                emit!("local.tee $target");
+                emit!("local.get $target");
+                emit!("call $load");
+                emit!("local.get $cell");
+                if *multiplier != 1 {
+                    emit!("i32.const ", &multiplier.to_string());
+                    emit!("i32.mul");
+                }
+                emit!("i32.add");
+                emit!("call $store");
+                indent_level -= 1;
+                emit!("end");
+            }
+            Instruction::MultiplyProduct {
+                offset,
+                factor,
+                multiplier,
+            } => {
+                emit!("global.get $pointer");
+                emit!("call $load");
+                emit!("local.tee $cell");
+                emit!("if");
+                indent_level += 1;
+                // The product is worked out before finding the target, which can grow the tape.
+                cell_index!(*factor);
+                emit!("call $load");
+                emit!("local.get $cell");
+                emit!("i32.mul");
+                if *multiplier != 1 {
+                    emit!("i32.const ", &multiplier.to_string());
+                    emit!("i32.mul");
+                }
+                emit!("local.set $cell");
+                cell_index!(*offset);
+                emit!("local.tee $target");
+                emit!("local.get $target");
+                emit!("call $load");
+                emit!("local.get $cell");
+                emit!("i32.add");
+                emit!("call $store");
+                indent_level -= 1;
+                emit!("end");
+            }
+            Instruction::ForwardLoop(offset)
+            | Instruction::BackwardLoop(offset)
+            | Instruction::IncrementForwardLoop(offset, _)
+            | Instruction::IncrementBackwardLoop(offset, _) => {
+                emit!("block");
+                emit!("\tloop");
+                indent_level += 2;
+                emit!("global.get $pointer");
+                emit!("call $load");
+                emit!("i32.eqz");
+                emit!("br_if 1");
+                if let Instruction::IncrementForwardLoop(_, amount)
+                | Instruction::IncrementBackwardLoop(_, amount) = instructions[instruction_index]
+                {
+                    emit!("global.get $pointer");
+                    emit!("global.get $pointer");
+                    emit!("call $load");
+                    emit!("i32.const ", &amount.to_string());
+                    emit!("i32.add");
+                    emit!("call $store");
+                }
+                emit!("i32.const ", &offset.to_string());
+                if matches!(
+                    instructions[instruction_index],
+                    Instruction::ForwardLoop(_) | Instruction::IncrementForwardLoop(..)
+                ) {
+                    emit!("call $right");
+                } else {
+                    emit!("call $left");
+                }
+                emit!("global.set $pointer");
+                emit!("br 0");
+                indent_level -= 2;
+                emit!("\tend");
+                emit!("end");
+            }
+            Instruction::Output { offset } => {
+                cell_index!(*offset);
+                emit!("call $load");
+                emit!("i32.const 255");
+                emit!("i32.and");
+                emit!("call $output");
+            }
+            Instruction::Print { value, .. } => {
+                emit!("i32.const ", &value.to_string());
+                emit!("call $output");
+            }
+            // Memory is all tape, so the bytes are constants in the code rather than data.
+            Instruction::OutputBytes(bytes) => {
+                for byte in bytes {
+                    emit!("i32.const ", &byte.to_string());
+                    emit!("call $output");
+                }
+            }
+            Instruction::Input => {
+                emit!("call $read");
+            }
+            Instruction::Stop => break,
+        }
+        instruction_index += 1;
+    }
+    // Close the function and the module.
+    code.pop();
+    push_str!("))\n");
+    indent_level -= 2;
+    debug_assert_eq!(indent_level, 0);
+
+    code
+}
@@ -0,0 +1,459 @@
+use core::fmt::{self, Write};
+
+use crate::bytecode::{validate, LoadError};
+use crate::compile::{Instruction, Span};
+use crate::settings::CellWidth;
+
+/// Why parsing failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseErrorKind {
+    /// The name is not an instruction.
+    UnknownInstruction,
+
+    /// The instruction has the wrong number of operands, or one is not a number.
+    InvalidOperands,
+
+    /// A `LoopStart` has no matching `LoopEnd`.
+    UnclosedLoop,
+
+    /// A `LoopEnd` has no matching `LoopStart`.
+    UnopenedLoop,
+
+    /// A value does not fit in a cell.
+    ValueOutOfRange,
+}
+
+/// Error returned when IR text is malformed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParseError {
+    /// Why parsing failed.
+    pub kind: ParseErrorKind,
+
+    /// Line number, starting at 1.
+    pub line: usize,
+
+    /// Column of the instruction's name, starting at 1.
+    pub column: usize,
+}
+
+impl fmt::Display for ParseErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::UnknownInstruction => "unknown instruction",
+            Self::InvalidOperands => "invalid operands",
+            Self::UnclosedLoop => "unclosed loop",
+            Self::UnopenedLoop => "loop end without start",
+            Self::ValueOutOfRange => "value does not fit in a cell",
+        })
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at {}:{}", self.kind, self.line, self.column)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Writes an instruction as its name followed by its operands in brackets,
+/// and `@` and its offset if it addresses a cell other than the current one.
+fn write_instruction(text: &mut String, instruction: &Instruction) {
+    let offset = match instruction {
+        Instruction::Increment { offset, .. }
+        | Instruction::SetCell { offset, .. }
+        | Instruction::Output { offset }
+        | Instruction::Print { offset, .. }
+        | Instruction::MultiplyProduct { offset, .. } => *offset,
+        _ => 0,
+    };
+    if let Instruction::MultiplyProduct {
+        factor, multiplier, ..
+    } = instruction
+    {
+        // The factor is an offset, so unlike other operands it can be negative.
+        write!(text, "MultiplyProduct({factor}, {multiplier})").unwrap();
+        if offset != 0 {
+            write!(text, " @ {offset}").unwrap();
+        }
+        return;
+    }
+    let bytes: Vec<u32>;
+    let (name, operands): (&str, &[u32]) = match instruction {
+        Instruction::Forward(offset) => ("Forward", &[*offset]),
+        Instruction::Backward(offset) => ("Backward", &[*offset]),
+        Instruction::Increment { amount, .. } => ("Increment", &[*amount]),
+        Instruction::SetCell { value, .. } => ("SetCell", &[*value]),
+        Instruction::IncrementLoop(value) => ("IncrementLoop", &[*value]),
+        Instruction::MultiplyForward(offset, multiplier) => {
+            ("MultiplyForward", &[*offset, *multiplier])
+        }
+        Instruction::MultiplyBackward(offset, multiplier) => {
+            ("MultiplyBackward", &[*offset, *multiplier])
+        }
+        Instruction::ForwardLoop(offset) => ("ForwardLoop", &[*offset]),
+        Instruction::BackwardLoop(offset) => ("BackwardLoop", &[*offset]),
+        Instruction::IncrementForwardLoop(offset, value) => {
+            ("IncrementForwardLoop", &[*offset, *value])
+        }
+        Instruction::IncrementBackwardLoop(offset, value) => {
+            ("IncrementBackwardLoop", &[*offset, *value])
+        }
+        Instruction::LoopStart(loop_end) => ("LoopStart", &[*loop_end]),
+        Instruction::LoopEnd(loop_start) => ("LoopEnd", &[*loop_start]),
+        Instruction::Output { .. } => ("Output", &[]),
+        Instruction::Print { value, .. } => ("Print", &[u32::from(*value)]),
+        Instruction::OutputBytes(output) => {
+            bytes = output.iter().copied().map(u32::from).collect();
+            ("OutputBytes", &bytes)
+        }
+        Instruction::Input => ("Input", &[]),
+        Instruction::Stop => ("Stop", &[]),
+        Instruction::MultiplyProduct { .. } => unreachable!(),
+    };
+    text.push_str(name);
+    for (index, operand) in operands.iter().enumerate() {
+        text.push_str(if index == 0 { "(" } else { ", " });
+        write!(text, "{operand}").unwrap();
+    }
+    if !operands.is_empty() {
+        text.push(')');
+    }
+    if offset != 0 {
+        write!(text, " @ {offset}").unwrap();
+    }
+}
+
+/// Writes instructions as text, one per line after its index, indented by loop nesting.
+/// Instructions on a cell other than the current one end with `@` and the cell's offset.
+///
+/// With `spans`, each line ends with a comment giving the lines and columns of source it came from.
+#[must_use]
+pub fn dump(instructions: &[Instruction], spans: Option<&[Span]>) -> String {
+    let index_width = instructions.len().saturating_sub(1).to_string().len();
+
+    let mut lines = Vec::with_capacity(instructions.len());
+    let mut depth = 0;
+    for (index, instruction) in instructions.iter().enumerate() {
+        if matches!(instruction, Instruction::LoopEnd(_)) {
+            depth -= 1;
+        }
+        let mut line = format!("{index:>index_width$}  {}", "  ".repeat(depth));
+        write_instruction(&mut line, instruction);
+        if matches!(instruction, Instruction::LoopStart(_)) {
+            depth += 1;
+        }
+        lines.push(line);
+    }
+
+    let Some(spans) = spans else {
+        return lines.into_iter().map(|line| line + "\n").collect();
+    };
+    let width = lines.iter().map(String::len).max().unwrap_or_default();
+    let mut text = String::new();
+    for (line, span) in lines.iter().zip(spans) {
+        let (start, end) = (span.start, span.end);
+        write!(text, "{line:width$}  ; {}:{}", start.line, start.column).unwrap();
+        if end != start {
+            write!(text, "-{}:{}", end.line, end.column).unwrap();
+        }
+        text.push('\n');
+    }
+    text
+}
+
+/// Parses a line's instruction, without its index or comment.
+fn parse_instruction(text: &str) -> Option<Instruction> {
+    let (text, offset) = match text.split_once('@') {
+        Some((text, offset)) => (text.trim_end(), offset.trim().parse().ok()?),
+        None => (text, 0),
+    };
+    let (name, operands) = match text.split_once('(') {
+        Some((name, operands)) => (name.trim_end(), operands.strip_suffix(')')?),
+        None => (text, ""),
+    };
+    let operands = operands
+        .split(',')
+        .map(str::trim)
+        .filter(|operand| !operand.is_empty())
+        .map(str::parse)
+        .collect::<Result<Vec<i64>, _>>()
+        .ok()?;
+    if name == "MultiplyProduct" {
+        let &[factor, multiplier] = operands.as_slice() else {
+            return None;
+        };
+        return Some(Instruction::MultiplyProduct {
+            offset,
+            factor: i32::try_from(factor).ok()?,
+            multiplier: u32::try_from(multiplier).ok()?,
+        });
+    }
+    let operands = operands
+        .into_iter()
+        .map(u32::try_from)
+        .collect::<Result<Vec<u32>, _>>()
+        .ok()?;
+
+    Some(match (name, operands.as_slice()) {
+        ("Forward", &[offset]) => Instruction::Forward(offset),
+        ("Backward", &[offset]) => Instruction::Backward(offset),
+        ("Increment", &[amount]) => Instruction::Increment { offset, amount },
+        ("SetCell", &[value]) => Instruction::SetCell { offset, value },
+        ("Output", &[]) => Instruction::Output { offset },
+        ("Print", &[value]) => Instruction::Print {
+            offset,
+            value: u8::try_from(value).ok()?,
+        },
+        _ if offset != 0 => return None,
+        ("IncrementLoop", &[value]) => Instruction::IncrementLoop(value),
+        ("MultiplyForward", &[offset, multiplier]) => {
+            Instruction::MultiplyForward(offset, multiplier)
+        }
+        ("MultiplyBackward", &[offset, multiplier]) => {
+            Instruction::MultiplyBackward(offset, multiplier)
+        }
+        ("ForwardLoop", &[offset]) => Instruction::ForwardLoop(offset),
+        ("BackwardLoop", &[offset]) => Instruction::BackwardLoop(offset),
+        ("IncrementForwardLoop", &[offset, value]) => {
+            Instruction::IncrementForwardLoop(offset, value)
+        }
+        ("IncrementBackwardLoop", &[offset, value]) => {
+            Instruction::IncrementBackwardLoop(offset, value)
+        }
+        ("OutputBytes", bytes) => Instruction::OutputBytes(
+            bytes
+                .iter()
+                .map(|&byte| u8::try_from(byte).ok())
+                .collect::<Option<_>>()?,
+        ),
+        ("LoopStart", &[] | &[_]) => Instruction::LoopStart(0),
+        ("LoopEnd", &[] | &[_]) => Instruction::LoopEnd(0),
+        ("Input", &[]) => Instruction::Input,
+        ("Stop", &[]) => Instruction::Stop,
+        _ => return None,
+    })
+}
+
+/// Whether `name` is an instruction, to tell unknown names from wrong operands.
+fn is_instruction(name: &str) -> bool {
+    matches!(
+        name,
+        "Forward"
+            | "Backward"
+            | "Increment"
+            | "SetCell"
+            | "IncrementLoop"
+            | "MultiplyForward"
+            | "MultiplyBackward"
+            | "MultiplyProduct"
+            | "ForwardLoop"
+            | "BackwardLoop"
+            | "IncrementForwardLoop"
+            | "IncrementBackwardLoop"
+            | "LoopStart"
+            | "LoopEnd"
+            | "Output"
+            | "Print"
+            | "OutputBytes"
+            | "Input"
+            | "Stop"
+    )
+}
+
+/// Parses instructions written by [`dump`].
+///
+/// Anything after `;` is a comment. Indices and loop targets are worked out from the order
+/// of the lines, so they may be left out or go stale when editing by hand.
+/// A `Stop` is added to the end if it is missing.
+///
+/// # Errors
+///
+/// Returns an error if an instruction is unknown or has the wrong operands, if loops do not match,
+/// or if a value does not fit in a cell of `cell_width`.
+pub fn parse(text: &str, cell_width: CellWidth) -> Result<Vec<Instruction>, ParseError> {
+    let mut instructions = Vec::new();
+    // Line and column of each instruction, for errors found after parsing.
+    let mut positions = Vec::new();
+    let mut loop_stack = Vec::new();
+    for (line_index, line) in text.lines().enumerate() {
+        let line_number = line_index + 1;
+        let line = line.split_once(';').map_or(line, |(code, _)| code);
+        let code = line.trim_start();
+        let code = code.trim_start_matches(|character: char| character.is_ascii_digit());
+        let code = code.trim_start();
+        let column = line[..line.len() - code.len()].chars().count() + 1;
+        let code = code.trim_end();
+        if code.is_empty() {
+            continue;
+        }
+        let error = |kind| ParseError {
+            kind,
+            line: line_number,
+            column,
+        };
+
+        let Some(instruction) = parse_instruction(code) else {
+            let name = code.split(['(', '@']).next().unwrap_or_default().trim_end();
+            return Err(error(if is_instruction(name) {
+                ParseErrorKind::InvalidOperands
+            } else {
+                ParseErrorKind::UnknownInstruction
+            }));
+        };
+        match instruction {
+            Instruction::LoopStart(_) => {
+                loop_stack.push((instructions.len(), (line_number, column)));
+            }
+            Instruction::LoopEnd(_) => {
+                let Some((loop_start, _)) = loop_stack.pop() else {
+                    return Err(error(ParseErrorKind::UnopenedLoop));
+                };
+                instructions[loop_start] = Instruction::LoopStart((instructions.len() + 1) as u32);
+                instructions.push(Instruction::LoopEnd((loop_start + 1) as u32));
+                positions.push((line_number, column));
+                continue;
+            }
+            _ => {}
+        }
+        instructions.push(instruction);
+        positions.push((line_number, column));
+    }
+
+    if let Some(&(_, (line, column))) = loop_stack.last() {
+        return Err(ParseError {
+            kind: ParseErrorKind::UnclosedLoop,
+            line,
+            column,
+        });
+    }
+    if instructions.last() != Some(&Instruction::Stop) {
+        instructions.push(Instruction::Stop);
+    }
+    // Loop targets and the final `Stop` are worked out above, so only values can be wrong.
+    if let Err(LoadError::ValueOutOfRange { index }) = validate(&instructions, cell_width) {
+        let (line, column) = positions[index];
+        return Err(ParseError {
+            kind: ParseErrorKind::ValueOutOfRange,
+            line,
+            column,
+        });
+    }
+    Ok(instructions)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        compile::Compiler,
+        ir::{dump, parse, ParseErrorKind},
+        lexer::lex,
+        settings::CellWidth,
+    };
+
+    #[test]
+    fn dump_works() {
+        let (instructions, spans) = Compiler::new(lex(",>,\n[->+<]<[.>>,]"))
+            .compile_with_spans()
+            .unwrap();
+        assert_eq!(
+            dump(&instructions, Some(&spans)),
+            concat!(
+                " 0  Input                  ; 1:1\n",
+                " 1  Forward(1)             ; 1:2\n",
+                " 2  Input                  ; 1:3\n",
+                " 3  MultiplyForward(1, 1)  ; 2:1-2:6\n",
+                " 4  SetCell(0)             ; 2:1-2:6\n",
+                " 5  Backward(1)            ; 2:7\n",
+                " 6  LoopStart(11)          ; 2:8\n",
+                " 7    Output               ; 2:9\n",
+                " 8    Forward(2)           ; 2:10-2:11\n",
+                " 9    Input                ; 2:12\n",
+                "10  LoopEnd(7)             ; 2:13\n",
+                "11  Stop                   ; 2:13\n",
+            )
+        );
+        assert_eq!(dump(&instructions[..2], None), "0  Input\n1  Forward(1)\n");
+    }
+
+    #[test]
+    fn parse_works() {
+        for code in [
+            include_str!("../samples/Mandelbrot.bf"),
+            include_str!("../samples/TowersOfHanoi.bf"),
+            "++++++++[>++++++++<-]>+.+.,",
+            ",>,>>,[-<<[->+<<+>]>[-<+>]>]",
+        ] {
+            let (instructions, spans) = Compiler::new(lex(code)).compile_with_spans().unwrap();
+            assert_eq!(
+                parse(&dump(&instructions, Some(&spans)), CellWidth::U8),
+                Ok(instructions)
+            );
+        }
+
+        assert_eq!(
+            parse(
+                "
+                ; Loop targets come from nesting.
+                Input
+                LoopStart
+                    MultiplyBackward(2, 255)
+                    SetCell(0)
+                LoopEnd(99)
+                ",
+                CellWidth::U8
+            )
+            .map(|instructions| dump(&instructions, None)),
+            Ok("\
+0  Input
+1  LoopStart(5)
+2    MultiplyBackward(2, 255)
+3    SetCell(0)
+4  LoopEnd(2)
+5  Stop
+"
+            .to_string())
+        );
+    }
+
+    #[test]
+    fn parse_errors() {
+        let kind = |text| {
+            parse(text, CellWidth::U8)
+                .map(|_| ())
+                .map_err(|error| error.kind)
+        };
+        assert_eq!(
+            kind("Output\nJump(3)"),
+            Err(ParseErrorKind::UnknownInstruction)
+        );
+        assert_eq!(kind("Forward"), Err(ParseErrorKind::InvalidOperands));
+        assert_eq!(kind("Output(1)"), Err(ParseErrorKind::InvalidOperands));
+        assert_eq!(kind("SetCell(-1)"), Err(ParseErrorKind::InvalidOperands));
+        assert_eq!(
+            kind("MultiplyProduct(-1)"),
+            Err(ParseErrorKind::InvalidOperands)
+        );
+        assert_eq!(
+            kind("OutputBytes(72, 256)"),
+            Err(ParseErrorKind::InvalidOperands)
+        );
+        assert_eq!(
+            kind("LoopStart\nLoopEnd\nLoopEnd"),
+            Err(ParseErrorKind::UnopenedLoop)
+        );
+
+        let error = parse("Output\nLoopStart\n  Input\n", CellWidth::U8).unwrap_err();
+        assert_eq!(error.kind, ParseErrorKind::UnclosedLoop);
+        assert_eq!((error.line, error.column), (2, 1));
+
+        let error = parse("Input\n1  Increment(256) @ 2", CellWidth::U8).unwrap_err();
+        assert_eq!(error.kind, ParseErrorKind::ValueOutOfRange);
+        assert_eq!((error.line, error.column), (2, 4));
+        assert_eq!(parse("Increment(256)", CellWidth::U16).map(|_| ()), Ok(()));
+        assert_eq!(
+            kind("LoopStart\n  MultiplyForward(1, 300)\nLoopEnd"),
+            Err(ParseErrorKind::ValueOutOfRange)
+        );
+    }
+}
@@ -0,0 +1,736 @@
+use core::ffi::{c_int, c_void};
+use core::{mem, ptr, slice};
+use std::io::{self, stdin, stdout, Read, Write};
+
+use crate::compile::Instruction;
+use crate::interpreter::{read_byte, Cell, Meter, Metered, RuntimeError, RuntimeErrorKind, Tape};
+use crate::settings::{Budget, CellWidth, EofBehavior, Settings};
+
+extern "C" {
+    fn mmap(
+        address: *mut c_void,
+        length: usize,
+        protection: c_int,
+        flags: c_int,
+        file: c_int,
+        offset: i64,
+    ) -> *mut c_void;
+    fn mprotect(address: *mut c_void, length: usize, protection: c_int) -> c_int;
+    fn munmap(address: *mut c_void, length: usize) -> c_int;
+}
+
+const PROT_READ: c_int = 1;
+const PROT_WRITE: c_int = 2;
+const PROT_EXEC: c_int = 4;
+const MAP_PRIVATE: c_int = 2;
+const MAP_ANONYMOUS: c_int = 0x20;
+
+const RAX: u8 = 0;
+const RCX: u8 = 1;
+const RDX: u8 = 2;
+const RBX: u8 = 3;
+const RSI: u8 = 6;
+const RDI: u8 = 7;
+const R12: u8 = 12;
+const R13: u8 = 13;
+const R14: u8 = 14;
+const R15: u8 = 15;
+
+// Registers while running:
+// rbx = start of memory
+// r12 = pointer
+// r13 = memory length in cells
+// r14 = context
+// r15 = steps left before calling `tick`, when the budget is limited
+
+/// State shared between the machine code and the functions it calls.
+///
+/// The machine code reads the first five fields directly, so their order matters.
+#[repr(C)]
+struct Context<'a, C> {
+    memory: *mut C,
+    length: usize,
+    pointer: usize,
+    index: usize,
+    remaining: u64,
+
+    tape: Tape<C>,
+    meter: Metered,
+    eof: EofBehavior,
+    input: &'a mut dyn Read,
+    output: &'a mut dyn Write,
+    error: Option<RuntimeErrorKind>,
+}
+
+const MEMORY: u8 = 0;
+const LENGTH: u8 = 8;
+const POINTER: u8 = 16;
+const INDEX: u8 = 24;
+const REMAINING: u8 = 32;
+
+impl<C: Cell> Context<'_, C> {
+    fn sync(&mut self) {
+        self.memory = self.tape.memory.as_mut_ptr();
+        self.length = self.tape.memory.len();
+        self.pointer = self.tape.pointer;
+    }
+
+    fn fail(&mut self, kind: RuntimeErrorKind) -> u64 {
+        self.error = Some(kind);
+        1
+    }
+}
+
+/// Finds the cell `offset` cells away from `pointer`, which is off the memory, following the tape policy.
+///
+/// Sets `index` to where the cell ended up and `pointer` to where the pointer ended up.
+extern "C" fn resolve<C: Cell>(
+    context: *mut Context<C>,
+    pointer: usize,
+    offset: usize,
+    forward: u64,
+) -> u64 {
+    let context = unsafe { &mut *context };
+    context.tape.pointer = pointer;
+    let resolved = if forward == 0 {
+        context.tape.left(offset)
+    } else {
+        context.tape.right(offset)
+    };
+    match resolved {
+        Ok(index) => {
+            context.sync();
+            context.index = index;
+            0
+        }
+        Err(kind) => context.fail(kind),
+    }
+}
+
+/// Counts a step once the steps in `r15` have run out, refilling `remaining`.
+extern "C" fn tick<C: Cell>(context: *mut Context<C>) -> u64 {
+    let context = unsafe { &mut *context };
+    context.meter.remaining = 0;
+    match context.meter.tick() {
+        Ok(()) => {
+            context.remaining = context.meter.remaining;
+            0
+        }
+        Err(kind) => context.fail(kind),
+    }
+}
+
+extern "C" fn output<C: Cell>(context: *mut Context<C>, byte: u64) -> u64 {
+    let context = unsafe { &mut *context };
+    match context.output.write_all(&[byte as u8]) {
+        Ok(()) => 0,
+        Err(error) => context.fail(RuntimeErrorKind::Io(error)),
+    }
+}
+
+/// Writes the `length` bytes at `bytes`, which belong to an `OutputBytes` instruction.
+extern "C" fn output_bytes<C: Cell>(
+    context: *mut Context<C>,
+    bytes: *const u8,
+    length: usize,
+) -> u64 {
+    let context = unsafe { &mut *context };
+    let bytes = unsafe { slice::from_raw_parts(bytes, length) };
+    match context.output.write_all(bytes) {
+        Ok(()) => 0,
+        Err(error) => context.fail(RuntimeErrorKind::Io(error)),
+    }
+}
+
+extern "C" fn input<C: Cell>(context: *mut Context<C>, pointer: usize) -> u64 {
+    let context = unsafe { &mut *context };
+    match read_byte(&mut context.input, context.eof) {
+        Ok(Some(value)) => {
+            context.tape.memory[pointer] = C::truncate(value);
+            0
+        }
+        Ok(None) => 0,
+        Err(error) => context.fail(RuntimeErrorKind::Io(error)),
+    }
+}
+
+/// Writes x86-64 machine code.
+struct Assembler {
+    code: Vec<u8>,
+
+    /// Bytes per cell.
+    size: u8,
+
+    /// Places that jump to the exit when a call fails.
+    failures: Vec<usize>,
+
+    /// Address of `tick`, if steps are counted.
+    tick: Option<usize>,
+}
+
+impl Assembler {
+    fn emit(&mut self, bytes: &[u8]) {
+        self.code.extend_from_slice(bytes);
+    }
+
+    /// Emits the value with the size of a cell.
+    fn cell_immediate(&mut self, value: u32) {
+        match self.size {
+            1 => self.code.push(value as u8),
+            2 => self.emit(&(value as u16).to_le_bytes()),
+            _ => self.emit(&value.to_le_bytes()),
+        }
+    }
+
+    /// Emits the operand size prefix for 16-bit cells.
+    fn cell_prefix(&mut self) {
+        if self.size == 2 {
+            self.code.push(0x66);
+        }
+    }
+
+    /// Emits `opcode` with `reg` and the cell at `[rbx + index * size]`.
+    fn cell_operand(&mut self, opcode: &[u8], reg: u8, index: u8) {
+        let rex = 0x40 | ((reg >> 3) << 2) | ((index >> 3) << 1);
+        if rex != 0x40 {
+            self.code.push(rex);
+        }
+        self.emit(opcode);
+        self.code.push(((reg & 7) << 3) | 0b100);
+        self.code
+            .push((self.size.trailing_zeros() as u8) << 6 | ((index & 7) << 3) | RBX);
+    }
+
+    /// Emits `opcode` between two 64-bit registers, with `reg` in the `ModRM` reg field.
+    fn registers(&mut self, opcode: u8, reg: u8, rm: u8) {
+        self.emit(&[
+            0x48 | ((reg >> 3) << 2) | (rm >> 3),
+            opcode,
+            0xC0 | ((reg & 7) << 3) | (rm & 7),
+        ]);
+    }
+
+    /// `mov destination, source`
+    fn mov(&mut self, destination: u8, source: u8) {
+        self.registers(0x89, source, destination);
+    }
+
+    /// `mov register, imm64`
+    fn mov_immediate(&mut self, register: u8, value: u64) {
+        self.emit(&[0x48 | (register >> 3), 0xB8 | (register & 7)]);
+        self.emit(&value.to_le_bytes());
+    }
+
+    /// Calls `resolve` for the cell `offset` cells away from the pointer in rsi.
+    fn call_resolve(&mut self, offset: u32, forward: bool, resolve: usize) {
+        self.mov_immediate(RDX, u64::from(offset));
+        self.mov_immediate(RCX, u64::from(forward));
+        self.call(resolve);
+        self.reload();
+    }
+
+    /// Adds or subtracts a constant from a 64-bit register.
+    fn add_offset(&mut self, register: u8, offset: u32, subtract: bool) {
+        if let Ok(offset) = i32::try_from(offset) {
+            // add/sub register, imm32
+            let extension = if subtract { 5 } else { 0 };
+            self.emit(&[
+                0x48 | (register >> 3),
+                0x81,
+                0xC0 | (extension << 3) | (register & 7),
+            ]);
+            self.emit(&offset.to_le_bytes());
+        } else {
+            self.mov_immediate(RCX, u64::from(offset));
+            self.registers(if subtract { 0x29 } else { 0x01 }, RCX, register);
+        }
+    }
+
+    /// `mov register, [r14 + field]`
+    fn load_context(&mut self, register: u8, field: u8) {
+        self.emit(&[
+            0x49 | ((register >> 3) << 2),
+            0x8B,
+            0x46 | ((register & 7) << 3),
+            field,
+        ]);
+    }
+
+    /// `mov [r14 + field], register`
+    fn store_context(&mut self, register: u8, field: u8) {
+        self.emit(&[
+            0x49 | ((register >> 3) << 2),
+            0x89,
+            0x46 | ((register & 7) << 3),
+            field,
+        ]);
+    }
+
+    /// Emits a jump with a 32-bit displacement, returning where to patch it.
+    fn jump(&mut self, opcode: &[u8]) -> usize {
+        self.emit(opcode);
+        self.emit(&[0; 4]);
+        self.code.len() - 4
+    }
+
+    /// Points the jump at `from` to `to`.
+    fn patch(&mut self, from: usize, to: usize) {
+        let displacement = to as i32 - (from as i32 + 4);
+        self.code[from..from + 4].copy_from_slice(&displacement.to_le_bytes());
+    }
+
+    /// Calls `function` with the context as the first argument, leaving on failure.
+    fn call(&mut self, function: usize) {
+        self.mov(RDI, R14);
+        self.mov_immediate(RAX, function as u64);
+        self.emit(&[0xFF, 0xD0]); // call rax
+        self.emit(&[0x48, 0x85, 0xC0]); // test rax, rax
+        let failure = self.jump(&[0x0F, 0x85]); // jnz
+        self.failures.push(failure);
+    }
+
+    /// Reloads the memory after a call that might have moved it.
+    fn reload(&mut self) {
+        self.load_context(RBX, MEMORY);
+        self.load_context(R13, LENGTH);
+        self.load_context(R12, POINTER);
+    }
+
+    /// `add cell, amount` for the cell at `[rbx + index * size]`.
+    fn add_to_cell(&mut self, index: u8, amount: u32) {
+        self.cell_prefix();
+        let opcode = if self.size == 1 { 0x80 } else { 0x81 };
+        self.cell_operand(&[opcode], 0, index);
+        self.cell_immediate(amount);
+    }
+
+    /// `cmp cell, 0`
+    fn compare_cell(&mut self) {
+        self.cell_prefix();
+        self.cell_operand(&[if self.size == 1 { 0x80 } else { 0x83 }], 7, R12);
+        self.code.push(0);
+    }
+
+    /// Loads the cell at `[rbx + index * size]` into eax, zero extended.
+    fn load_cell(&mut self, index: u8) {
+        self.load_cell_into(RAX, index);
+    }
+
+    /// Loads the cell at `[rbx + index * size]` into the 32-bit half of `register`, zero extended.
+    fn load_cell_into(&mut self, register: u8, index: u8) {
+        match self.size {
+            1 => self.cell_operand(&[0x0F, 0xB6], register, index),
+            2 => self.cell_operand(&[0x0F, 0xB7], register, index),
+            _ => self.cell_operand(&[0x8B], register, index),
+        }
+    }
+
+    /// Tests the low bits of eax that fit in a cell.
+    fn test_eax(&mut self) {
+        self.cell_prefix();
+        self.emit(&[if self.size == 1 { 0x84 } else { 0x85 }, 0xC0]);
+    }
+
+    /// Moves the pointer, calling `resolve` when it leaves the memory.
+    fn move_pointer(&mut self, offset: u32, forward: bool, resolve: usize) {
+        self.add_offset(R12, offset, !forward);
+        self.registers(0x39, R13, R12); // cmp r12, r13
+        let inside = self.jump(&[0x0F, 0x82]); // jb
+
+        self.mov(RSI, R12);
+        self.add_offset(RSI, offset, forward);
+        self.call_resolve(offset, forward, resolve);
+        self.load_context(R12, INDEX);
+
+        let here = self.code.len();
+        self.patch(inside, here);
+    }
+
+    /// Puts the index of the cell `offset` cells from the pointer in rdx, calling `resolve`
+    /// when it is off the memory, and gives the register holding the index.
+    fn cell_index(&mut self, offset: i32, resolve: usize) -> u8 {
+        if offset == 0 {
+            return R12;
+        }
+        let (distance, forward) = (offset.unsigned_abs(), offset.is_positive());
+        self.mov(RDX, R12);
+        self.add_offset(RDX, distance, !forward);
+        self.registers(0x39, R13, RDX); // cmp rdx, r13
+        let inside = self.jump(&[0x0F, 0x82]); // jb
+
+        self.mov(RSI, R12);
+        self.call_resolve(distance, forward, resolve);
+        self.load_context(RDX, INDEX);
+
+        let here = self.code.len();
+        self.patch(inside, here);
+        RDX
+    }
+
+    /// Counts a step if the budget is limited.
+    ///
+    /// When `in_eax`, the cell is in eax rather than memory, so is stored before calling `tick`.
+    fn count_step(&mut self, in_eax: bool) {
+        let Some(tick) = self.tick else {
+            return;
+        };
+        self.emit(&[0x49, 0x83, 0xEF, 0x01]); // sub r15, 1
+        let enough = self.jump(&[0x0F, 0x83]); // jae
+        if in_eax {
+            self.store_eax();
+        }
+        self.call(tick);
+        self.load_context(R15, REMAINING);
+        if in_eax {
+            self.load_cell(R12);
+        }
+        let here = self.code.len();
+        self.patch(enough, here);
+    }
+
+    /// Stores the low bits of eax that fit in a cell into the current cell.
+    fn store_eax(&mut self) {
+        self.cell_prefix();
+        let opcode = if self.size == 1 { 0x88 } else { 0x89 };
+        self.cell_operand(&[opcode], RAX, R12);
+    }
+
+    /// Adds the current cell times `multiplier` to the cell `offset` cells away.
+    fn multiply(&mut self, offset: u32, multiplier: u32, forward: bool, resolve: usize) {
+        self.load_cell(R12);
+        self.test_eax();
+        let zero = self.jump(&[0x0F, 0x84]); // jz
+
+        self.mov(RDX, R12);
+        self.add_offset(RDX, offset, !forward);
+        self.registers(0x39, R13, RDX); // cmp rdx, r13
+        let inside = self.jump(&[0x0F, 0x82]); // jb
+
+        self.mov(RSI, R12);
+        self.call_resolve(offset, forward, resolve);
+        self.load_context(RDX, INDEX);
+        self.load_cell(R12);
+
+        let here = self.code.len();
+        self.patch(inside, here);
+        if multiplier != 1 {
+            self.emit(&[0x69, 0xC0]); // imul eax, eax, imm32
+            self.emit(&multiplier.to_le_bytes());
+        }
+        self.cell_prefix();
+        let opcode = match self.size {
+            1 => 0x00,
+            _ => 0x01,
+        };
+        self.cell_operand(&[opcode], RAX, RDX);
+
+        let here = self.code.len();
+        self.patch(zero, here);
+    }
+
+    /// Adds the current cell times the cell `factor` cells away times `multiplier` to the cell
+    /// `offset` cells away.
+    fn multiply_product(&mut self, offset: i32, factor: i32, multiplier: u32, resolve: usize) {
+        self.load_cell(R12);
+        self.test_eax();
+        let zero = self.jump(&[0x0F, 0x84]); // jz
+
+        // Grow the memory to both cells first, as that can move it and clobber registers.
+        self.cell_index(factor, resolve);
+        self.cell_index(offset, resolve);
+
+        self.load_cell(R12);
+        let index = self.cell_index(factor, resolve);
+        self.load_cell_into(RCX, index);
+        self.emit(&[0x0F, 0xAF, 0xC1]); // imul eax, ecx
+        if multiplier != 1 {
+            self.emit(&[0x69, 0xC0]); // imul eax, eax, imm32
+            self.emit(&multiplier.to_le_bytes());
+        }
+        let index = self.cell_index(offset, resolve);
+        self.cell_prefix();
+        let opcode = match self.size {
+            1 => 0x00,
+            _ => 0x01,
+        };
+        self.cell_operand(&[opcode], RAX, index);
+
+        let here = self.code.len();
+        self.patch(zero, here);
+    }
+}
+
+/// Compiles instructions into machine code for cells of type `C`.
+///
+/// Steps are only counted when `metered`.
+#[allow(clippy::too_many_lines)]
+fn assemble<C: Cell>(instructions: &[Instruction], metered: bool) -> Vec<u8> {
+    let resolve = resolve::<C> as *const () as usize;
+    let output = output::<C> as *const () as usize;
+    let output_bytes = output_bytes::<C> as *const () as usize;
+    let input = input::<C> as *const () as usize;
+
+    let mut assembler = Assembler {
+        code: Vec::with_capacity(instructions.len() * 16),
+        size: mem::size_of::<C>() as u8,
+        failures: Vec::new(),
+        tick: metered.then_some(tick::<C> as *const () as usize),
+    };
+
+    // push rbp, rbx, r12, r13, r14, r15, then keep the stack 16 byte aligned for calls.
+    assembler.emit(&[0x55, 0x53, 0x41, 0x54, 0x41, 0x55, 0x41, 0x56, 0x41, 0x57]);
+    assembler.emit(&[0x48, 0x83, 0xEC, 0x08]);
+    assembler.mov(R14, RDI);
+    assembler.reload();
+    assembler.load_context(R15, REMAINING);
+
+    let mut loop_stack = Vec::new();
+    for instruction in instructions {
+        if !matches!(instruction, Instruction::Stop) {
+            assembler.count_step(false);
+        }
+        match *instruction {
+            Instruction::Forward(offset) => assembler.move_pointer(offset, true, resolve),
+            Instruction::Backward(offset) => assembler.move_pointer(offset, false, resolve),
+
+            Instruction::Increment { offset, amount } => {
+                let index = assembler.cell_index(offset, resolve);
+                assembler.add_to_cell(index, amount);
+            }
+            Instruction::SetCell { offset, value } => {
+                let index = assembler.cell_index(offset, resolve);
+                assembler.cell_prefix();
+                let opcode = if assembler.size == 1 { 0xC6 } else { 0xC7 };
+                assembler.cell_operand(&[opcode], 0, index);
+                assembler.cell_immediate(value);
+            }
+
+            Instruction::LoopStart(_) => {
+                assembler.compare_cell();
+                let exit = assembler.jump(&[0x0F, 0x84]); // jz
+                loop_stack.push(exit);
+            }
+            Instruction::LoopEnd(_) => {
+                let exit = loop_stack.pop().unwrap();
+                assembler.compare_cell();
+                let body = assembler.jump(&[0x0F, 0x85]); // jnz
+                assembler.patch(body, exit + 4);
+                let here = assembler.code.len();
+                assembler.patch(exit, here);
+            }
+
+            Instruction::IncrementLoop(increment) => {
+                assembler.load_cell(R12);
+                let start = assembler.code.len();
+                assembler.test_eax();
+                let done = assembler.jump(&[0x0F, 0x84]); // jz
+                assembler.count_step(true);
+                assembler.code.push(0x05); // add eax, imm32
+                assembler.emit(&increment.to_le_bytes());
+                let again = assembler.jump(&[0xE9]); // jmp
+                assembler.patch(again, start);
+                let here = assembler.code.len();
+                assembler.patch(done, here);
+                assembler.store_eax();
+            }
+
+            Instruction::MultiplyForward(offset, multiplier) => {
+                assembler.multiply(offset, multiplier, true, resolve);
+            }
+            Instruction::MultiplyBackward(offset, multiplier) => {
+                assembler.multiply(offset, multiplier, false, resolve);
+            }
+            Instruction::MultiplyProduct {
+                offset,
+                factor,
+                multiplier,
+            } => assembler.multiply_product(offset, factor, multiplier, resolve),
+
+            Instruction::ForwardLoop(offset)
+            | Instruction::BackwardLoop(offset)
+            | Instruction::IncrementForwardLoop(offset, _)
+            | Instruction::IncrementBackwardLoop(offset, _) => {
+                let start = assembler.code.len();
+                assembler.compare_cell();
+                let done = assembler.jump(&[0x0F, 0x84]); // jz
+                let forward = matches!(
+                    instruction,
+                    Instruction::ForwardLoop(_) | Instruction::IncrementForwardLoop(..)
+                );
+                assembler.count_step(false);
+                if let Instruction::IncrementForwardLoop(_, amount)
+                | Instruction::IncrementBackwardLoop(_, amount) = *instruction
+                {
+                    assembler.add_to_cell(R12, amount);
+                }
+                assembler.move_pointer(offset, forward, resolve);
+                let again = assembler.jump(&[0xE9]); // jmp
+                assembler.patch(again, start);
+                let here = assembler.code.len();
+                assembler.patch(done, here);
+            }
+
+            Instruction::Output { offset } => {
+                let index = assembler.cell_index(offset, resolve);
+                assembler.load_cell(index);
+                assembler.emit(&[0x89, 0xC6]); // mov esi, eax
+                assembler.call(output);
+            }
+            Instruction::Print { value, .. } => {
+                assembler.mov_immediate(RSI, u64::from(value));
+                assembler.call(output);
+            }
+            // The instructions outlive the machine code, so it can point at their bytes.
+            Instruction::OutputBytes(ref bytes) => {
+                assembler.mov_immediate(RSI, bytes.as_ptr() as u64);
+                assembler.mov_immediate(RDX, bytes.len() as u64);
+                assembler.call(output_bytes);
+            }
+            Instruction::Input => {
+                assembler.mov(RSI, R12);
+                assembler.call(input);
+            }
+
+            Instruction::Stop => break,
+        }
+    }
+
+    assembler.emit(&[0x31, 0xC0]); // xor eax, eax
+    let exit = assembler.code.len();
+    for failure in mem::take(&mut assembler.failures) {
+        assembler.patch(failure, exit);
+    }
+    assembler.store_context(R12, POINTER);
+    assembler.emit(&[0x48, 0x83, 0xC4, 0x08]);
+    // pop r15, r14, r13, r12, rbx, rbp, then return.
+    assembler.emit(&[
+        0x41, 0x5F, 0x41, 0x5E, 0x41, 0x5D, 0x41, 0x5C, 0x5B, 0x5D, 0xC3,
+    ]);
+
+    assembler.code
+}
+
+/// Memory mapped as executable, unmapped when dropped.
+struct Executable {
+    address: *mut c_void,
+    length: usize,
+}
+
+impl Executable {
+    fn new(code: &[u8]) -> io::Result<Self> {
+        let length = code.len().next_multiple_of(4096);
+        let address = unsafe {
+            mmap(
+                ptr::null_mut(),
+                length,
+                PROT_READ | PROT_WRITE,
+                MAP_PRIVATE | MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        if address as isize == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        let executable = Self { address, length };
+
+        unsafe {
+            ptr::copy_nonoverlapping(code.as_ptr(), address.cast::<u8>(), code.len());
+            if mprotect(address, length, PROT_READ | PROT_EXEC) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(executable)
+    }
+}
+
+impl Drop for Executable {
+    fn drop(&mut self) {
+        unsafe {
+            munmap(self.address, self.length);
+        }
+    }
+}
+
+/// Compiles instructions into x86-64 machine code and runs it, reading from stdin and writing to stdout.
+///
+/// # Panics
+///
+/// Panics if the program stops early, see [`execute_with`].
+#[must_use]
+pub fn execute(instructions: &[Instruction], settings: &Settings) -> Vec<u32> {
+    execute_with(instructions, settings, stdin().lock(), stdout().lock()).unwrap()
+}
+
+/// Compiles instructions into x86-64 machine code and runs it, reading from `input` and writing to `output`.
+///
+/// Behaves the same as [`crate::interpreter::execute_with`].
+///
+/// # Errors
+///
+/// Returns an error if the machine code cannot be made executable, if reading from `input` or
+/// writing to `output` fails, or if the pointer moves off the tape when the tape policy does not
+/// allow it.
+///
+/// # Panics
+///
+/// Panics if the tape policy or tape limit has a size of 0.
+pub fn execute_with(
+    instructions: &[Instruction],
+    settings: &Settings,
+    mut input: impl Read,
+    mut output: impl Write,
+) -> Result<Vec<u32>, RuntimeError> {
+    match settings.cell_width {
+        CellWidth::U8 => execute_as::<u8>(instructions, settings, &mut input, &mut output),
+        CellWidth::U16 => execute_as::<u16>(instructions, settings, &mut input, &mut output),
+        CellWidth::U32 => execute_as::<u32>(instructions, settings, &mut input, &mut output),
+    }
+}
+
+fn execute_as<C: Cell>(
+    instructions: &[Instruction],
+    settings: &Settings,
+    input: &mut dyn Read,
+    output: &mut dyn Write,
+) -> Result<Vec<u32>, RuntimeError> {
+    let mut context = Context {
+        memory: ptr::null_mut(),
+        length: 0,
+        pointer: 0,
+        index: 0,
+        remaining: 0,
+        tape: Tape::<C>::new(settings),
+        meter: Metered::new(settings.budget),
+        eof: settings.eof,
+        input,
+        output,
+        error: None,
+    };
+    context.sync();
+
+    let result = match Executable::new(&assemble::<C>(
+        instructions,
+        settings.budget != Budget::default(),
+    )) {
+        Ok(executable) => {
+            let function: extern "C" fn(*mut Context<C>) -> u64 =
+                unsafe { mem::transmute(executable.address) };
+            if function(&raw mut context) == 0 {
+                Ok(())
+            } else {
+                Err(context.error.take().unwrap())
+            }
+        }
+        Err(error) => Err(RuntimeErrorKind::Io(error)),
+    };
+    // Flush even when stopping early, so output up to the error is not lost.
+    let flushed = context.output.flush();
+    let result = result.and_then(|()| Ok(flushed?));
+
+    let memory = context.tape.memory.into_iter().map(Cell::widen).collect();
+    match result {
+        Ok(()) => Ok(memory),
+        Err(kind) => Err(RuntimeError { kind, memory }),
+    }
+}
@@ -29,21 +29,51 @@ pub enum Token {
     Comment,
 }
 
-/// Maps Brainfuck into tokens.
-pub fn lex(input: &str) -> impl Iterator<Item = Token> + '_ {
-    let mapped = input.chars().map(|character| match character {
-        '+' => Token::Increment,
-        '-' => Token::Decrement,
+/// Location of a character in the source.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Position {
+    /// Byte offset from the start of the source.
+    pub offset: usize,
 
-        '>' => Token::Forward,
-        '<' => Token::Backward,
+    /// Line number, starting at 1.
+    pub line: usize,
 
-        '[' => Token::LoopStart,
-        ']' => Token::LoopEnd,
+    /// Column number in characters, starting at 1.
+    pub column: usize,
+}
+
+/// Maps Brainfuck into tokens, along with where each token is in the source.
+pub fn lex(input: &str) -> impl Iterator<Item = (Position, Token)> + '_ {
+    let mut line = 1;
+    let mut column = 0;
+
+    let mapped = input.char_indices().map(move |(offset, character)| {
+        column += 1;
+        let position = Position {
+            offset,
+            line,
+            column,
+        };
+        if character == '\n' {
+            line += 1;
+            column = 0;
+        }
+
+        let token = match character {
+            '+' => Token::Increment,
+            '-' => Token::Decrement,
+
+            '>' => Token::Forward,
+            '<' => Token::Backward,
+
+            '[' => Token::LoopStart,
+            ']' => Token::LoopEnd,
 
-        '.' => Token::Output,
-        ',' => Token::Input,
-        _ => Token::Comment,
+            '.' => Token::Output,
+            ',' => Token::Input,
+            _ => Token::Comment,
+        };
+        (position, token)
     });
 
     mapped
@@ -5,23 +5,61 @@
 #![warn(missing_docs)]
 #![allow(clippy::cast_possible_truncation)]
 #![allow(clippy::cast_sign_loss)]
+#![allow(clippy::cast_possible_wrap)]
 #![allow(clippy::similar_names)]
 
+/// Settings shared by the interpreter and transpilers.
+pub mod settings;
+
 /// Compile Brainfuck into tokens.
 pub mod lexer;
 
 /// Compile tokens into instructions.
 pub mod compile;
 
+/// Save compiled instructions as bytecode and load them back.
+pub mod bytecode;
+
+/// Write instructions as text and read them back.
+pub mod ir;
+
+/// Find out what instructions do without running them.
+pub mod analysis;
+
 /// Interpret instructions.
 pub mod interpreter;
 
+/// Step through instructions, with breakpoints and tape inspection.
+pub mod debugger;
+
 /// Compile instructions to Rust.
 pub mod into_rust;
 
 /// Compile instructions to C.
 pub mod into_c;
 
+/// Compile instructions to WebAssembly text.
+pub mod into_wasm;
+
+/// Compile instructions to x86-64 assembly.
+pub mod into_asm;
+
+/// Compile instructions to LLVM IR.
+pub mod into_llvm;
+
+/// Compile instructions to JavaScript.
+pub mod into_js;
+
+/// Compile instructions to Python.
+pub mod into_python;
+
+/// Compile instructions back to Brainfuck.
+pub mod into_brainfuck;
+
+/// Compile instructions to x86-64 machine code and run them.
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+pub mod jit;
+
 /// Initial memory capacity, however memory will resize when full.
 pub const INITIAL_MEMORY_CAPACITY: usize = 32;
 
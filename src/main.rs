@@ -1,17 +1,677 @@
 #![deny(clippy::all)]
 #![warn(clippy::pedantic)]
 
+use std::env;
+use std::fmt;
 use std::fs;
-use std::io::{stdin, stdout, BufRead, Write};
+use std::io::{stdin, stdout, BufRead, BufReader, Read, Write};
+use std::path::Path;
+use std::process::ExitCode;
+use std::time::Duration;
 
-use brainfuck::compile::Compiler;
-use brainfuck::interpreter::execute;
+use brainfuck::analysis::analyse;
+use brainfuck::bytecode::{self, from_bytecode, to_bytecode};
+use brainfuck::compile::{CompileError, Compiler, Instruction, Span};
+use brainfuck::debugger::{Debugger, Event};
+use brainfuck::interpreter::{execute_with, RuntimeErrorKind};
+use brainfuck::ir;
 use brainfuck::lexer::lex;
+use brainfuck::settings::{Budget, CellWidth, EofBehavior, Optimisations, Settings, TapePolicy};
 
+use brainfuck::into_asm::to_asm;
+use brainfuck::into_brainfuck::{to_brainfuck, to_trimmed_brainfuck};
 use brainfuck::into_c::to_c;
+use brainfuck::into_js::to_js;
+use brainfuck::into_llvm::to_llvm;
+use brainfuck::into_python::to_python;
 use brainfuck::into_rust::to_rust;
+use brainfuck::into_wasm::to_wasm;
 
-fn main() {
+const USAGE: &str = "Usage: brainfuck <COMMAND> [OPTIONS] [FILE]
+
+Commands:
+  run        Interpret the program
+  jit        Compile the program to x86-64 machine code and run it
+  debug      Step through the program, stopping at breakpoints and `#`
+  ir         Print the optimised instructions, which `.ir` files are read as
+  compile    Save the compiled program as `.bfc` bytecode
+  to-rust    Transpile the program into Rust
+  to-c       Transpile the program into C
+  to-wasm    Transpile the program into WebAssembly text
+  to-asm     Transpile the program into x86-64 assembly for Linux
+  to-llvm    Transpile the program into LLVM IR
+  to-js      Transpile the program into a JavaScript module
+  to-python  Transpile the program into a Python 3 script
+  minify     Rewrite the program as optimised Brainfuck without comments
+  analyse    Report on the program's loops, tape use and input without running it
+
+Options:
+  -e <CODE>            Use CODE as the program instead of reading FILE
+  -o <PATH>            Write transpiled code to PATH instead of stdout
+  -i <PATH>            Read program input from PATH instead of stdin
+  --json               Write the `analyse` report as JSON
+  --trim-end           Leave out cells `minify` would set at the end, which
+                       nothing reads
+  --eof <MODE>         What `,` does at end of input: unchanged (default), zero or max
+  --cell-width <BITS>  Bits per cell: 8 (default), 16 or 32
+  --tape <POLICY>      What happens at the ends of the tape: grow (default),
+                       bidirectional, wrap:SIZE or fixed:SIZE
+  --tape-limit <CELLS> Most cells a growing tape may have (default unlimited)
+  --max-steps <STEPS>  Stop `run`, `jit` and `debug` after STEPS steps
+  --timeout <SECONDS>  Stop `run`, `jit` and `debug` after SECONDS seconds
+  -O0, -O1, -O2        Optimise nothing, only runs and clear and scan loops,
+                       or everything (default)
+  --disable <PASS>     Turn off one optimisation: merge-runs, clear-loops,
+                       scan-loops, known-cells, multiply-loops, fold-moves
+                       or evaluate-prefix
+  -h, --help           Print this help
+
+Run without arguments for interactive mode.";
+
+const DEBUG_HELP: &str = "Commands:
+  s, step [N]           Run N instructions (default 1)
+  c, continue           Run until a breakpoint, a watched cell changes or the program ends
+  b, break LINE[:COL]   Stop before the instruction at LINE and COL
+  w, watch CELL         Stop when CELL changes
+  t, tape [RADIUS]      Show RADIUS cells either side of the pointer (default 8)
+  l, where              Show the next instruction
+  q, quit               Stop debugging
+  h, help               Print this help
+
+Program input is empty unless given with `-i`.";
+
+enum Command {
+    Run,
+    Jit,
+    Debug,
+    Ir,
+    Compile,
+    ToRust,
+    ToC,
+    ToWasm,
+    ToAsm,
+    ToLlvm,
+    ToJs,
+    ToPython,
+    Minify,
+    Analyse,
+    Help,
+}
+
+enum Source {
+    File(String),
+    Code(String),
+}
+
+struct Arguments {
+    command: Command,
+    source: Option<Source>,
+    output: Option<String>,
+    input: Option<String>,
+    json: bool,
+    trim_end: bool,
+    settings: Settings,
+}
+
+#[allow(clippy::too_many_lines)]
+fn parse_arguments(mut arguments: impl Iterator<Item = String>) -> Result<Arguments, String> {
+    let command = match arguments.next().as_deref() {
+        Some("run") => Command::Run,
+        Some("jit") => Command::Jit,
+        Some("debug") => Command::Debug,
+        Some("ir") => Command::Ir,
+        Some("compile") => Command::Compile,
+        Some("to-rust") => Command::ToRust,
+        Some("to-c") => Command::ToC,
+        Some("to-wasm") => Command::ToWasm,
+        Some("to-asm") => Command::ToAsm,
+        Some("to-llvm") => Command::ToLlvm,
+        Some("to-js") => Command::ToJs,
+        Some("to-python") => Command::ToPython,
+        Some("minify") => Command::Minify,
+        Some("analyse") => Command::Analyse,
+        Some("-h" | "--help" | "help") => Command::Help,
+        Some(other) => return Err(format!("unknown command `{other}`")),
+        None => return Err("missing command".to_string()),
+    };
+
+    let mut parsed = Arguments {
+        command,
+        source: None,
+        output: None,
+        input: None,
+        json: false,
+        trim_end: false,
+        settings: Settings::default(),
+    };
+
+    while let Some(argument) = arguments.next() {
+        let mut value = |flag: &str| {
+            arguments
+                .next()
+                .ok_or_else(|| format!("`{flag}` expects a value"))
+        };
+        match argument.as_str() {
+            "-h" | "--help" => parsed.command = Command::Help,
+            "-e" => {
+                if parsed.source.is_some() {
+                    return Err("more than one program given".to_string());
+                }
+                parsed.source = Some(Source::Code(value("-e")?));
+            }
+            "-o" => parsed.output = Some(value("-o")?),
+            "-i" => parsed.input = Some(value("-i")?),
+            "--json" => parsed.json = true,
+            "--trim-end" => parsed.trim_end = true,
+            "--eof" => {
+                parsed.settings.eof = match value("--eof")?.as_str() {
+                    "unchanged" => EofBehavior::Unchanged,
+                    "zero" => EofBehavior::Zero,
+                    "max" => EofBehavior::Max,
+                    other => return Err(format!("unknown end of input behaviour `{other}`")),
+                }
+            }
+            "--cell-width" => {
+                parsed.settings.cell_width = match value("--cell-width")?.as_str() {
+                    "8" => CellWidth::U8,
+                    "16" => CellWidth::U16,
+                    "32" => CellWidth::U32,
+                    other => return Err(format!("unsupported cell width `{other}`")),
+                }
+            }
+            "--tape" => parsed.settings.tape = parse_tape(&value("--tape")?)?,
+            "--tape-limit" => {
+                let cells = value("--tape-limit")?;
+                parsed.settings.tape_limit = match cells.parse() {
+                    Ok(0) | Err(_) => return Err(format!("invalid tape limit `{cells}`")),
+                    Ok(cells) => Some(cells),
+                };
+            }
+            "--max-steps" => {
+                let steps = value("--max-steps")?;
+                parsed.settings.budget.max_steps = Some(
+                    steps
+                        .parse()
+                        .map_err(|_| format!("invalid number of steps `{steps}`"))?,
+                );
+            }
+            "--timeout" => {
+                let seconds = value("--timeout")?;
+                parsed.settings.budget.timeout = Some(
+                    seconds
+                        .parse()
+                        .ok()
+                        .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+                        .ok_or_else(|| format!("invalid timeout `{seconds}`"))?,
+                );
+            }
+            "-O0" => parsed.settings.optimisations = Optimisations::level(0),
+            "-O1" => parsed.settings.optimisations = Optimisations::level(1),
+            "-O2" => parsed.settings.optimisations = Optimisations::level(2),
+            "--disable" => {
+                let optimisations = &mut parsed.settings.optimisations;
+                match value("--disable")?.as_str() {
+                    "merge-runs" => optimisations.merge_runs = false,
+                    "clear-loops" => optimisations.clear_loops = false,
+                    "scan-loops" => optimisations.scan_loops = false,
+                    "known-cells" => optimisations.known_cells = false,
+                    "multiply-loops" => optimisations.multiply_loops = false,
+                    "fold-moves" => optimisations.fold_moves = false,
+                    "evaluate-prefix" => optimisations.evaluate_prefix = false,
+                    other => return Err(format!("unknown optimisation `{other}`")),
+                }
+            }
+            flag if flag.starts_with('-') && flag != "-" => {
+                return Err(format!("unknown option `{flag}`"));
+            }
+            _ => {
+                if parsed.source.is_some() {
+                    return Err("more than one program given".to_string());
+                }
+                parsed.source = Some(Source::File(argument));
+            }
+        }
+    }
+
+    if !matches!(parsed.command, Command::Run | Command::Jit | Command::Debug)
+        && parsed.settings.budget != Budget::default()
+    {
+        return Err(
+            "`--max-steps` and `--timeout` only apply to `run`, `jit` and `debug`".to_string(),
+        );
+    }
+
+    if parsed.json && !matches!(parsed.command, Command::Analyse) {
+        return Err("`--json` only applies to `analyse`".to_string());
+    }
+    if parsed.trim_end && !matches!(parsed.command, Command::Minify) {
+        return Err("`--trim-end` only applies to `minify`".to_string());
+    }
+
+    Ok(parsed)
+}
+
+fn parse_tape(policy: &str) -> Result<TapePolicy, String> {
+    let size = |size: &str| match size.parse() {
+        Ok(0) | Err(_) => Err(format!("invalid tape size `{size}`")),
+        Ok(size) => Ok(size),
+    };
+    match policy.split_once(':') {
+        None if policy == "grow" => Ok(TapePolicy::Grow),
+        None if policy == "bidirectional" => Ok(TapePolicy::Bidirectional),
+        Some(("wrap", size_text)) => Ok(TapePolicy::Wrap(size(size_text)?)),
+        Some(("fixed", size_text)) => Ok(TapePolicy::Fixed(size(size_text)?)),
+        _ => Err(format!("unknown tape policy `{policy}`")),
+    }
+}
+
+fn write_output(path: Option<&str>, code: impl AsRef<[u8]>) -> Result<(), String> {
+    if let Some(path) = path {
+        fs::write(path, code).map_err(|error| format!("could not write `{path}`: {error}"))
+    } else {
+        let mut stdout = stdout().lock();
+        stdout
+            .write_all(code.as_ref())
+            .and_then(|()| stdout.flush())
+            .map_err(|error| format!("could not write to stdout: {error}"))
+    }
+}
+
+fn open_input(path: Option<&str>) -> Result<Box<dyn Read>, String> {
+    match path {
+        Some(path) => fs::File::open(path)
+            .map(|file| Box::new(BufReader::new(file)) as Box<dyn Read>)
+            .map_err(|error| format!("could not read `{path}`: {error}")),
+        None => Ok(Box::new(stdin().lock())),
+    }
+}
+
+/// Formats a compile error with the offending line and a caret under the bracket.
+fn diagnostic(error: &CompileError, name: &str, source: &str) -> String {
+    caret(
+        &error.kind,
+        name,
+        source,
+        error.position.line,
+        error.position.column,
+    )
+}
+
+/// Formats `message` with line `line` of `source` and a caret under column `column`.
+fn caret(
+    message: &impl fmt::Display,
+    name: &str,
+    source: &str,
+    line_number: usize,
+    column: usize,
+) -> String {
+    let line = source.lines().nth(line_number - 1).unwrap_or_default();
+    let number = line_number.to_string();
+    let gutter = " ".repeat(number.len());
+    let padding: String = line
+        .chars()
+        .take(column - 1)
+        .map(|character| if character == '\t' { '\t' } else { ' ' })
+        .collect();
+
+    format!(
+        "{message}\n{gutter}--> {name}:{line_number}:{column}\n{gutter} |\n{number} | {line}\n{gutter} | {padding}^"
+    )
+}
+
+/// A program ready to run.
+struct Program<'a> {
+    name: &'a str,
+    instructions: Vec<Instruction>,
+
+    /// The source and spans, unless the program was loaded from bytecode or IR.
+    source: Option<(String, Vec<Span>)>,
+}
+
+/// Compiles the program, or loads it from bytecode or IR.
+fn load(arguments: &Arguments) -> Result<Program<'_>, String> {
+    let (name, bytes) = match &arguments.source {
+        Some(Source::File(path)) => (
+            path.as_str(),
+            fs::read(path).map_err(|error| format!("could not read `{path}`: {error}"))?,
+        ),
+        Some(Source::Code(code)) => ("<code>", code.clone().into_bytes()),
+        None => return Err("no program given, pass a FILE or `-e CODE`".to_string()),
+    };
+
+    if bytes.starts_with(&bytecode::MAGIC) {
+        let (instructions, cell_width) =
+            from_bytecode(&bytes).map_err(|error| format!("could not load `{name}`: {error}"))?;
+        if cell_width != arguments.settings.cell_width {
+            return Err(format!(
+                "`{name}` was compiled for {}-bit cells, pass `--cell-width {}`",
+                cell_width.bits(),
+                cell_width.bits()
+            ));
+        }
+        return Ok(Program {
+            name,
+            instructions,
+            source: None,
+        });
+    }
+
+    let source = String::from_utf8(bytes)
+        .map_err(|_| format!("could not read `{name}`: stream did not contain valid UTF-8"))?;
+    if Path::new(name)
+        .extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("ir"))
+    {
+        let instructions = ir::parse(&source, arguments.settings.cell_width)
+            .map_err(|error| caret(&error.kind, name, &source, error.line, error.column))?;
+        return Ok(Program {
+            name,
+            instructions,
+            source: None,
+        });
+    }
+    let (instructions, spans) = Compiler::new(lex(&source))
+        .with_cell_width(arguments.settings.cell_width)
+        .with_tape(arguments.settings.tape)
+        .with_tape_limit(arguments.settings.tape_limit)
+        .with_budget(arguments.settings.budget)
+        .with_optimisations(arguments.settings.optimisations)
+        .compile_with_spans()
+        .map_err(|error| diagnostic(&error, name, &source))?;
+    Ok(Program {
+        name,
+        instructions,
+        source: Some((source, spans)),
+    })
+}
+
+fn run(arguments: &Arguments) -> Result<(), String> {
+    let Program {
+        name,
+        instructions,
+        source,
+    } = load(arguments)?;
+
+    match arguments.command {
+        Command::Run => execute_with(
+            &instructions,
+            &arguments.settings,
+            open_input(arguments.input.as_deref())?,
+            stdout().lock(),
+        )
+        .map(|_| ())
+        .map_err(|error| error.to_string()),
+        #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+        Command::Jit => brainfuck::jit::execute_with(
+            &instructions,
+            &arguments.settings,
+            open_input(arguments.input.as_deref())?,
+            stdout().lock(),
+        )
+        .map(|_| ())
+        .map_err(|error| error.to_string()),
+        #[cfg(not(all(target_arch = "x86_64", target_os = "linux")))]
+        Command::Jit => Err("the JIT only supports x86-64 Linux".to_string()),
+        Command::Debug => {
+            let Some((program, _)) = source else {
+                return Err("`debug` needs Brainfuck source, not bytecode or IR".to_string());
+            };
+            let input: Box<dyn Read> = match &arguments.input {
+                Some(path) => open_input(Some(path))?,
+                None => Box::new(&b""[..]),
+            };
+            let debugger = Debugger::new(&program, &arguments.settings, input, stdout())
+                .map_err(|error| diagnostic(&error, name, &program))?;
+            debug(debugger, &program)
+        }
+        Command::Ir => write_output(
+            arguments.output.as_deref(),
+            ir::dump(
+                &instructions,
+                source.as_ref().map(|(_, spans)| spans.as_slice()),
+            ),
+        ),
+        Command::Compile => write_output(
+            arguments.output.as_deref(),
+            to_bytecode(&instructions, arguments.settings.cell_width)
+                .map_err(|error| format!("could not compile `{name}`: {error}"))?,
+        ),
+        Command::ToRust => write_output(
+            arguments.output.as_deref(),
+            to_rust(&instructions, &arguments.settings),
+        ),
+        Command::ToC => write_output(
+            arguments.output.as_deref(),
+            to_c(&instructions, &arguments.settings),
+        ),
+        Command::ToWasm => write_output(
+            arguments.output.as_deref(),
+            to_wasm(&instructions, &arguments.settings),
+        ),
+        Command::ToAsm => write_output(
+            arguments.output.as_deref(),
+            to_asm(&instructions, &arguments.settings),
+        ),
+        Command::ToLlvm => write_output(
+            arguments.output.as_deref(),
+            to_llvm(&instructions, &arguments.settings),
+        ),
+        Command::ToJs => write_output(
+            arguments.output.as_deref(),
+            to_js(&instructions, &arguments.settings),
+        ),
+        Command::ToPython => write_output(
+            arguments.output.as_deref(),
+            to_python(&instructions, &arguments.settings),
+        ),
+        Command::Minify => write_output(
+            arguments.output.as_deref(),
+            minify(arguments, name, &instructions, source.as_ref())?,
+        ),
+        Command::Analyse => write_output(
+            arguments.output.as_deref(),
+            analysis(arguments, name, &instructions, source.as_ref())?,
+        ),
+        Command::Help => unreachable!(),
+    }
+}
+
+/// Minifies the program, also without running its start if there is source to compile again.
+fn minify(
+    arguments: &Arguments,
+    name: &str,
+    instructions: &[Instruction],
+    source: Option<&(String, Vec<Span>)>,
+) -> Result<String, String> {
+    let settings = &arguments.settings;
+    let emit = if arguments.trim_end {
+        to_trimmed_brainfuck
+    } else {
+        to_brainfuck
+    };
+    let mut minified = emit(instructions, settings);
+    // Cells the start of the program leaves can take longer to write than the loops that
+    // set them, so it is also minified without running the start, keeping the shorter.
+    if let Some((program, _)) = source {
+        let unevaluated = Settings {
+            optimisations: Optimisations {
+                evaluate_prefix: false,
+                ..settings.optimisations
+            },
+            ..*settings
+        };
+        if unevaluated != *settings {
+            let instructions = Compiler::new(lex(program))
+                .with_cell_width(unevaluated.cell_width)
+                .with_tape(unevaluated.tape)
+                .with_tape_limit(unevaluated.tape_limit)
+                .with_optimisations(unevaluated.optimisations)
+                .compile()
+                .map_err(|error| diagnostic(&error, name, program))?;
+            let shorter = emit(&instructions, &unevaluated);
+            if shorter.len() < minified.len() {
+                minified = shorter;
+            }
+        }
+    }
+    Ok(minified)
+}
+
+/// Analyses the program, compiling source again without the passes that run its start, leave out
+/// loops or turn them into multiplications, so the report is about the loops as written.
+fn analysis(
+    arguments: &Arguments,
+    name: &str,
+    instructions: &[Instruction],
+    source: Option<&(String, Vec<Span>)>,
+) -> Result<String, String> {
+    let settings = &arguments.settings;
+    let (report, spans) = match source {
+        Some((program, _)) => {
+            let optimisations = Optimisations {
+                evaluate_prefix: false,
+                known_cells: false,
+                multiply_loops: false,
+                ..settings.optimisations
+            };
+            let (instructions, spans) = Compiler::new(lex(program))
+                .with_cell_width(settings.cell_width)
+                .with_tape(settings.tape)
+                .with_tape_limit(settings.tape_limit)
+                .with_optimisations(optimisations)
+                .compile_with_spans()
+                .map_err(|error| diagnostic(&error, name, program))?;
+            (analyse(&instructions, settings), Some(spans))
+        }
+        None => (analyse(instructions, settings), None),
+    };
+    Ok(if arguments.json {
+        report.to_json(spans.as_deref())
+    } else {
+        report.to_text(spans.as_deref())
+    })
+}
+
+/// Prints the next instruction, with its line of source and carets under its span.
+fn show_position(debugger: &Debugger, source: &str) {
+    let (Some(span), Some(instruction)) = (debugger.span(), debugger.instruction()) else {
+        return;
+    };
+    let line = source.lines().nth(span.start.line - 1).unwrap_or_default();
+    let end = if span.end.line == span.start.line {
+        span.end.column
+    } else {
+        line.chars().count().max(span.start.column)
+    };
+    let number = span.start.line.to_string();
+    let gutter = " ".repeat(number.len());
+    let padding: String = line
+        .chars()
+        .take(span.start.column - 1)
+        .map(|character| if character == '\t' { '\t' } else { ' ' })
+        .collect();
+    let carets = "^".repeat(end + 1 - span.start.column);
+
+    println!(
+        "{gutter}--> {}:{} {instruction:?}\n{number} | {line}\n{gutter} | {padding}{carets}",
+        span.start.line, span.start.column
+    );
+}
+
+/// Reports why the debugger stopped.
+fn report(debugger: &Debugger, source: &str, event: Result<Event, RuntimeErrorKind>) {
+    let show =
+        |value: Option<u32>| value.map_or_else(|| "nothing".to_string(), |value| value.to_string());
+    match event {
+        Ok(Event::Stepped) => {}
+        Ok(Event::Breakpoint) => println!("breakpoint"),
+        Ok(Event::Watch { address, old, new }) => {
+            println!("cell {address} changed from {} to {}", show(old), show(new));
+        }
+        Ok(Event::Finished) => println!("program finished"),
+        Err(error) => println!("program stopped: {error}"),
+    }
+    show_position(debugger, source);
+}
+
+fn debug(mut debugger: Debugger, source: &str) -> Result<(), String> {
+    println!("Type `help` for commands.");
+    show_position(&debugger, source);
+
+    let mut commands = stdin().lock().lines();
+    loop {
+        print!("(debug) ");
+        stdout().flush().map_err(|error| error.to_string())?;
+        let Some(line) = commands.next() else {
+            println!();
+            return Ok(());
+        };
+        let line = line.map_err(|error| error.to_string())?;
+        let mut words = line.split_whitespace();
+        let command = words.next().unwrap_or_default();
+        let argument = words.next();
+
+        match command {
+            "" => {}
+            "s" | "step" => {
+                let Some(count) = argument.map_or(Some(1), |count| count.parse::<u64>().ok())
+                else {
+                    println!("expected a number of steps");
+                    continue;
+                };
+                let mut event = Ok(Event::Stepped);
+                for _ in 0..count {
+                    event = debugger.step();
+                    if !matches!(event, Ok(Event::Stepped)) {
+                        break;
+                    }
+                }
+                report(&debugger, source, event);
+            }
+            "c" | "continue" => {
+                let event = debugger.resume();
+                report(&debugger, source, event);
+            }
+            "b" | "break" => {
+                let position = argument.and_then(|position| {
+                    let (line, column) = position.split_once(':').unwrap_or((position, "1"));
+                    Some((line.parse().ok()?, column.parse().ok()?))
+                });
+                let Some((line, column)) = position else {
+                    println!("expected LINE or LINE:COLUMN");
+                    continue;
+                };
+                match debugger.add_breakpoint(line, column) {
+                    Some(span) => {
+                        println!("breakpoint at {}:{}", span.start.line, span.start.column);
+                    }
+                    None => println!("no instruction at or after {line}:{column}"),
+                }
+            }
+            "w" | "watch" => match argument.and_then(|address| address.parse().ok()) {
+                Some(address) => debugger.watch(address),
+                None => println!("expected a cell address"),
+            },
+            "t" | "tape" => {
+                let Some(radius) = argument.map_or(Some(8), |radius| radius.parse().ok()) else {
+                    println!("expected a radius");
+                    continue;
+                };
+                let pointer = debugger.pointer();
+                for (address, value) in debugger.window(radius) {
+                    let marker = if address == pointer { '>' } else { ' ' };
+                    println!("{marker} {address:>6}: {value}");
+                }
+            }
+            "l" | "where" => show_position(&debugger, source),
+            "q" | "quit" => return Ok(()),
+            "h" | "help" => println!("{DEBUG_HELP}"),
+            other => println!("unknown command `{other}`, type `help` for commands"),
+        }
+    }
+}
+
+fn interactive() -> Result<(), String> {
     let input;
     let option;
     {
@@ -20,54 +680,90 @@ fn main() {
 
         macro_rules! ask {
             ($question:expr) => {{
-                write!(stdout, "\x1b[37m{}\x1b[0m", $question).unwrap();
-                stdout.flush().unwrap();
+                write!(stdout, "\x1b[37m{}\x1b[0m", $question)
+                    .and_then(|()| stdout.flush())
+                    .map_err(|error| format!("could not write to stdout: {error}"))?;
 
                 let mut input = String::new();
-                stdin.read_line(&mut input).unwrap();
+                stdin
+                    .read_line(&mut input)
+                    .map_err(|error| format!("could not read from stdin: {error}"))?;
                 input.trim().to_string()
             }};
         }
 
         let input_type = ask!("(A) File directory or (B) text input? ");
         if input_type == "A" {
-            input = fs::read_to_string(ask!("File directory: ")).unwrap();
+            let path = ask!("File directory: ");
+            input = fs::read_to_string(&path)
+                .map_err(|error| format!("could not read `{path}`: {error}"))?;
         } else if input_type == "B" {
             input = ask!("Code: ");
         } else {
-            panic!("Invalid input")
+            return Err(format!("invalid answer `{input_type}`, expected A or B"));
         }
 
         option = ask!("(A) Interpret or (B) transpile into rust or (C) transpile into C? ");
-        assert!(
-            option == "A" || option == "B" || option == "C",
-            "Invalid input"
-        );
+        if !matches!(option.as_str(), "A" | "B" | "C") {
+            return Err(format!("invalid answer `{option}`, expected A, B or C"));
+        }
     }
 
     let before = std::time::Instant::now();
-    let lexed = lex(&input);
-
-    //println!("{:?}", lex(&input).collect::<Vec<crate::lexer::Token>>());
-
-    let mut compiler = Compiler::new(lexed);
-    let compiled = compiler.compile();
-    //println!("{compiled:?}");
+    let instructions = Compiler::new(lex(&input))
+        .compile()
+        .map_err(|error| diagnostic(&error, "<input>", &input))?;
 
+    let settings = Settings::default();
     if option == "A" {
-        execute(compiled);
+        execute_with(&instructions, &settings, stdin().lock(), stdout().lock())
+            .map_err(|error| error.to_string())?;
     } else if option == "B" {
-        fs::write("r_output.rs", to_rust(compiled)).unwrap();
-    } else if option == "C" {
-        fs::write("c_output.c", to_c(compiled)).unwrap();
+        write_output(Some("r_output.rs"), to_rust(&instructions, &settings))?;
+    } else {
+        write_output(Some("c_output.c"), to_c(&instructions, &settings))?;
     }
 
     let mut stdout = stdout().lock();
-    stdout.flush().unwrap();
     write!(
         stdout,
         "\x1b[37mElapsed time: {:.2?}\x1b[0m",
         before.elapsed()
     )
-    .unwrap();
+    .and_then(|()| stdout.flush())
+    .map_err(|error| format!("could not write to stdout: {error}"))
+}
+
+fn main() -> ExitCode {
+    let mut arguments = env::args().skip(1).peekable();
+    if arguments.peek().is_none() {
+        return match interactive() {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(message) => {
+                eprintln!("error: {message}");
+                ExitCode::FAILURE
+            }
+        };
+    }
+
+    let arguments = match parse_arguments(arguments) {
+        Ok(arguments) => arguments,
+        Err(message) => {
+            eprintln!("error: {message}\n\n{USAGE}");
+            return ExitCode::from(2);
+        }
+    };
+
+    if matches!(arguments.command, Command::Help) {
+        println!("{USAGE}");
+        return ExitCode::SUCCESS;
+    }
+
+    match run(&arguments) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(message) => {
+            eprintln!("error: {message}");
+            ExitCode::FAILURE
+        }
+    }
 }
@@ -0,0 +1,200 @@
+use core::time::Duration;
+
+use crate::INITIAL_MEMORY_CAPACITY;
+
+/// What `,` does once input has run out.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EofBehavior {
+    /// Leave the cell unchanged.
+    #[default]
+    Unchanged,
+
+    /// Set the cell to 0.
+    Zero,
+
+    /// Set the cell to its maximum value, the same as -1.
+    Max,
+}
+
+/// How many bits each cell has.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CellWidth {
+    /// 8-bit cells.
+    #[default]
+    U8,
+
+    /// 16-bit cells.
+    U16,
+
+    /// 32-bit cells.
+    U32,
+}
+
+impl CellWidth {
+    /// Number of bits in a cell.
+    #[must_use]
+    pub const fn bits(self) -> u32 {
+        match self {
+            Self::U8 => 8,
+            Self::U16 => 16,
+            Self::U32 => 32,
+        }
+    }
+
+    /// Largest value a cell can hold, which is also the mask of its bits.
+    #[must_use]
+    pub const fn max(self) -> u32 {
+        u32::MAX >> (32 - self.bits())
+    }
+}
+
+/// What happens when the pointer moves off the end of the tape.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TapePolicy {
+    /// Grow to the right as needed, error when moving left of the first cell.
+    #[default]
+    Grow,
+
+    /// Fixed number of cells, wrapping around at both ends. Must not be 0.
+    Wrap(usize),
+
+    /// Grow in both directions as needed.
+    Bidirectional,
+
+    /// Fixed number of cells, error when moving past either end. Must not be 0.
+    Fixed(usize),
+}
+
+impl TapePolicy {
+    /// Number of cells the tape starts with.
+    #[must_use]
+    pub const fn initial_size(self) -> usize {
+        match self {
+            Self::Grow | Self::Bidirectional => INITIAL_MEMORY_CAPACITY,
+            Self::Wrap(size) | Self::Fixed(size) => size,
+        }
+    }
+}
+
+/// How long a program may run for. Only the interpreter and JIT enforce it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Budget {
+    /// Most steps to run, where each instruction and each pass of a scan or increment loop is a step.
+    pub max_steps: Option<u64>,
+
+    /// Longest time to run for. Checked between steps, so a blocked read is not interrupted.
+    pub timeout: Option<Duration>,
+}
+
+/// Which optimisations the compiler makes. Turning them off never changes what a program prints or
+/// leaves on the tape, only how many steps it takes, so a step budget can run out at a different place.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(clippy::struct_excessive_bools)]
+pub struct Optimisations {
+    /// Merge runs of `+` and `-`, or `>` and `<`, into one instruction.
+    pub merge_runs: bool,
+
+    /// Turn loops that only add to the current cell, like `[-]`, into `SetCell` or `IncrementLoop`.
+    pub clear_loops: bool,
+
+    /// Turn loops that only move, like `[>]`, or that also add to each cell they pass, like `[-<]`,
+    /// into scans such as `ForwardLoop` or `IncrementBackwardLoop`.
+    pub scan_loops: bool,
+
+    /// Track the values of cells through code without loops and out of loops, to drop loops that
+    /// never run, set cells instead of adding to them or multiplying into them, and print known characters.
+    pub known_cells: bool,
+
+    /// Turn balanced loops, like `[->+<]`, into multiplies.
+    pub multiply_loops: bool,
+
+    /// Defer pointer moves until a loop or input, addressing the cells in between by offset.
+    pub fold_moves: bool,
+
+    /// Run the start of the program while compiling, up to its first input or a step budget,
+    /// replacing it with the characters it prints and the cells it leaves. Skipped when the
+    /// compiler is given a budget, which would not count those steps.
+    pub evaluate_prefix: bool,
+}
+
+impl Optimisations {
+    /// Every optimisation, which is the default.
+    pub const ALL: Self = Self {
+        merge_runs: true,
+        clear_loops: true,
+        scan_loops: true,
+        known_cells: true,
+        multiply_loops: true,
+        fold_moves: true,
+        evaluate_prefix: true,
+    };
+
+    /// No optimisations, so each command becomes one instruction.
+    pub const NONE: Self = Self {
+        merge_runs: false,
+        clear_loops: false,
+        scan_loops: false,
+        known_cells: false,
+        multiply_loops: false,
+        fold_moves: false,
+        evaluate_prefix: false,
+    };
+
+    /// Optimisations for a level, like `-O`: 0 makes none, 1 merges runs and replaces
+    /// clear and scan loops, and 2 or more makes them all.
+    #[must_use]
+    pub const fn level(level: u8) -> Self {
+        match level {
+            0 => Self::NONE,
+            1 => Self {
+                merge_runs: true,
+                clear_loops: true,
+                scan_loops: true,
+                ..Self::NONE
+            },
+            _ => Self::ALL,
+        }
+    }
+}
+
+impl Default for Optimisations {
+    fn default() -> Self {
+        Self::ALL
+    }
+}
+
+/// Settings shared by the interpreter and transpilers.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Settings {
+    /// What `,` does once input has run out.
+    pub eof: EofBehavior,
+
+    /// How many bits each cell has.
+    pub cell_width: CellWidth,
+
+    /// What happens when the pointer moves off the end of the tape.
+    pub tape: TapePolicy,
+
+    /// How long a program may run for.
+    pub budget: Budget,
+
+    /// Most cells a growing tape may have, or `None` for no limit. Must not be 0.
+    pub tape_limit: Option<usize>,
+
+    /// Which optimisations the compiler makes.
+    pub optimisations: Optimisations,
+}
+
+impl Settings {
+    /// Number of cells the tape starts with, within the tape limit.
+    #[must_use]
+    pub fn initial_tape_size(&self) -> usize {
+        match self.tape {
+            TapePolicy::Grow | TapePolicy::Bidirectional => self
+                .tape
+                .initial_size()
+                .min(self.tape_limit.unwrap_or(usize::MAX)),
+            TapePolicy::Wrap(size) | TapePolicy::Fixed(size) => size,
+        }
+    }
+}
@@ -0,0 +1,468 @@
+//! Runs every backend over the same programs, checking each does what the interpreter does.
+//!
+//! Backends that need a tool which is not installed, like `node` for JavaScript, are skipped.
+
+mod wat;
+
+use std::env;
+use std::fs;
+use std::io::{self, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::process::{self, Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use brainfuck::compile::{Compiler, Instruction};
+use brainfuck::interpreter::{self, RuntimeError};
+use brainfuck::into_asm::to_asm;
+use brainfuck::into_c::to_c;
+use brainfuck::into_js::to_js;
+use brainfuck::into_llvm::to_llvm;
+use brainfuck::into_python::to_python;
+use brainfuck::into_rust::to_rust;
+use brainfuck::into_wasm::to_wasm;
+use brainfuck::lexer::lex;
+use brainfuck::settings::{Budget, CellWidth, EofBehavior, Optimisations, Settings, TapePolicy};
+
+/// Samples that finish quickly, with their input.
+const SAMPLES: [(&str, &[u8]); 4] = [
+    (include_str!("../samples/Zyx.bf"), b""),
+    (include_str!("../samples/Bitwidth.bf"), b""),
+    (include_str!("../samples/NumWarp.bf"), b"1234\n"),
+    (
+        include_str!("../samples/BrainfuckInBrainfuck.bf"),
+        b"++++++++[>++++++++<-]>+.+.!",
+    ),
+];
+
+/// What running a program did.
+#[derive(Debug, PartialEq, Eq)]
+struct Run {
+    output: Vec<u8>,
+
+    /// Why the program stopped early, if it did.
+    error: Option<String>,
+
+    /// Cells at the end, from backends that can show them.
+    memory: Option<Vec<u32>>,
+}
+
+impl Run {
+    fn new(output: Vec<u8>, result: Result<Vec<u32>, RuntimeError>) -> Self {
+        match result {
+            Ok(memory) => Self {
+                output,
+                error: None,
+                memory: Some(memory),
+            },
+            Err(error) => Self {
+                output,
+                error: Some(error.kind.to_string()),
+                memory: Some(error.memory),
+            },
+        }
+    }
+}
+
+/// Runs instructions, or returns `None` if a tool it needs is not installed.
+type Backend = fn(&[Instruction], &Settings, &[u8]) -> Option<Run>;
+
+fn compile(code: &str, settings: &Settings) -> Vec<Instruction> {
+    Compiler::new(lex(code))
+        .with_cell_width(settings.cell_width)
+        .with_tape(settings.tape)
+        .with_tape_limit(settings.tape_limit)
+        .with_budget(settings.budget)
+        .with_optimisations(settings.optimisations)
+        .compile()
+        .unwrap()
+}
+
+/// Runs `code` with `backend` and the interpreter, checking they agree.
+fn assert_same(backend: Backend, code: &str, settings: Settings, input: &[u8]) {
+    let instructions = compile(code, &settings);
+    let Some(run) = backend(&instructions, &settings, input) else {
+        return;
+    };
+    let mut expected = interpret(&instructions, &settings, input);
+    if run.memory.is_none() {
+        expected.memory = None;
+    }
+    assert_eq!(run, expected, "{code:?} with {settings:?}");
+}
+
+fn interpret(instructions: &[Instruction], settings: &Settings, input: &[u8]) -> Run {
+    let mut output = Vec::new();
+    let result = interpreter::execute_with(instructions, settings, input, &mut output);
+    Run::new(output, result)
+}
+
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+#[allow(clippy::unnecessary_wraps)]
+fn jit(instructions: &[Instruction], settings: &Settings, input: &[u8]) -> Option<Run> {
+    let mut output = Vec::new();
+    let result = brainfuck::jit::execute_with(instructions, settings, input, &mut output);
+    Some(Run::new(output, result))
+}
+
+#[cfg(not(all(target_arch = "x86_64", target_os = "linux")))]
+fn jit(_: &[Instruction], _: &Settings, _: &[u8]) -> Option<Run> {
+    None
+}
+
+fn wasm(instructions: &[Instruction], settings: &Settings, input: &[u8]) -> Option<Run> {
+    let module = to_wasm(instructions, settings);
+    let mut machine = wat::Machine::new(&module, input);
+    let result = machine.run();
+    if machine.out_of_fuel() {
+        eprintln!("skipping, too slow to run without a WebAssembly runtime");
+        return None;
+    }
+    let error = result.err().map(|wat::Trap| {
+        match machine.global("$error") {
+            1 => "pointer moved left of the first cell",
+            2 => "pointer moved right of the last cell",
+            3 => "tape limit exceeded",
+            code => panic!("unexpected error {code}"),
+        }
+        .to_string()
+    });
+    Some(Run {
+        memory: Some(machine.tape(settings.cell_width)),
+        output: machine.output,
+        error,
+    })
+}
+
+/// A directory to build a program in, removed when dropped.
+struct Scratch(PathBuf);
+
+impl Scratch {
+    fn new() -> Self {
+        static SCRATCHES: AtomicUsize = AtomicUsize::new(0);
+        let scratch = SCRATCHES.fetch_add(1, Ordering::Relaxed);
+        let path = env::temp_dir().join(format!("brainfuck-{}-{scratch}", process::id()));
+        fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+
+    /// Writes `contents` to the file `name`, returning its path.
+    fn write(&self, name: &str, contents: &str) -> PathBuf {
+        let path = self.0.join(name);
+        fs::write(&path, contents).unwrap();
+        path
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Starts `program`, or returns `None` if it is not installed.
+fn spawn(program: &Path, arguments: &[&Path], input: Stdio) -> Option<process::Child> {
+    match Command::new(program)
+        .args(arguments)
+        .stdin(input)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+    {
+        Ok(child) => Some(child),
+        Err(error) if error.kind() == ErrorKind::NotFound => {
+            eprintln!("skipping, `{}` is not installed", program.display());
+            None
+        }
+        Err(error) => panic!("could not run `{}`: {error}", program.display()),
+    }
+}
+
+/// Runs a tool that builds the program, checking it succeeds.
+fn build(program: &str, arguments: &[&Path]) -> Option<()> {
+    let output = spawn(Path::new(program), arguments, Stdio::null())?
+        .wait_with_output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "`{program}` failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    Some(())
+}
+
+/// Runs the program with `input`, taking what it writes to stderr as why it stopped early.
+fn run(program: &Path, arguments: &[&Path], input: &[u8]) -> Option<Run> {
+    let mut child = spawn(program, arguments, Stdio::piped())?;
+    // The program may stop before reading all of its input.
+    if let Err(error) = child.stdin.take().unwrap().write_all(input) {
+        assert_eq!(error.kind(), ErrorKind::BrokenPipe, "{error}");
+    }
+    let output = child.wait_with_output().unwrap();
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    let error = (!output.status.success()).then(|| stderr.trim_end().to_string());
+    Some(Run {
+        output: output.stdout,
+        error,
+        memory: None,
+    })
+}
+
+fn c(instructions: &[Instruction], settings: &Settings, input: &[u8]) -> Option<Run> {
+    let scratch = Scratch::new();
+    let source = scratch.write("program.c", &to_c(instructions, settings));
+    let binary = scratch.0.join("program");
+    build("cc", &[Path::new("-O1"), &source, Path::new("-o"), &binary])?;
+    run(&binary, &[], input)
+}
+
+fn rust(instructions: &[Instruction], settings: &Settings, input: &[u8]) -> Option<Run> {
+    let scratch = Scratch::new();
+    let source = scratch.write("program.rs", &to_rust(instructions, settings));
+    let binary = scratch.0.join("program");
+    let options = ["--edition", "2021", "-A", "warnings", "-o"].map(Path::new);
+    let [edition, year, allow, warnings, out] = options;
+    build(
+        "rustc",
+        &[edition, year, allow, warnings, out, &binary, &source],
+    )?;
+    run(&binary, &[], input)
+}
+
+fn asm(instructions: &[Instruction], settings: &Settings, input: &[u8]) -> Option<Run> {
+    if !cfg!(all(target_arch = "x86_64", target_os = "linux")) {
+        return None;
+    }
+    let scratch = Scratch::new();
+    let source = scratch.write("program.s", &to_asm(instructions, settings));
+    let object = scratch.0.join("program.o");
+    let binary = scratch.0.join("program");
+    build("as", &[&source, Path::new("-o"), &object])?;
+    build("ld", &[&object, Path::new("-o"), &binary])?;
+    run(&binary, &[], input)
+}
+
+fn llvm(instructions: &[Instruction], settings: &Settings, input: &[u8]) -> Option<Run> {
+    let version = spawn(Path::new("lli"), &[Path::new("--version")], Stdio::null())?
+        .wait_with_output()
+        .unwrap();
+    let version = String::from_utf8(version.stdout).unwrap();
+    let major: u32 = version
+        .split("version ")
+        .nth(1)
+        .and_then(|version| version.split('.').next())
+        .and_then(|major| major.parse().ok())
+        .unwrap();
+    let scratch = Scratch::new();
+    let source = scratch.write("program.ll", &to_llvm(instructions, settings));
+    // Opaque pointers need a flag before LLVM 17, which removed it.
+    if major < 17 {
+        run(
+            Path::new("lli"),
+            &[Path::new("-opaque-pointers"), &source],
+            input,
+        )
+    } else {
+        run(Path::new("lli"), &[&source], input)
+    }
+}
+
+fn js(instructions: &[Instruction], settings: &Settings, input: &[u8]) -> Option<Run> {
+    let scratch = Scratch::new();
+    scratch.write("program.mjs", &to_js(instructions, settings));
+    let runner = scratch.write(
+        "main.mjs",
+        r#"import { readFileSync, writeSync } from "node:fs";
+import { run } from "./program.mjs";
+
+try {
+	writeSync(1, run(readFileSync(0)));
+} catch (error) {
+	writeSync(1, error.output);
+	process.stderr.write(error.message + "\n");
+	process.exitCode = 1;
+}
+"#,
+    );
+    run(Path::new("node"), &[&runner], input)
+}
+
+fn python(instructions: &[Instruction], settings: &Settings, input: &[u8]) -> Option<Run> {
+    let scratch = Scratch::new();
+    let script = scratch.write("program.py", &to_python(instructions, settings));
+    run(Path::new("python3"), &[&script], input)
+}
+
+fn samples_work(backend: Backend) {
+    for (code, input) in SAMPLES {
+        assert_same(backend, code, Settings::default(), input);
+    }
+}
+
+fn eof_works(backend: Backend) {
+    for eof in [EofBehavior::Unchanged, EofBehavior::Zero, EofBehavior::Max] {
+        for cell_width in [CellWidth::U8, CellWidth::U16, CellWidth::U32] {
+            let settings = Settings {
+                eof,
+                cell_width,
+                ..Settings::default()
+            };
+            assert_same(backend, "+++++,.,+.", settings, b"a");
+        }
+    }
+}
+
+fn cell_width_works(backend: Backend) {
+    for cell_width in [CellWidth::U8, CellWidth::U16, CellWidth::U32] {
+        let settings = Settings {
+            cell_width,
+            ..Settings::default()
+        };
+        for code in [
+            include_str!("../samples/Bitwidth.bf"),
+            "-[>+++<-]>[>>+<<-]<<-[>+<---]",
+            "--[++]>++[-->+<]",
+        ] {
+            assert_same(backend, code, settings, b"");
+        }
+    }
+}
+
+fn tape_policy_works(backend: Backend) {
+    for (tape, tape_limit) in [
+        (TapePolicy::Grow, None),
+        (TapePolicy::Grow, Some(40)),
+        (TapePolicy::Wrap(5), None),
+        (TapePolicy::Bidirectional, None),
+        (TapePolicy::Bidirectional, Some(35)),
+        (TapePolicy::Fixed(2), None),
+        (TapePolicy::Fixed(3), None),
+    ] {
+        for cell_width in [CellWidth::U8, CellWidth::U16] {
+            let settings = Settings {
+                cell_width,
+                tape,
+                tape_limit,
+                ..Settings::default()
+            };
+            for code in [
+                "+>+<<+",
+                "+<++>>>+++",
+                "+++[<<++>>-]+",
+                ",+[>>+<<-]",
+                "+>>>>+>>>>+[<<<<]",
+                "+>+>+[>]<[<]",
+                "+<<+>[-]+[<<]>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>+.",
+            ] {
+                assert_same(backend, code, settings, b"");
+            }
+            if tape_limit.is_some() {
+                assert_same(backend, "+[>+]", settings, b"");
+                assert_same(backend, "+[<+]", settings, b"");
+            }
+        }
+    }
+}
+
+/// Checks each backend against the interpreter on the samples,
+/// and with each end of input behaviour, cell width and tape policy.
+macro_rules! backend_tests {
+    ($($backend:ident),*) => {$(
+        mod $backend {
+            #[test]
+            fn samples_work() {
+                super::samples_work(super::$backend);
+            }
+
+            #[test]
+            fn eof_works() {
+                super::eof_works(super::$backend);
+            }
+
+            #[test]
+            fn cell_width_works() {
+                super::cell_width_works(super::$backend);
+            }
+
+            #[test]
+            fn tape_policy_works() {
+                super::tape_policy_works(super::$backend);
+            }
+        }
+    )*};
+}
+
+backend_tests!(jit, wasm, c, rust, asm, llvm, js, python);
+
+#[test]
+fn jit_budget_works() {
+    for max_steps in [1, 2, 7, 100, 1000] {
+        for cell_width in [CellWidth::U8, CellWidth::U16] {
+            let settings = Settings {
+                cell_width,
+                tape: TapePolicy::Wrap(2),
+                budget: Budget {
+                    max_steps: Some(max_steps),
+                    ..Budget::default()
+                },
+                ..Settings::default()
+            };
+            for code in ["+[]", ",[++]", "+>+[>]", "+[>+<-]>[<++>-]<[>>+<<-]", "+>+."] {
+                assert_same(jit, code, settings, b"a");
+            }
+        }
+    }
+}
+
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+#[test]
+fn jit_stops_when_output_fails() {
+    /// Fails once 100 bytes have been written.
+    struct Limited(Vec<u8>);
+
+    impl Write for Limited {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.0.len() >= 100 {
+                return Err(ErrorKind::WriteZero.into());
+            }
+            self.0.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let settings = Settings::default();
+    let instructions = compile(include_str!("../samples/Squares.bf"), &settings);
+    let mut output = Limited(Vec::new());
+    let result = brainfuck::jit::execute_with(&instructions, &settings, &b""[..], &mut output);
+    assert!(result.is_err());
+    assert!(output.0.starts_with(b"0\n1\n4\n9\n16\n"));
+}
+
+#[test]
+fn transpilers_do_not_need_a_stop() {
+    let instructions = [
+        Instruction::Increment {
+            offset: 0,
+            amount: 65,
+        },
+        Instruction::Output { offset: 0 },
+    ];
+    let settings = Settings::default();
+    for transpile in [to_c, to_rust, to_wasm, to_asm, to_llvm, to_js, to_python] {
+        assert!(!transpile(&instructions, &settings).is_empty());
+    }
+}
+
+#[test]
+fn python_runs_deep_loops() {
+    // More loops nested than Python allows in one function.
+    let code = format!("+{}-{}.", "[".repeat(45), "]".repeat(45));
+    let settings = Settings {
+        optimisations: Optimisations::NONE,
+        ..Settings::default()
+    };
+    assert_same(python, &code, settings, b"");
+}
@@ -0,0 +1,136 @@
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+
+fn brainfuck(arguments: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_brainfuck"))
+        .args(arguments)
+        .output()
+        .unwrap()
+}
+
+/// Runs with `arguments`, writing `stdin` to its input.
+fn brainfuck_with_input(arguments: &[&str], stdin: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_brainfuck"))
+        .args(arguments)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(stdin.as_bytes())
+        .unwrap();
+    child.wait_with_output().unwrap()
+}
+
+/// Runs the interactive mode, answering its questions with `answers`.
+fn interactive(answers: &str) -> Output {
+    brainfuck_with_input(&[], answers)
+}
+
+#[test]
+fn max_steps_counts_the_start() {
+    // The whole program could be run while compiling, but not within the budget.
+    let code = format!("{}[.-]", "+".repeat(50));
+    let output = brainfuck(&["run", "--max-steps", "20", "-e", &code]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("step limit reached after 20 steps"));
+    assert!(output.stdout.len() < 50);
+
+    let output = brainfuck(&["run", "--max-steps", "200", "-e", &code]);
+    assert!(output.status.success());
+    assert_eq!(output.stdout, (1..=50).rev().collect::<Vec<u8>>());
+}
+
+#[test]
+fn minify_keeps_loops_shorter_than_their_cells() {
+    let output = brainfuck(&["minify", "-e", "++++++++[>++++++++<-]>+.+.+."]);
+    assert!(output.status.success());
+    assert_eq!(output.stdout, b"++++++++[->++++++++<]>+.+.+.\n");
+}
+
+#[test]
+fn interactive_reports_bad_answers() {
+    for answers in ["", "C\n", "B\n+.\nD\n"] {
+        let output = interactive(answers);
+        assert_eq!(output.status.code(), Some(1), "{answers:?}");
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stderr.starts_with("error: invalid answer"), "{stderr}");
+    }
+}
+
+#[test]
+fn interactive_reports_runtime_errors() {
+    let output = interactive("B\n+.<\nA\n");
+    assert_eq!(output.status.code(), Some(1));
+    assert!(output.stdout.ends_with(&[1]));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(stderr, "error: pointer moved left of the first cell\n");
+
+    let output = interactive("B\n++++++++[>++++++<-]>+.\nA\n");
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("1\x1b[37mElapsed time"));
+}
+
+#[test]
+fn ir_values_must_fit_in_cells() {
+    let path = env::temp_dir().join(format!("brainfuck-{}.ir", std::process::id()));
+    fs::write(&path, "Input\n  Increment(300)\nOutput\n").unwrap();
+    let path = path.to_str().unwrap();
+    for command in ["run", "compile", "minify", "to-python", "to-rust"] {
+        let output = brainfuck(&[command, path]);
+        assert_eq!(output.status.code(), Some(1), "{command}");
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(
+            stderr.starts_with("error: value does not fit in a cell"),
+            "{stderr}"
+        );
+        assert!(stderr.contains(&format!("{path}:2:3")), "{stderr}");
+    }
+    assert!(brainfuck(&["to-python", "--cell-width", "16", path])
+        .status
+        .success());
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn analyse_reports_loops_as_written() {
+    let output = brainfuck(&["analyse", "-e", "+[>+<-]>."]);
+    assert!(output.status.success());
+    let report = String::from_utf8(output.stdout).unwrap();
+    assert!(report.contains("loops: 1\n"), "{report}");
+
+    let output = brainfuck(&["analyse", "-e", "<[+>]>"]);
+    assert!(output.status.success());
+    let report = String::from_utf8(output.stdout).unwrap();
+    assert!(report.contains("moves left of cell 0: yes\n"), "{report}");
+}
+
+#[test]
+fn minify_only_trims_the_end_when_asked() {
+    let output = brainfuck(&["minify", "-e", ",+++++[-]>+"]);
+    assert_eq!(output.stdout, b",+++++[-]>+\n");
+    let output = brainfuck(&["minify", "--trim-end", "-e", ",+++++[-]>+"]);
+    assert_eq!(output.stdout, b",\n");
+    let output = brainfuck(&["minify", "--trim-end", "--tape", "fixed:1", "-e", ",>+"]);
+    assert_eq!(output.stdout, b",>+\n");
+}
+
+#[test]
+fn debug_keeps_to_the_tape_limit() {
+    // The loop is on a cell past the limit, so it cannot be left out even though the cell is 0.
+    let code = ">>>>>[+]<<<<<+.";
+    let output = brainfuck(&["run", "--tape-limit", "3", "-e", code]);
+    assert!(String::from_utf8_lossy(&output.stderr).contains("tape limit exceeded"));
+    let output = brainfuck_with_input(&["debug", "--tape-limit", "3", "-e", code], "continue\n");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        stdout.contains("program stopped: tape limit exceeded"),
+        "{stdout}"
+    );
+}
@@ -0,0 +1,503 @@
+//! Runs WebAssembly text modules, supporting what `to_wasm` generates, so its output can be
+//! checked without a WebAssembly runtime.
+
+use std::collections::HashMap;
+
+use brainfuck::settings::CellWidth;
+
+/// Bytes in a WebAssembly memory page.
+const PAGE_SIZE: usize = 65536;
+
+/// Part of a module in the text format.
+enum Sexp {
+    Atom(String),
+    List(Vec<Sexp>),
+}
+
+impl Sexp {
+    fn atom(&self) -> &str {
+        match self {
+            Self::Atom(atom) => atom,
+            Self::List(_) => panic!("expected an atom"),
+        }
+    }
+
+    fn list(&self) -> &[Self] {
+        match self {
+            Self::Atom(atom) => panic!("expected a list, got {atom}"),
+            Self::List(list) => list,
+        }
+    }
+}
+
+fn parse(source: &str) -> Sexp {
+    let mut lists = vec![Vec::new()];
+    let mut characters = source.chars().peekable();
+    while let Some(character) = characters.next() {
+        match character {
+            '(' => lists.push(Vec::new()),
+            ')' => {
+                let list = lists.pop().unwrap();
+                lists.last_mut().unwrap().push(Sexp::List(list));
+            }
+            character if character.is_whitespace() => {}
+            character => {
+                let mut atom = character.to_string();
+                while let Some(&next) = characters.peek() {
+                    if character == '"' {
+                        atom.push(next);
+                        characters.next();
+                        if next == '"' {
+                            break;
+                        }
+                    } else if next.is_whitespace() || next == '(' || next == ')' {
+                        break;
+                    } else {
+                        atom.push(next);
+                        characters.next();
+                    }
+                }
+                lists.last_mut().unwrap().push(Sexp::Atom(atom));
+            }
+        }
+    }
+    assert_eq!(lists.len(), 1, "unbalanced parentheses");
+    lists.pop().unwrap().pop().unwrap()
+}
+
+/// A decoded instruction, with names resolved to indexes.
+#[derive(Clone, Copy)]
+enum Op {
+    Const(u32),
+    Binary(fn(u32, u32) -> u32),
+    Eqz,
+    Select,
+    LocalGet(usize),
+    LocalSet(usize),
+    LocalTee(usize),
+    GlobalGet(usize),
+    GlobalSet(usize),
+    Call(usize),
+    Load(usize),
+    Store(usize),
+    MemorySize,
+    MemoryGrow,
+    MemoryCopy,
+    MemoryFill,
+    /// Index of the matching `end`.
+    Block(usize),
+    Loop,
+    /// Index of the matching `end`.
+    If(usize),
+    End,
+    Br(usize),
+    BrIf(usize),
+    Return,
+    Unreachable,
+}
+
+enum Function {
+    Input,
+    Output,
+    Defined {
+        params: usize,
+        locals: usize,
+        result: bool,
+        body: Vec<Op>,
+    },
+}
+
+struct Label {
+    start: usize,
+    end: usize,
+    is_loop: bool,
+}
+
+/// Why the module stopped early.
+#[derive(Debug)]
+pub struct Trap;
+
+/// Runs modules in the text format, supporting what `to_wasm` generates.
+pub struct Machine<'a> {
+    functions: Vec<Function>,
+    run: usize,
+    global_names: HashMap<String, usize>,
+    globals: Vec<u32>,
+    memory: Vec<u8>,
+    input: &'a [u8],
+    /// What the module has written.
+    pub output: Vec<u8>,
+    fuel: u64,
+}
+
+impl<'a> Machine<'a> {
+    pub fn new(source: &str, input: &'a [u8]) -> Self {
+        let module = parse(source);
+        let items = module.list();
+        assert_eq!(items[0].atom(), "module");
+
+        let mut function_names = HashMap::new();
+        let mut global_names = HashMap::new();
+        let mut globals = Vec::new();
+        let mut pages = 0;
+        for item in &items[1..] {
+            let item = item.list();
+            match item[0].atom() {
+                "import" => {
+                    function_names.insert(item[3].list()[1].atom(), function_names.len());
+                }
+                "func" => {
+                    let name = match &item[1] {
+                        Sexp::Atom(name) => name.as_str(),
+                        Sexp::List(_) => "",
+                    };
+                    function_names.insert(name, function_names.len());
+                }
+                "global" => {
+                    global_names.insert(item[1].atom().to_string(), globals.len());
+                    let init = item.last().unwrap().list();
+                    assert_eq!(init[0].atom(), "i32.const");
+                    globals.push(init[1].atom().parse::<i64>().unwrap() as u32);
+                }
+                "memory" => pages = item.last().unwrap().atom().parse().unwrap(),
+                other => panic!("unexpected {other}"),
+            }
+        }
+
+        let mut functions = Vec::new();
+        let mut run = None;
+        for item in &items[1..] {
+            let item = item.list();
+            match item[0].atom() {
+                "import" => functions.push(match item[2].atom() {
+                    "\"input\"" => Function::Input,
+                    "\"output\"" => Function::Output,
+                    other => panic!("unexpected import {other}"),
+                }),
+                "func" => {
+                    let mut local_names = HashMap::new();
+                    let mut params = 0;
+                    let mut result = false;
+                    let mut body = Vec::new();
+                    for part in &item[1..] {
+                        match part {
+                            Sexp::Atom(atom) if body.is_empty() && atom.starts_with('$') => {}
+                            Sexp::Atom(atom) => body.push(atom.as_str()),
+                            Sexp::List(list) => match list[0].atom() {
+                                "export" => {
+                                    assert_eq!(list[1].atom(), "\"run\"");
+                                    run = Some(functions.len());
+                                }
+                                "param" => {
+                                    local_names.insert(list[1].atom(), local_names.len());
+                                    params += 1;
+                                }
+                                "local" => {
+                                    local_names.insert(list[1].atom(), local_names.len());
+                                }
+                                "result" => result = true,
+                                other => panic!("unexpected {other}"),
+                            },
+                        }
+                    }
+                    functions.push(Function::Defined {
+                        params,
+                        locals: local_names.len(),
+                        result,
+                        body: decode(&body, &local_names, &global_names, &function_names),
+                    });
+                }
+                _ => {}
+            }
+        }
+
+        Self {
+            functions,
+            run: run.expect("no run export"),
+            global_names,
+            globals,
+            memory: vec![0; pages * PAGE_SIZE],
+            input,
+            output: Vec::new(),
+            fuel: 100_000_000,
+        }
+    }
+
+    pub fn global(&self, name: &str) -> u32 {
+        self.globals[self.global_names[name]]
+    }
+
+    /// Cells of the tape.
+    pub fn tape(&self, cell_width: CellWidth) -> Vec<u32> {
+        let bytes = cell_width.bits() as usize / 8;
+        self.memory[..self.global("$size") as usize * bytes]
+            .chunks(bytes)
+            .map(|cell| {
+                cell.iter()
+                    .rev()
+                    .fold(0, |value, &byte| value << 8 | u32::from(byte))
+            })
+            .collect()
+    }
+
+    fn range(&self, address: u32, length: u32) -> Result<core::ops::Range<usize>, Trap> {
+        let start = address as usize;
+        let end = start + length as usize;
+        if end <= self.memory.len() {
+            Ok(start..end)
+        } else {
+            Err(Trap)
+        }
+    }
+
+    #[allow(clippy::too_many_lines)]
+    fn call(&mut self, function: usize, stack: &mut Vec<u32>) -> Result<(), Trap> {
+        let (params, locals, result, body) = match &self.functions[function] {
+            Function::Input => {
+                let value = match self.input.split_first() {
+                    Some((&byte, rest)) => {
+                        self.input = rest;
+                        u32::from(byte)
+                    }
+                    None => u32::MAX,
+                };
+                stack.push(value);
+                return Ok(());
+            }
+            Function::Output => {
+                let value = stack.pop().unwrap();
+                self.output.push(value as u8);
+                return Ok(());
+            }
+            Function::Defined {
+                params,
+                locals,
+                result,
+                body,
+            } => (*params, *locals, *result, body.clone()),
+        };
+
+        let mut local = stack.split_off(stack.len() - params);
+        local.resize(locals, 0);
+        let mut values = Vec::new();
+        let mut labels: Vec<Label> = Vec::new();
+        let mut index = 0;
+        while index < body.len() {
+            if self.fuel == 0 {
+                return Err(Trap);
+            }
+            self.fuel -= 1;
+            let mut pop = || values.pop().unwrap();
+            match body[index] {
+                Op::Const(value) => values.push(value),
+                Op::Binary(operation) => {
+                    let right = pop();
+                    let left = pop();
+                    values.push(operation(left, right));
+                }
+                Op::Eqz => {
+                    let value = pop();
+                    values.push(u32::from(value == 0));
+                }
+                Op::Select => {
+                    let condition = pop();
+                    let second = pop();
+                    let first = pop();
+                    values.push(if condition == 0 { second } else { first });
+                }
+                Op::LocalGet(slot) => values.push(local[slot]),
+                Op::LocalSet(slot) => local[slot] = pop(),
+                Op::LocalTee(slot) => {
+                    local[slot] = pop();
+                    values.push(local[slot]);
+                }
+                Op::GlobalGet(slot) => values.push(self.globals[slot]),
+                Op::GlobalSet(slot) => self.globals[slot] = pop(),
+                Op::Call(function) => self.call(function, &mut values)?,
+                Op::Load(bytes) => {
+                    let address = pop();
+                    let range = self.range(address, bytes as u32)?;
+                    let value = self.memory[range]
+                        .iter()
+                        .rev()
+                        .fold(0, |value, &byte| value << 8 | u32::from(byte));
+                    values.push(value);
+                }
+                Op::Store(bytes) => {
+                    let value = pop();
+                    let address = pop();
+                    let range = self.range(address, bytes as u32)?;
+                    self.memory[range].copy_from_slice(&value.to_le_bytes()[..bytes]);
+                }
+                Op::MemorySize => values.push((self.memory.len() / PAGE_SIZE) as u32),
+                Op::MemoryGrow => {
+                    let pages = pop() as usize;
+                    let old_pages = self.memory.len() / PAGE_SIZE;
+                    if old_pages + pages > 1024 {
+                        values.push(u32::MAX);
+                    } else {
+                        self.memory.resize((old_pages + pages) * PAGE_SIZE, 0);
+                        values.push(old_pages as u32);
+                    }
+                }
+                Op::MemoryCopy => {
+                    let length = pop();
+                    let source = pop();
+                    let destination = pop();
+                    let source = self.range(source, length)?;
+                    let destination = self.range(destination, length)?;
+                    self.memory.copy_within(source, destination.start);
+                }
+                Op::MemoryFill => {
+                    let length = pop();
+                    let value = pop();
+                    let destination = pop();
+                    let destination = self.range(destination, length)?;
+                    self.memory[destination].fill(value as u8);
+                }
+                Op::Block(end) => labels.push(Label {
+                    start: index,
+                    end,
+                    is_loop: false,
+                }),
+                Op::Loop => labels.push(Label {
+                    start: index,
+                    end: 0,
+                    is_loop: true,
+                }),
+                Op::If(end) => {
+                    if pop() == 0 {
+                        index = end;
+                    } else {
+                        labels.push(Label {
+                            start: index,
+                            end,
+                            is_loop: false,
+                        });
+                    }
+                }
+                Op::End => {
+                    labels.pop();
+                }
+                Op::Br(depth) => {
+                    index = branch(&mut labels, depth);
+                    continue;
+                }
+                Op::BrIf(depth) => {
+                    if pop() != 0 {
+                        index = branch(&mut labels, depth);
+                        continue;
+                    }
+                }
+                Op::Return => break,
+                Op::Unreachable => return Err(Trap),
+            }
+            index += 1;
+        }
+
+        if result {
+            stack.push(values.pop().unwrap());
+        }
+        Ok(())
+    }
+
+    pub fn run(&mut self) -> Result<(), Trap> {
+        self.call(self.run, &mut Vec::new())
+    }
+
+    /// Whether the module was stopped for running too long rather than trapping.
+    pub fn out_of_fuel(&self) -> bool {
+        self.fuel == 0
+    }
+}
+
+/// Jumps out of the label `depth` labels up, returning the index to continue from.
+fn branch(labels: &mut Vec<Label>, depth: usize) -> usize {
+    let label = &labels[labels.len() - 1 - depth];
+    if label.is_loop {
+        let start = label.start;
+        labels.truncate(labels.len() - depth);
+        start + 1
+    } else {
+        let end = label.end;
+        labels.truncate(labels.len() - 1 - depth);
+        end + 1
+    }
+}
+
+fn decode(
+    body: &[&str],
+    locals: &HashMap<&str, usize>,
+    globals: &HashMap<String, usize>,
+    functions: &HashMap<&str, usize>,
+) -> Vec<Op> {
+    let mut ops = Vec::new();
+    let mut blocks = Vec::new();
+    let mut tokens = body.iter();
+    while let Some(&token) = tokens.next() {
+        let mut immediate = || *tokens.next().unwrap();
+        let op = match token {
+            "i32.const" => Op::Const(immediate().parse::<i64>().unwrap() as u32),
+            "i32.add" => Op::Binary(u32::wrapping_add),
+            "i32.sub" => Op::Binary(u32::wrapping_sub),
+            "i32.mul" => Op::Binary(u32::wrapping_mul),
+            "i32.and" => Op::Binary(|left, right| left & right),
+            "i32.shl" => Op::Binary(|left, right| left << (right & 31)),
+            "i32.shr_u" => Op::Binary(|left, right| left >> (right & 31)),
+            "i32.rem_u" => Op::Binary(|left, right| left % right),
+            "i32.eq" => Op::Binary(|left, right| u32::from(left == right)),
+            "i32.lt_u" => Op::Binary(|left, right| u32::from(left < right)),
+            "i32.gt_u" => Op::Binary(|left, right| u32::from(left > right)),
+            "i32.ge_u" => Op::Binary(|left, right| u32::from(left >= right)),
+            "i32.lt_s" => Op::Binary(|left, right| u32::from((left as i32) < right as i32)),
+            "i32.gt_s" => Op::Binary(|left, right| u32::from(left as i32 > right as i32)),
+            "i32.eqz" => Op::Eqz,
+            "select" => Op::Select,
+            "local.get" => Op::LocalGet(locals[immediate()]),
+            "local.set" => Op::LocalSet(locals[immediate()]),
+            "local.tee" => Op::LocalTee(locals[immediate()]),
+            "global.get" => Op::GlobalGet(globals[immediate()]),
+            "global.set" => Op::GlobalSet(globals[immediate()]),
+            "call" => Op::Call(functions[immediate()]),
+            "i32.load8_u" => Op::Load(1),
+            "i32.load16_u" => Op::Load(2),
+            "i32.load" => Op::Load(4),
+            "i32.store8" => Op::Store(1),
+            "i32.store16" => Op::Store(2),
+            "i32.store" => Op::Store(4),
+            "memory.size" => Op::MemorySize,
+            "memory.grow" => Op::MemoryGrow,
+            "memory.copy" => Op::MemoryCopy,
+            "memory.fill" => Op::MemoryFill,
+            "block" | "if" => {
+                blocks.push(ops.len());
+                if token == "block" {
+                    Op::Block(0)
+                } else {
+                    Op::If(0)
+                }
+            }
+            "loop" => {
+                blocks.push(ops.len());
+                Op::Loop
+            }
+            "end" => {
+                let start = blocks.pop().expect("unmatched end");
+                let index = ops.len();
+                match &mut ops[start] {
+                    Op::Block(end) | Op::If(end) => *end = index,
+                    _ => {}
+                }
+                Op::End
+            }
+            "br" => Op::Br(immediate().parse().unwrap()),
+            "br_if" => Op::BrIf(immediate().parse().unwrap()),
+            "return" => Op::Return,
+            "unreachable" => Op::Unreachable,
+            other => panic!("unknown instruction {other}"),
+        };
+        ops.push(op);
+    }
+    assert!(blocks.is_empty(), "unclosed block");
+    ops
+}